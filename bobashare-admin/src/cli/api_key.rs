@@ -0,0 +1,130 @@
+use anyhow::{bail, Context};
+use bobashare::{
+    generate_api_key, hash_api_key,
+    storage::{api_key::ApiKey as StoredApiKey, file::FileBackend},
+};
+use chrono::TimeDelta;
+use clap::{Args, Subcommand};
+use tracing::{event, instrument, Level};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct ApiKey {
+    #[clap(subcommand)]
+    command: ApiKeyCommand,
+}
+#[derive(Debug, Clone, Subcommand)]
+pub(crate) enum ApiKeyCommand {
+    /// Create a new API key and print it.
+    ///
+    /// Only a hash of the key is stored, so it can't be shown again later.
+    Create {
+        /// Name used to refer to the key.
+        name: String,
+        /// Maximum size of each upload in bytes.
+        ///
+        /// If not provided, the server's `max_file_size` is used.
+        #[clap(long, value_parser)]
+        max_file_size: Option<u64>,
+        /// Maximum expiry of each upload in days.
+        ///
+        /// If not provided, the server's `max_expiry` is used.
+        #[clap(long, value_parser)]
+        max_expiry: Option<u16>,
+        /// Allow uploads that never expire.
+        #[clap(long)]
+        allow_never_expiry: bool,
+        /// Maximum total size in bytes of the uploads created with the key
+        /// that haven't been deleted yet.
+        #[clap(long, value_parser)]
+        quota: Option<u64>,
+        /// Allow uploads to be given a custom ID.
+        #[clap(long)]
+        allow_custom_id: bool,
+    },
+    /// List the API keys and their limits.
+    ///
+    /// Keys defined in the server's config file are not included.
+    List,
+    /// Delete an API key, so it can't be used anymore.
+    ///
+    /// Uploads created with it are kept.
+    Delete {
+        /// Name of the key to delete.
+        name: String,
+    },
+}
+
+#[instrument(skip(backend))]
+pub(crate) async fn api_key(backend: FileBackend, args: ApiKey) -> anyhow::Result<()> {
+    let mut keys = backend
+        .read_api_keys()
+        .await
+        .context("error reading API keys")?;
+
+    match args.command {
+        ApiKeyCommand::Create {
+            name,
+            max_file_size,
+            max_expiry,
+            allow_never_expiry,
+            quota,
+            allow_custom_id,
+        } => {
+            if keys.iter().any(|k| k.name == name) {
+                bail!("an API key named `{name}` already exists");
+            }
+            let key = generate_api_key();
+            keys.push(StoredApiKey {
+                max_file_size,
+                max_expiry: max_expiry.map(|e| TimeDelta::try_days(e.into()).unwrap()),
+                allow_never_expiry,
+                quota,
+                allow_custom_id,
+                ..StoredApiKey::new(name, hash_api_key(&key))
+            });
+            backend
+                .write_api_keys(&keys)
+                .await
+                .context("error writing API keys")?;
+            event!(Level::DEBUG, "created API key");
+            println!("{key}");
+        }
+        ApiKeyCommand::List => {
+            for key in keys {
+                let usage = backend
+                    .api_key_usage(&key.name)
+                    .await
+                    .with_context(|| format!("error counting usage of API key {}", key.name))?;
+                println!("{}:", key.name);
+                match key.max_file_size {
+                    Some(size) => println!("  max file size: {size} bytes"),
+                    None => println!("  max file size: server default"),
+                }
+                match key.max_expiry {
+                    Some(expiry) => println!("  max expiry: {} days", expiry.num_days()),
+                    None => println!("  max expiry: server default"),
+                }
+                println!("  allow never expiry: {}", key.allow_never_expiry);
+                match key.quota {
+                    Some(quota) => println!("  usage: {usage} / {quota} bytes"),
+                    None => println!("  usage: {usage} bytes"),
+                }
+                println!("  allow custom id: {}", key.allow_custom_id);
+            }
+        }
+        ApiKeyCommand::Delete { name } => {
+            let len = keys.len();
+            keys.retain(|k| k.name != name);
+            if keys.len() == len {
+                bail!("no API key named `{name}` exists");
+            }
+            backend
+                .write_api_keys(&keys)
+                .await
+                .context("error writing API keys")?;
+            event!(Level::DEBUG, name, "deleted API key");
+        }
+    }
+
+    Ok(())
+}
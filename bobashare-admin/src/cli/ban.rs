@@ -0,0 +1,85 @@
+use anyhow::{bail, Context};
+use bobashare::{
+    ip::IpCidr,
+    storage::{ban::Ban as StoredBan, file::FileBackend},
+};
+use chrono::Utc;
+use clap::{Args, Subcommand};
+use tracing::{event, instrument, Level};
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct Ban {
+    #[clap(subcommand)]
+    command: BanCommand,
+}
+#[derive(Debug, Clone, Subcommand)]
+pub(crate) enum BanCommand {
+    /// Ban an IP address or range from uploading.
+    Add {
+        /// IP address or CIDR range to ban (ex. `192.0.2.1` or `192.0.2.0/24`).
+        range: IpCidr,
+        /// Why the addresses are banned.
+        #[clap(long, value_parser)]
+        reason: Option<String>,
+    },
+    /// List the banned IP addresses and ranges.
+    List,
+    /// Allow a banned IP address or range to upload again.
+    Remove {
+        /// IP address or CIDR range to unban, the same as when it was added.
+        range: IpCidr,
+    },
+}
+
+#[instrument(skip(backend))]
+pub(crate) async fn ban(backend: FileBackend, args: Ban) -> anyhow::Result<()> {
+    match args.command {
+        BanCommand::Add { range, reason } => {
+            let added = backend
+                .update_bans(|bans| {
+                    if bans.iter().any(|b| b.range == range) {
+                        return false;
+                    }
+                    bans.push(StoredBan {
+                        range,
+                        reason,
+                        creation_date: Utc::now(),
+                    });
+                    true
+                })
+                .await
+                .context("error updating bans")?;
+            if !added {
+                bail!("`{range}` is already banned");
+            }
+            event!(Level::DEBUG, %range, "added ban");
+        }
+        BanCommand::List => {
+            let bans = backend.read_bans().await.context("error reading bans")?;
+            for ban in bans {
+                match ban.reason {
+                    Some(reason) => {
+                        println!("{} (since {}): {reason}", ban.range, ban.creation_date)
+                    }
+                    None => println!("{} (since {})", ban.range, ban.creation_date),
+                }
+            }
+        }
+        BanCommand::Remove { range } => {
+            let removed = backend
+                .update_bans(|bans| {
+                    let len = bans.len();
+                    bans.retain(|b| b.range != range);
+                    bans.len() != len
+                })
+                .await
+                .context("error updating bans")?;
+            if !removed {
+                bail!("`{range}` is not banned");
+            }
+            event!(Level::DEBUG, %range, "removed ban");
+        }
+    }
+
+    Ok(())
+}
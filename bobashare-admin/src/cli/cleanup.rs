@@ -7,6 +7,7 @@ pub(crate) struct Cleanup {}
 
 #[instrument(skip(backend))]
 pub(crate) async fn cleanup(backend: FileBackend, args: Cleanup) -> anyhow::Result<()> {
-    backend.cleanup().await?;
+    let remaining = backend.cleanup().await?;
+    println!("{remaining} uploads remaining");
     Ok(())
 }
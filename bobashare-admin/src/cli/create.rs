@@ -1,7 +1,10 @@
 use std::path::PathBuf;
 
 use anyhow::{anyhow, Context};
-use bobashare::{generate_randomized_id, storage::file::FileBackend};
+use bobashare::{
+    generate_delete_key, generate_randomized_id, hash_delete_key, sanitize_filename,
+    storage::file::FileBackend,
+};
 use chrono::TimeDelta;
 use clap::{Args, Subcommand};
 use tokio::{
@@ -19,6 +22,11 @@ pub(crate) struct CreateUpload {
     expiry: Option<u16>,
     #[clap(short, long, value_parser)]
     source_file: PathBuf,
+    /// Delete the upload after it has been viewed this many times.
+    ///
+    /// If not provided, the upload can be viewed any number of times.
+    #[clap(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_views: Option<u64>,
 
     #[clap(subcommand)]
     name: NameOptions,
@@ -47,22 +55,32 @@ pub(crate) async fn create_upload(backend: FileBackend, args: CreateUpload) -> a
         NameOptions::Random { length } => generate_randomized_id(length.into()),
     };
 
-    let filename = args
-        .source_file
-        .file_name()
-        .ok_or_else(|| anyhow!("invalid filename for source file"))?
-        .to_string_lossy()
-        .to_string();
+    let filename = sanitize_filename(
+        &args
+            .source_file
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid filename for source file"))?
+            .to_string_lossy(),
+    );
     let mut file = File::open(&args.source_file)
         .await
         .with_context(|| format!("error opening file at {:?}", args.source_file))?;
     let mimetype = mime_guess::from_path(&args.source_file).first_or_octet_stream();
 
+    let delete_key = generate_delete_key();
     let mut upload = backend
-        .create_upload(name, filename, mimetype, expiry, None)
+        .create_upload(
+            name,
+            filename,
+            mimetype,
+            expiry,
+            hash_delete_key(&delete_key),
+        )
         .await?;
+    upload.metadata.remaining_views = args.max_views;
 
     println!("{:?}", upload.metadata);
+    println!("delete key: {delete_key}");
 
     let copied = io::copy(&mut file, &mut upload.file).await?;
 
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use bobashare::{ip::hash_ip, storage::file::FileBackend};
+use clap::Args;
+use tracing::{event, instrument, Level};
+
+/// Delete every upload created by an uploader.
+#[derive(Debug, Clone, Args)]
+pub(crate) struct DeleteUploads {
+    /// IP address of the uploader, or the hash of it that is stored if the
+    /// server has `uploader_ip_salt` set.
+    uploader: String,
+    /// The server's `uploader_ip_salt`, to also find uploads from the IP
+    /// address if it was stored hashed.
+    #[clap(long, value_parser)]
+    salt: Option<String>,
+    /// Only list the uploads that would be deleted.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[instrument(skip(backend))]
+pub(crate) async fn delete_uploads(
+    backend: FileBackend,
+    args: DeleteUploads,
+) -> anyhow::Result<()> {
+    let mut uploaders = vec![args.uploader.clone()];
+    if let Ok(ip) = args.uploader.parse::<IpAddr>() {
+        // stored addresses are formatted the same way
+        uploaders[0] = ip.to_canonical().to_string();
+        if let Some(salt) = &args.salt {
+            uploaders.push(hash_ip(ip, salt));
+        }
+    }
+
+    let uploads = backend
+        .find_uploads_by_uploader(&uploaders)
+        .await
+        .context("error finding uploads")?;
+    for upload in uploads {
+        println!("{} ({})", upload.id, upload.filename);
+        if args.dry_run {
+            continue;
+        }
+        backend
+            .delete_upload(&upload.id)
+            .await
+            .with_context(|| format!("error deleting upload {}", upload.id))?;
+        event!(Level::DEBUG, id = upload.id, "deleted upload");
+    }
+
+    Ok(())
+}
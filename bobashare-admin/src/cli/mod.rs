@@ -1,2 +1,6 @@
+pub(crate) mod api_key;
+pub(crate) mod ban;
 pub(crate) mod cleanup;
 pub(crate) mod create;
+pub(crate) mod delete_uploads;
+pub(crate) mod stats;
@@ -0,0 +1,40 @@
+use anyhow::Context;
+use bobashare::storage::file::FileBackend;
+use clap::Args;
+use tracing::instrument;
+
+#[derive(Debug, Clone, Args)]
+pub(crate) struct Stats {
+    /// ID of the upload to show access stats for.
+    ///
+    /// Stats collected by a running server that haven't been written to disk
+    /// yet are not included.
+    id: String,
+}
+
+#[instrument(skip(backend))]
+pub(crate) async fn stats(backend: FileBackend, args: Stats) -> anyhow::Result<()> {
+    let metadata = backend
+        .read_upload_metadata(&args.id)
+        .await
+        .with_context(|| format!("error reading metadata of upload {}", args.id))?;
+    let stats = backend
+        .read_upload_stats(&args.id)
+        .await
+        .with_context(|| format!("error reading stats of upload {}", args.id))?;
+
+    println!("id: {}", metadata.id);
+    println!("filename: {}", metadata.filename);
+    if let Some(uploader_ip) = metadata.uploader_ip {
+        println!("uploader ip: {uploader_ip}");
+    }
+    println!("views: {}", stats.views);
+    println!("downloads: {}", stats.downloads);
+    println!("bytes served: {}", stats.bytes_served);
+    match stats.last_accessed {
+        Some(date) => println!("last accessed: {date}"),
+        None => println!("last accessed: never"),
+    }
+
+    Ok(())
+}
@@ -21,6 +21,10 @@ pub(crate) struct Cli {
 pub(crate) enum Command {
     CreateUpload(create::CreateUpload),
     Cleanup(cleanup::Cleanup),
+    Stats(stats::Stats),
+    ApiKey(api_key::ApiKey),
+    Ban(ban::Ban),
+    DeleteUploads(delete_uploads::DeleteUploads),
 }
 
 #[tokio::main]
@@ -45,6 +49,18 @@ async fn main() -> anyhow::Result<()> {
         Command::Cleanup(args) => {
             cli::cleanup::cleanup(backend, args).await?;
         }
+        Command::Stats(args) => {
+            cli::stats::stats(backend, args).await?;
+        }
+        Command::ApiKey(args) => {
+            cli::api_key::api_key(backend, args).await?;
+        }
+        Command::Ban(args) => {
+            cli::ban::ban(backend, args).await?;
+        }
+        Command::DeleteUploads(args) => {
+            cli::delete_uploads::delete_uploads(backend, args).await?;
+        }
     };
 
     Ok(())
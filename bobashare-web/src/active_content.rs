@@ -0,0 +1,145 @@
+//! Serving uploads that browsers can run script in without letting them run
+//! script against the instance
+//!
+//! Raw files are served from the same origin as the app, so an HTML or SVG
+//! upload opened in a browser could otherwise use the API as whoever opened it.
+//! Every raw response is sent with `X-Content-Type-Options: nosniff` and a
+//! [`CONTENT_SECURITY_POLICY`] that sandboxes it, and uploads with one of the
+//! `active_content_types` or any XML type (see [`is_active`]) are also served
+//! as text or downloaded, depending on the [`ActiveContentAction`].
+
+use mime::Mime;
+use serde::Deserialize;
+
+/// `Content-Security-Policy` sent with raw files, which puts them in a unique
+/// origin and stops them from running script, submitting forms or opening
+/// popups
+pub const CONTENT_SECURITY_POLICY: &str = "sandbox";
+
+/// Types that are treated as active content if `active_content_types` isn't
+/// set: HTML and XML, since browsers run script in XHTML embedded in any XML
+/// document
+pub const DEFAULT_ACTIVE_CONTENT_TYPES: [&str; 8] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "application/xslt+xml",
+    "text/xsl",
+    "application/mathml+xml",
+];
+
+/// What to do when serving an upload with an active content type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActiveContentAction {
+    /// serve it as `text/plain`, so its source is shown
+    #[default]
+    PlainText,
+    /// keep its type but make browsers download it instead of opening it
+    Download,
+}
+
+/// Check whether an upload of type `mimetype` is active content, because its
+/// type is one of `types` (ignoring parameters like `charset`) or has a `+xml`
+/// suffix.
+///
+/// Every `+xml` type is active whatever `types` is, since there are too many to
+/// list (like `application/rss+xml` or vendor types) and browsers render all of
+/// them as XML, which can embed XHTML.
+pub fn is_active(types: &[Mime], mimetype: &Mime) -> bool {
+    mimetype.suffix() == Some(mime::XML)
+        || types
+            .iter()
+            .any(|t| t.essence_str() == mimetype.essence_str())
+}
+
+/// Get the `text/plain` type to serve active content as, keeping its charset.
+///
+/// The charset is quoted if it needs to be, and left out if it can't be kept
+/// even then.
+pub fn as_plain_text(mimetype: &Mime) -> Mime {
+    let Some(charset) = mimetype.get_param(mime::CHARSET) else {
+        return mime::TEXT_PLAIN;
+    };
+    format!("{}; charset={charset}", mime::TEXT_PLAIN)
+        .parse()
+        .or_else(|_| format!("{}; charset=\"{charset}\"", mime::TEXT_PLAIN).parse())
+        .unwrap_or(mime::TEXT_PLAIN)
+}
+
+/// Check whether raw files of type `mimetype` can be sandboxed with
+/// [`CONTENT_SECURITY_POLICY`].
+///
+/// PDFs aren't, since browsers refuse to show sandboxed PDFs, including in the
+/// display page; scripts in PDFs run inside the browser's PDF viewer rather
+/// than the page, so they can't use the instance anyway.
+pub fn can_sandbox(mimetype: &Mime) -> bool {
+    mimetype.essence_str() != "application/pdf"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_types() -> Vec<Mime> {
+        DEFAULT_ACTIVE_CONTENT_TYPES
+            .iter()
+            .map(|t| t.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn active_types() {
+        let types = default_types();
+        assert!(is_active(&types, &mime::TEXT_HTML));
+        assert!(is_active(&types, &mime::TEXT_HTML_UTF_8));
+        assert!(is_active(&types, &"Image/SVG+XML".parse().unwrap()));
+        assert!(!is_active(&types, &mime::TEXT_PLAIN_UTF_8));
+        assert!(!is_active(&types, &mime::IMAGE_PNG));
+        assert!(!is_active(&[], &mime::TEXT_HTML));
+    }
+    #[test]
+    fn xml_suffix_is_always_active() {
+        assert!(is_active(&[], &"application/rss+xml".parse().unwrap()));
+        assert!(is_active(&[], &"image/svg+xml".parse().unwrap()));
+        assert!(is_active(
+            &[],
+            &"application/vnd.example+XML; charset=utf-8"
+                .parse()
+                .unwrap()
+        ));
+        assert!(!is_active(&[], &"application/ld+json".parse().unwrap()));
+        assert!(!is_active(&[], &"application/xml-dtd".parse().unwrap()));
+    }
+    #[test]
+    fn plain_text_keeps_charset() {
+        assert_eq!(as_plain_text(&mime::TEXT_HTML), mime::TEXT_PLAIN);
+        assert_eq!(
+            as_plain_text(&mime::TEXT_HTML_UTF_8),
+            mime::TEXT_PLAIN_UTF_8
+        );
+        assert_eq!(
+            as_plain_text(&"text/xml; charset=shift_jis".parse().unwrap()).to_string(),
+            "text/plain; charset=shift_jis"
+        );
+    }
+    #[test]
+    fn plain_text_with_quoted_charset() {
+        assert_eq!(
+            as_plain_text(&r#"text/html; charset="a b""#.parse().unwrap()).to_string(),
+            r#"text/plain; charset="a b""#
+        );
+        assert_eq!(
+            as_plain_text(&r#"text/html; charset="utf-8""#.parse().unwrap()),
+            mime::TEXT_PLAIN_UTF_8
+        );
+    }
+    #[test]
+    fn pdfs_are_not_sandboxed() {
+        assert!(!can_sandbox(&mime::APPLICATION_PDF));
+        assert!(can_sandbox(&mime::TEXT_HTML));
+        assert!(can_sandbox(&mime::IMAGE_SVG));
+    }
+}
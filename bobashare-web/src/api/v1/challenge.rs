@@ -0,0 +1,97 @@
+//! API to get a proof-of-work challenge
+
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{event, instrument, Level};
+
+use crate::{
+    pow::{difficulty, Challenge, PowError, CHALLENGE_LIFETIME},
+    AppState,
+};
+
+/// Query parameters of [`challenge`]
+#[derive(Debug, Deserialize)]
+pub struct ChallengeQuery {
+    /// size in bytes of the file that will be uploaded
+    size: Option<u64>,
+}
+
+/// The JSON API response with a new challenge
+#[derive(Debug, Clone, Serialize)]
+pub struct ChallengeResponse {
+    /// the challenge, which is the first part of the `Bobashare-Proof-Of-Work`
+    /// header
+    pub challenge: String,
+    /// number of leading zero bits the SHA-256 hash of
+    /// `{challenge}:{solution}` needs
+    pub difficulty: u8,
+    /// maximum size in bytes of the upload the challenge can be used for
+    pub max_size: u64,
+    /// date the challenge stops working, in RFC 3339 format
+    pub expiry_date: DateTime<Utc>,
+}
+
+/// Get a proof-of-work challenge to solve before uploading without an API key
+///
+/// # Request
+///
+/// `GET /api/v1/challenge?size=:size`
+///
+/// `size` is the size of the file that will be uploaded, in bytes. The
+/// challenge can't be used for a larger file. If it's not given, the maximum
+/// file size is used, which could make the challenge harder to solve.
+///
+/// # Response
+///
+/// ## Success
+///
+/// - 200 OK
+/// - JSON body created from [`ChallengeResponse`]
+///
+/// To solve the challenge, find any string `solution` (of at most
+/// [`MAX_SOLUTION_LENGTH`] bytes, without a `:`) such that the SHA-256 hash
+/// of `{challenge}:{solution}` starts with at least `difficulty` zero bits.
+/// Then upload with the header
+/// `Bobashare-Proof-Of-Work: {challenge}:{solution}` before the challenge
+/// expires. Each challenge can only be used once.
+///
+/// ## Errors
+///
+/// - 404 Not Found if proof of work isn't enabled on this server
+///
+/// [`MAX_SOLUTION_LENGTH`]: crate::pow::MAX_SOLUTION_LENGTH
+#[instrument(skip(state))]
+pub async fn challenge(
+    State(state): State<&'static AppState>,
+    Query(query): Query<ChallengeQuery>,
+) -> Result<impl IntoResponse, PowError> {
+    if state.pow_difficulty == 0 {
+        return Err(PowError::Disabled);
+    }
+    let max_size = query
+        .size
+        .map_or(state.max_file_size, |s| s.min(state.max_file_size));
+    let challenge = Challenge::new(
+        difficulty(state.pow_difficulty, state.pow_scale_size, max_size),
+        max_size,
+    );
+    let expiry_date = Utc::now() + CHALLENGE_LIFETIME;
+    event!(
+        Level::DEBUG,
+        nonce = challenge.nonce,
+        difficulty = challenge.difficulty,
+        max_size,
+        "created challenge"
+    );
+    Ok(Json(ChallengeResponse {
+        challenge: challenge.sign(&state.secret_key, expiry_date),
+        difficulty: challenge.difficulty,
+        max_size,
+        expiry_date,
+    }))
+}
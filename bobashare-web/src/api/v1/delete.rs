@@ -11,7 +11,7 @@ use thiserror::Error;
 use tracing::{event, instrument, Level};
 
 use super::ApiErrorExt;
-use crate::AppState;
+use crate::{check_delete_key, AppState};
 
 /// Errors that could occur when deleting an upload
 #[derive(Debug, Error, Display)]
@@ -87,7 +87,7 @@ pub async fn delete(
         state.backend.delete_upload(&id).await?;
         return Err(DeleteError::NotFound);
     }
-    if metadata.delete_key != key {
+    if !check_delete_key(&metadata, key).await {
         event!(Level::INFO, "provided delete key was incorrect");
         return Err(DeleteError::IncorrectKey);
     }
@@ -1,20 +1,27 @@
 //! API to get metadata about an upload
 
+use anyhow::Context;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::{IntoResponse, Response},
     Json,
 };
-use bobashare::storage::file::OpenUploadError;
+use bobashare::storage::{file::OpenUploadError, stats::UploadStats};
 use chrono::{DateTime, Utc};
 use displaydoc::Display;
-use hyper::StatusCode;
-use serde::Serialize;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{event, instrument, Level};
 
 use super::ApiErrorExt;
-use crate::AppState;
+use crate::{
+    check_delete_key, check_password,
+    client_ip::ClientIp,
+    rate_limit::TooManyRequests,
+    token::{self, TokenScope, UNLOCK_TOKEN_LIFETIME},
+    AppState,
+};
 
 /// Successful upload info API response
 #[derive(Debug, Clone, Serialize)]
@@ -25,14 +32,31 @@ pub struct InfoResponse {
     pub url: String,
     /// direct URL to download the upload file
     pub direct_url: String,
-    /// filename of the uploaded file
-    pub filename: String,
-    /// MIME type of the file
-    pub mimetype: String,
+    /// filename of the uploaded file, or None if the upload is password
+    /// protected and the password wasn't provided
+    pub filename: Option<String>,
+    /// MIME type of the file, or None if the upload is password protected and
+    /// the password wasn't provided
+    pub mimetype: Option<String>,
+    /// title of the upload, or None if not set (or hidden like `filename`)
+    pub title: Option<String>,
+    /// description of the upload, or None if not set (or hidden like
+    /// `filename`)
+    pub description: Option<String>,
+    /// tags of the upload (empty if hidden like `filename`)
+    pub tags: Vec<String>,
+    /// whether a password is needed to view the upload
+    pub password_protected: bool,
     /// date the upload was created
     pub creation_date: DateTime<Utc>,
     /// date the upload expires, or None if it never expires
     pub expiry_date: Option<DateTime<Utc>>,
+    /// number of views left before the upload is deleted, or None if unlimited
+    pub remaining_views: Option<u64>,
+    /// access stats of the upload, only included if the delete key was
+    /// provided
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<UploadStats>,
     // don't accidentally send `delete_key` lol
 }
 
@@ -41,6 +65,12 @@ pub struct InfoResponse {
 pub enum InfoError {
     /// an upload at the specified id was not found
     NotFound,
+    /// incorrect password
+    IncorrectPassword,
+    /// incorrect delete key
+    IncorrectKey,
+    /// {0}
+    TooManyAttempts(#[from] TooManyRequests),
 
     /// internal server error
     InternalServer(#[from] anyhow::Error),
@@ -49,6 +79,10 @@ impl IntoResponse for InfoError {
     fn into_response(self) -> Response {
         let code = match self {
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::IncorrectPassword => StatusCode::FORBIDDEN,
+            Self::IncorrectKey => StatusCode::FORBIDDEN,
+            // includes the `Retry-After` header
+            Self::TooManyAttempts(e) => return e.into_response(),
             Self::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -56,22 +90,44 @@ impl IntoResponse for InfoError {
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InfoParams {
+    access: Option<String>,
+}
+
 /// Get information (metadata) about an upload
 ///
 /// # Request
 ///
 /// `GET /api/v1/info/:id`
 ///
+/// ## Query parameters
+///
+/// - `access` -- the access token from a signed link; required if the upload
+///   is private, otherwise it will appear to not exist
+///
+/// ## Headers
+///
+/// - `Bobashare-Password` (optional) -- string -- password of the upload, if
+///   it's password protected. Without it, the filename, mimetype, title,
+///   description and tags are left out of the response. With it, `direct_url`
+///   contains a short-lived token that can be used to download the file.
+/// - `Bobashare-Delete-Key` (optional) -- string -- the key used to delete the
+///   upload. With it, the response includes the upload's access stats.
+///
 /// # Response
 ///
 /// ## Success
 ///
 /// - 200 OK
 /// - JSON body created from [`InfoResponse`]
-#[instrument(skip(state))]
+#[instrument(skip(state, ip, headers, access))]
 pub async fn info(
     state: State<&'static AppState>,
     Path(id): Path<String>,
+    Query(InfoParams { access }): Query<InfoParams>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, InfoError> {
     event!(Level::DEBUG, id, "reading upload metadata");
     let metadata = state
@@ -85,16 +141,96 @@ pub async fn info(
             ),
         })?;
 
-    let url = state.base_url.join(&id).unwrap().to_string();
-    let direct_url = state.raw_url.join(&id).unwrap().to_string();
+    let mut url = state.base_url.join(&id).unwrap();
+    let mut direct_url = state.raw_url.join(&id).unwrap();
+    if metadata.private {
+        let Some(access) = access else {
+            event!(
+                Level::INFO,
+                "upload is private and no access token was given"
+            );
+            return Err(InfoError::NotFound);
+        };
+        if let Err(e) = token::verify(&state.secret_key, TokenScope::Access, &id, &access) {
+            event!(
+                Level::INFO,
+                "upload is private and access token is invalid: {e}"
+            );
+            return Err(InfoError::NotFound);
+        }
+        url.query_pairs_mut().append_pair("access", &access);
+        direct_url.query_pairs_mut().append_pair("access", &access);
+    }
+
+    let password = headers
+        .get("Bobashare-Password")
+        .and_then(|p| p.to_str().ok());
+    let password_protected = metadata.is_password_protected();
+    let unlocked = match password {
+        _ if !password_protected => true,
+        None => false,
+        Some(p) => {
+            state.rate_limiters.check_password_attempt(ip, &id)?;
+            if !check_password(&metadata, p).await {
+                return Err(InfoError::IncorrectPassword);
+            }
+            let expiry = Utc::now() + UNLOCK_TOKEN_LIFETIME;
+            let token = token::sign(&state.secret_key, TokenScope::Unlock, &id, expiry);
+            direct_url.query_pairs_mut().append_pair("token", &token);
+            true
+        }
+    };
+    let stats = match headers
+        .get("Bobashare-Delete-Key")
+        .and_then(|k| k.to_str().ok())
+    {
+        None => None,
+        Some(k) if check_delete_key(&metadata, k.trim()).await => {
+            let mut stats = state
+                .backend
+                .read_upload_stats(&id)
+                .await
+                .context("error reading upload stats")?;
+            // include stats that haven't been written to disk yet
+            stats.merge(&state.stats.pending(&id));
+            Some(stats)
+        }
+        Some(_) => {
+            event!(Level::INFO, "provided delete key was incorrect");
+            return Err(InfoError::IncorrectKey);
+        }
+    };
+
+    let (filename, mimetype, title, description, tags) = if unlocked {
+        (
+            Some(metadata.filename),
+            Some(metadata.mimetype.to_string()),
+            metadata.title,
+            metadata.description,
+            metadata.tags,
+        )
+    } else {
+        event!(
+            Level::DEBUG,
+            "upload is password protected; hiding filename and description"
+        );
+        (None, None, None, None, Vec::new())
+    };
+
     event!(Level::INFO, "successfully queried upload metadata");
     Ok(Json(InfoResponse {
         id,
-        url,
-        direct_url,
-        filename: metadata.filename,
-        mimetype: metadata.mimetype.to_string(),
+        url: url.to_string(),
+        direct_url: direct_url.to_string(),
+        filename,
+        mimetype,
+        title,
+        description,
+        tags,
+        password_protected,
         creation_date: metadata.creation_date,
         expiry_date: metadata.expiry_date,
+        remaining_views: metadata.remaining_views,
+        stats,
     }))
 }
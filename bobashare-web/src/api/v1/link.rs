@@ -0,0 +1,133 @@
+//! API to create new signed links to a private upload
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bobashare::storage::file::OpenUploadError;
+use chrono::{DateTime, Utc};
+use displaydoc::Display;
+use hyper::{HeaderMap, StatusCode};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{event, instrument, Level};
+
+use super::{parse_link_expiry_header, upload_urls, ApiErrorExt};
+use crate::{check_delete_key, AppState};
+
+/// Successful link API response
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkResponse {
+    /// ID of the upload
+    pub id: String,
+    /// signed URL of the upload
+    pub url: String,
+    /// signed direct URL to download the upload file
+    pub direct_url: String,
+    /// date the signed links stop working
+    pub expiry_date: DateTime<Utc>,
+}
+
+/// Errors when creating a signed link to an upload
+#[derive(Debug, Error, Display)]
+pub enum LinkError {
+    /// an upload at the specified id was not found
+    NotFound,
+    /// incorrect delete key
+    IncorrectKey,
+    /// the upload is not private, so it doesn't need a signed link
+    NotPrivate,
+    /// error parsing `{name}` header
+    ParseHeader { name: String, source: anyhow::Error },
+
+    /// internal server error
+    InternalServer(#[from] anyhow::Error),
+}
+impl IntoResponse for LinkError {
+    fn into_response(self) -> Response {
+        let code = match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::IncorrectKey => StatusCode::FORBIDDEN,
+            Self::NotPrivate => StatusCode::BAD_REQUEST,
+            Self::ParseHeader { name: _, source: _ } => StatusCode::BAD_REQUEST,
+            Self::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        self.into_response_with_code(code)
+    }
+}
+
+/// Create a new signed link to a private upload
+///
+/// # Request
+///
+/// `POST /api/v1/link/:id`
+///
+/// ## Headers
+///
+/// - `Bobashare-Link-Expiry` (optional) -- duration until the link stops
+///   working (see [`str_to_duration`] for the format); defaults to when the
+///   upload expires
+///
+/// [`str_to_duration`]: crate::str_to_duration
+///
+/// ## Body
+///
+/// Should contain the key used to delete the upload (`delete_key` in
+/// [`UploadResponse`]).
+///
+/// [`UploadResponse`]: super::upload::UploadResponse::delete_key
+///
+/// # Response
+///
+/// ## Success
+///
+/// - 200 OK
+/// - JSON body created from [`LinkResponse`]
+#[instrument(skip(state, headers, key))]
+pub async fn link(
+    state: State<&'static AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    key: String,
+) -> Result<impl IntoResponse, LinkError> {
+    let key = key.trim();
+    let link_expiry =
+        parse_link_expiry_header(&headers).map_err(|source| LinkError::ParseHeader {
+            name: String::from("Bobashare-Link-Expiry"),
+            source,
+        })?;
+
+    event!(Level::DEBUG, "reading upload metadata");
+    let metadata = state
+        .backend
+        .read_upload_metadata(&id)
+        .await
+        .map_err(|e| match e {
+            OpenUploadError::NotFound(_) => LinkError::NotFound,
+            e => LinkError::InternalServer(
+                anyhow::Error::new(e).context("error reading upload metadata"),
+            ),
+        })?;
+    if metadata.is_expired() {
+        return Err(LinkError::NotFound);
+    }
+    if !check_delete_key(&metadata, key).await {
+        event!(Level::INFO, "provided delete key was incorrect");
+        return Err(LinkError::IncorrectKey);
+    }
+    if !metadata.private {
+        return Err(LinkError::NotPrivate);
+    }
+
+    let (url, direct_url, expiry_date) = upload_urls(&state, &metadata, link_expiry);
+    // SAFETY: upload_urls always signs links to private uploads
+    let expiry_date = expiry_date.unwrap();
+    event!(Level::INFO, %expiry_date, "created signed link to upload");
+    Ok(Json(LinkResponse {
+        id,
+        url: url.to_string(),
+        direct_url: direct_url.to_string(),
+        expiry_date,
+    }))
+}
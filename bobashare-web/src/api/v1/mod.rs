@@ -3,31 +3,134 @@
 use std::error::Error;
 
 use axum::{
+    extract::DefaultBodyLimit,
     response::{IntoResponse, Response},
-    routing::{delete, get, put},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
-use hyper::StatusCode;
+use bobashare::storage::upload::Upload;
+use chrono::{DateTime, TimeDelta, Utc};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, StatusCode,
+};
 use serde_json::json;
+use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::{event, Level};
+use url::Url;
 
-use crate::AppState;
+use crate::{
+    str_to_duration,
+    token::{self, TokenScope},
+    AppState,
+};
 
+pub mod challenge;
 pub mod delete;
 pub mod info;
+pub mod link;
+pub mod tus;
 pub mod upload;
 
 /// Routes under `/api/v1/`
 ///
+/// - `/api/v1/challenge`: [`challenge::challenge`]
 /// - `/api/v1/info/:id`: [`info::info`]
-/// - `/api/v1/upload`: [`upload::put`]
+/// - `/api/v1/upload`: [`upload::post`]
 /// - `/api/v1/upload/:filename`: [`upload::put`]
 /// - `/api/v1/delete/:id`: [`delete::delete`]
+/// - `/api/v1/link/:id`: [`link::link`]
+/// - `/api/v1/tus`: [`tus::create`], [`tus::options`]
+/// - `/api/v1/tus/:session`: [`tus::patch`], [`tus::head`], [`tus::terminate`]
 pub fn router() -> Router<&'static AppState> {
     Router::new()
+        .route("/challenge", get(challenge::challenge))
         .route("/info/{id}", get(info::info))
+        .route(
+            "/upload",
+            // the size of each file is limited while streaming it instead
+            post(upload::post).layer(DefaultBodyLimit::disable()),
+        )
         .route("/upload/{filename}", put(upload::put))
         .route("/delete/{id}", delete(delete::delete))
+        .route("/link/{id}", post(link::link))
+        .nest("/tus", tus_router())
+}
+
+/// Routes under `/api/v1/tus`, which all send the `Tus-Resumable` header
+fn tus_router() -> Router<&'static AppState> {
+    Router::new()
+        .route("/", post(tus::create).options(tus::options))
+        .route(
+            "/{session}",
+            // the size of each chunk is limited by the `Upload-Length` instead
+            patch(tus::patch)
+                .head(tus::head)
+                .delete(tus::terminate)
+                .options(tus::options)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static("tus-resumable"),
+            HeaderValue::from_static(tus::TUS_VERSION),
+        ))
+}
+
+/// Parse how long a signed link to a private upload is valid for (see
+/// [`str_to_duration`] for the format).
+pub(crate) fn parse_link_expiry(expiry: &str) -> Result<TimeDelta, anyhow::Error> {
+    let expiry = str_to_duration(expiry)
+        .map_err(|e| anyhow::Error::new(e).context("error parsing duration string"))?;
+    TimeDelta::from_std(expiry)
+        .map_err(|e| anyhow::Error::new(e).context("error converting duration"))
+}
+
+/// Parse the `Bobashare-Link-Expiry` header with [`parse_link_expiry`].
+pub(crate) fn parse_link_expiry_header(
+    headers: &HeaderMap,
+) -> Result<Option<TimeDelta>, anyhow::Error> {
+    let Some(expiry) = headers.get("Bobashare-Link-Expiry") else {
+        return Ok(None);
+    };
+    let expiry = expiry
+        .to_str()
+        .map_err(|e| anyhow::Error::new(e).context("error converting to string"))?;
+    parse_link_expiry(expiry).map(Some)
+}
+
+/// Get the URL and direct URL of an upload.
+///
+/// If the upload is private, both are signed with an access token that is
+/// valid for `link_expiry` if provided, otherwise until the upload expires (or
+/// for the default expiry if it never does). Returns the expiry date of the
+/// signed link, or [`None`] if the upload isn't private.
+pub(crate) fn upload_urls(
+    state: &AppState,
+    upload: &Upload,
+    link_expiry: Option<TimeDelta>,
+) -> (Url, Url, Option<DateTime<Utc>>) {
+    // SAFETY: this shouldn't fail because `upload.id` should be valid in a URL
+    let mut url = state.base_url.join(&upload.id).unwrap();
+    let mut direct_url = state.raw_url.join(&upload.id).unwrap();
+    if !upload.private {
+        return (url, direct_url, None);
+    }
+
+    let link_expiry_date = match link_expiry {
+        Some(e) => Utc::now() + e,
+        None => upload
+            .expiry_date
+            .unwrap_or_else(|| Utc::now() + state.default_expiry),
+    };
+    let access = token::sign(
+        &state.secret_key,
+        TokenScope::Access,
+        &upload.id,
+        link_expiry_date,
+    );
+    url.query_pairs_mut().append_pair("access", &access);
+    direct_url.query_pairs_mut().append_pair("access", &access);
+    (url, direct_url, Some(link_expiry_date))
 }
 
 /// Method to convert an [`std::error::Error`] into a [`Response`] with a
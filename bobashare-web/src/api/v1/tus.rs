@@ -0,0 +1,626 @@
+//! Resumable uploads with the [tus protocol](https://tus.io/protocols/resumable-upload)
+//!
+//! A session is created with `POST /api/v1/tus`, which creates an upload and
+//! keeps it locked while the file is sent in one or more `PATCH` requests.
+//! If a request fails partway through, the client can ask for the current
+//! offset with `HEAD` and continue from there. Once the whole file has been
+//! received, the upload is finished just like one created with
+//! [`super::upload::put`].
+//!
+//! Each session has a random ID that's only given to the client that created
+//! it, in the `Location` URL, so nobody else can send data to it or cancel it
+//! even if they know the ID of the upload.
+//!
+//! Sessions are only kept in memory, and expire if no data is sent for
+//! [`AppState::tus_session_timeout`]. They can't be resumed after the server
+//! restarts, so their uploads are deleted when it shuts down.
+
+use std::{collections::HashMap, pin::pin, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bobashare::{generate_randomized_id, storage::handle::UploadHandle};
+use chrono::{DateTime, TimeDelta, Utc};
+use displaydoc::Display;
+use futures_util::TryStreamExt;
+use hyper::{header, HeaderMap, StatusCode};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::{event, instrument, Level};
+
+use super::{
+    upload::{
+        abort_upload, create_upload, finish_upload, release_quota, reserve_quota, UploadError,
+        UploadOptions, OPTIONS,
+    },
+    upload_urls, ApiErrorExt,
+};
+use crate::{api_key::UploadLimits, client_ip::ClientIp, AppState};
+
+/// Version of the tus protocol that is supported
+pub const TUS_VERSION: &str = "1.0.0";
+/// tus protocol extensions that are supported
+pub const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+/// Length of the random ID of a session, which is used in its URL
+pub const SESSION_ID_LENGTH: usize = 32;
+
+/// An upload that is in progress
+#[derive(Debug)]
+pub struct TusSession {
+    /// the locked upload, or [`None`] if it was finished or terminated
+    upload: Option<UploadHandle>,
+    /// total size of the file in bytes
+    length: u64,
+    /// number of bytes received so far
+    offset: u64,
+    /// when the session expires if no more data is sent
+    expires: DateTime<Utc>,
+    /// how long signed links to the finished upload are valid for
+    link_expiry: Option<TimeDelta>,
+    /// the upload's delete key, which isn't stored in its metadata
+    delete_key: String,
+}
+
+/// All the tus sessions that are in progress, by their session ID (which is
+/// different from the ID of their upload)
+#[derive(Debug, Default)]
+pub struct TusSessions {
+    sessions: std::sync::Mutex<HashMap<String, Arc<Mutex<TusSession>>>>,
+}
+impl TusSessions {
+    fn get(&self, id: &str) -> Option<Arc<Mutex<TusSession>>> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+    fn insert(&self, id: String, session: TusSession) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(session)));
+    }
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// Delete the uploads of all sessions that have expired. Sessions that are
+    /// currently receiving data are skipped.
+    #[instrument(skip_all)]
+    pub async fn expire(&self, state: &AppState) {
+        let expired = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let now = Utc::now();
+            let mut expired = Vec::new();
+            sessions.retain(|_, session| {
+                let Ok(mut session) = session.try_lock() else {
+                    return true;
+                };
+                if session.expires > now {
+                    return true;
+                }
+                if let Some(upload) = session.upload.take() {
+                    event!(Level::INFO, id = upload.metadata.id, "tus session expired");
+                    expired.push((upload, session.length));
+                }
+                false
+            });
+            expired
+        };
+        for (upload, length) in expired {
+            if let Err(err) = abort_upload(state, upload, length).await {
+                event!(Level::ERROR, "error deleting expired upload: {err}");
+            }
+        }
+    }
+
+    /// Delete the uploads of all sessions. Used when the server shuts down,
+    /// since sessions are only kept in memory and can't be resumed afterwards.
+    #[instrument(skip_all)]
+    pub async fn abort_all(&self, state: &AppState) {
+        let sessions = std::mem::take(&mut *self.sessions.lock().unwrap());
+        for session in sessions.into_values() {
+            let mut session = session.lock().await;
+            let Some(upload) = session.upload.take() else {
+                continue;
+            };
+            let id = upload.metadata.id.clone();
+            event!(Level::INFO, id, "deleting unfinished tus upload");
+            if let Err(err) = abort_upload(state, upload, session.length).await {
+                event!(Level::ERROR, id, "error deleting unfinished upload: {err}");
+            }
+        }
+    }
+}
+
+/// Response after creating a tus session
+#[derive(Debug, Clone, Serialize)]
+pub struct TusCreateResponse {
+    /// ID the upload will have
+    pub id: String,
+    /// url the upload will be at once it's finished (signed if the upload is
+    /// private)
+    pub url: String,
+    /// direct url to download the raw file once the upload is finished (signed
+    /// if the upload is private)
+    pub direct_url: String,
+    /// key to delete the upload later before it's expired
+    pub delete_key: String,
+    /// date the signed links stop working, null if the upload isn't private
+    pub link_expiry_date: Option<DateTime<Utc>>,
+    /// date the session expires if no more data is sent
+    pub session_expiry_date: DateTime<Utc>,
+}
+
+/// Errors with a tus upload
+#[derive(Debug, Error, Display)]
+pub enum TusError {
+    /// unsupported tus version, this server supports 1.0.0
+    UnsupportedVersion,
+    /// no upload session was found at the specified id
+    NotFound,
+    /// error parsing `{name}` header
+    ParseHeader { name: String, source: anyhow::Error },
+    /// `Content-Type` must be `application/offset+octet-stream`
+    UnsupportedMediaType,
+    /// `Upload-Offset` does not match the current offset ({0})
+    OffsetMismatch(u64),
+    /// another request is already sending data to this upload
+    Busy,
+    /// more data was sent than the declared `Upload-Length`
+    ExceedsLength,
+    /// error creating upload
+    Upload(#[source] UploadError),
+
+    /// internal server error
+    InternalServer(#[from] anyhow::Error),
+}
+impl From<UploadError> for TusError {
+    fn from(err: UploadError) -> Self {
+        match err {
+            UploadError::ParseHeader { name, source } => Self::ParseHeader { name, source },
+            UploadError::InternalServer(e) => Self::InternalServer(e),
+            e => Self::Upload(e),
+        }
+    }
+}
+impl IntoResponse for TusError {
+    fn into_response(self) -> Response {
+        let code = match self {
+            Self::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::ParseHeader { name: _, source: _ } => StatusCode::BAD_REQUEST,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::OffsetMismatch(_) => StatusCode::CONFLICT,
+            Self::Busy => StatusCode::CONFLICT,
+            Self::ExceedsLength => StatusCode::BAD_REQUEST,
+            Self::Upload(ref e) => e.status_code(),
+            Self::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let mut resp = self.into_response_with_code(code);
+        if code == StatusCode::PRECONDITION_FAILED {
+            resp.headers_mut()
+                .insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+        }
+        if code == StatusCode::UNAUTHORIZED {
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        resp
+    }
+}
+
+/// Make sure the client speaks the same version of the tus protocol
+fn check_version(headers: &HeaderMap) -> Result<(), TusError> {
+    match headers.get("Tus-Resumable") {
+        Some(v) if v == TUS_VERSION => Ok(()),
+        _ => Err(TusError::UnsupportedVersion),
+    }
+}
+
+/// Parse a header containing a number of bytes
+fn parse_u64_header(headers: &HeaderMap, name: &str) -> Result<Option<u64>, TusError> {
+    headers
+        .get(name)
+        .map(|v| {
+            v.to_str()
+                .context("error converting to string")
+                .and_then(|v| v.parse().context("error parsing number"))
+                .map_err(|source| TusError::ParseHeader {
+                    name: name.to_string(),
+                    source,
+                })
+        })
+        .transpose()
+}
+
+/// Parse the `Upload-Metadata` header, which is a comma-separated list of keys
+/// and base64-encoded values.
+fn parse_upload_metadata(value: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = String::from_utf8(
+            STANDARD
+                .decode(value.trim())
+                .with_context(|| format!("error decoding base64 value of `{key}`"))?,
+        )
+        .with_context(|| format!("value of `{key}` is not UTF-8"))?;
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+/// Format a date for the `Upload-Expires` header
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Get the capabilities of the tus server
+///
+/// # Request
+///
+/// `OPTIONS /api/v1/tus`
+///
+/// # Response
+///
+/// - 204 No Content
+/// - `Tus-Version`, `Tus-Extension` and `Tus-Max-Size` headers
+pub async fn options(State(state): State<&'static AppState>) -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            ("Tus-Version", TUS_VERSION.to_string()),
+            ("Tus-Extension", TUS_EXTENSIONS.to_string()),
+            ("Tus-Max-Size", state.max_file_size.to_string()),
+        ],
+    )
+}
+
+/// Create a tus upload session
+///
+/// # Request
+///
+/// `POST /api/v1/tus`
+///
+/// ## Headers
+///
+/// - `Tus-Resumable` (required) -- must be `1.0.0`
+/// - `Upload-Length` (required) -- size of the whole file in bytes
+/// - `Authorization` -- same as [`super::upload::put`]
+/// - `Bobashare-Proof-Of-Work` -- same as [`super::upload::put`]
+/// - `Upload-Metadata` (optional) -- comma-separated list of keys and
+///   base64-encoded values. `filename` and `filetype` set the name and MIME
+///   type of the file, and the multipart field names of
+///   [`super::upload::post`] (like `expiry` or `delete_key`) set the options
+///   of the upload. Uploads always get a random ID, even if the server uses
+///   content hash IDs.
+/// - any of the `Bobashare-*` headers accepted by [`super::upload::put`], which
+///   are overridden by `Upload-Metadata`
+///
+/// # Response
+///
+/// ## Success
+///
+/// - 201 Created
+/// - `Location` header containing the URL of the session, which data should be
+///   sent to with `PATCH`. It contains a random session ID (not the ID of the
+///   upload), so it should be kept secret until the upload is finished.
+/// - `Upload-Expires` header with when the session expires if no data is sent
+/// - JSON body created from [`TusCreateResponse`]
+#[instrument(skip(state, ip, headers), fields(id))]
+pub async fn create(
+    State(state): State<&'static AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, TusError> {
+    check_version(&headers)?;
+    let length =
+        parse_u64_header(&headers, "Upload-Length")?.ok_or_else(|| TusError::ParseHeader {
+            name: String::from("Upload-Length"),
+            source: anyhow::anyhow!("header is required"),
+        })?;
+    let limits = UploadLimits::from_headers(state, ip, &headers)
+        .await
+        .map_err(UploadError::from)?;
+    let mut metadata = headers
+        .get("Upload-Metadata")
+        .map(|m| {
+            m.to_str()
+                .context("error converting to string")
+                .and_then(parse_upload_metadata)
+                .map_err(|source| TusError::ParseHeader {
+                    name: String::from("Upload-Metadata"),
+                    source,
+                })
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let mut options = UploadOptions::from_headers(state, limits, &headers)?;
+    for (_, field) in OPTIONS {
+        if let Some(value) = metadata.get(field) {
+            options
+                .set(field, value)
+                .map_err(|source| TusError::ParseHeader {
+                    name: format!("Upload-Metadata: {field}"),
+                    source,
+                })?;
+        }
+    }
+    let filename = metadata
+        .remove("filename")
+        .unwrap_or_else(|| String::from("upload"));
+    let mimetype = metadata
+        .get("filetype")
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let link_expiry = options.link_expiry;
+
+    // the whole file is reserved, since its size is known
+    let api_key = options.limits.api_key.clone();
+    reserve_quota(state, &options.limits, Some(length)).await?;
+    let (upload, delete_key) = match create_upload(state, &filename, mimetype, options).await {
+        Ok(created) => created,
+        Err(e) => {
+            release_quota(state, api_key.as_deref(), length).await;
+            return Err(e.into());
+        }
+    };
+    let (url, direct_url, link_expiry_date) = upload_urls(state, &upload.metadata, link_expiry);
+    let id = upload.metadata.id.clone();
+    let session_id = generate_randomized_id(SESSION_ID_LENGTH);
+    let location = state
+        .base_url
+        .join(&format!("api/v1/tus/{session_id}"))
+        .unwrap();
+
+    let expires = Utc::now() + state.tus_session_timeout;
+    if length == 0 {
+        event!(Level::INFO, "upload is empty, finishing it immediately");
+        finish_upload(state, upload, delete_key.clone(), link_expiry).await?;
+    } else {
+        state.tus_sessions.insert(
+            session_id,
+            TusSession {
+                upload: Some(upload),
+                length,
+                offset: 0,
+                expires,
+                link_expiry,
+                delete_key: delete_key.clone(),
+            },
+        );
+    }
+    event!(Level::INFO, length, "created tus session");
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, location.to_string()),
+            (
+                header::HeaderName::from_static("upload-expires"),
+                http_date(expires),
+            ),
+        ],
+        Json(TusCreateResponse {
+            id,
+            url: url.to_string(),
+            direct_url: direct_url.to_string(),
+            delete_key,
+            link_expiry_date,
+            session_expiry_date: expires,
+        }),
+    ))
+}
+
+/// Get the current offset of a tus upload session
+///
+/// # Request
+///
+/// `HEAD /api/v1/tus/:session`
+///
+/// # Response
+///
+/// - 200 OK
+/// - `Upload-Offset` header with the number of bytes received so far
+/// - `Upload-Length` header with the size of the whole file
+#[instrument(skip_all, fields(id))]
+pub async fn head(
+    State(state): State<&'static AppState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, TusError> {
+    check_version(&headers)?;
+    let session = state
+        .tus_sessions
+        .get(&session_id)
+        .ok_or(TusError::NotFound)?;
+    let session = session.try_lock().map_err(|_| TusError::Busy)?;
+    if let Some(upload) = &session.upload {
+        tracing::Span::current().record("id", &upload.metadata.id);
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            ("Upload-Offset", session.offset.to_string()),
+            ("Upload-Length", session.length.to_string()),
+            ("Upload-Expires", http_date(session.expires)),
+            ("Cache-Control", String::from("no-store")),
+        ],
+    ))
+}
+
+/// Send data to a tus upload session
+///
+/// # Request
+///
+/// `PATCH /api/v1/tus/:session`
+///
+/// ## Headers
+///
+/// - `Tus-Resumable` (required) -- must be `1.0.0`
+/// - `Content-Type` (required) -- must be `application/offset+octet-stream`
+/// - `Upload-Offset` (required) -- offset the data starts at, which must be the
+///   current offset of the session
+///
+/// ## Body
+///
+/// The next part of the file
+///
+/// # Response
+///
+/// - 204 No Content
+/// - `Upload-Offset` header with the new offset. Once it's equal to the
+///   `Upload-Length`, the upload is finished and can be found at the URL given
+///   when the session was created.
+#[instrument(skip_all, fields(id))]
+pub async fn patch(
+    State(state): State<&'static AppState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, TusError> {
+    check_version(&headers)?;
+    if headers
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|c| c != "application/offset+octet-stream")
+    {
+        return Err(TusError::UnsupportedMediaType);
+    }
+    let offset =
+        parse_u64_header(&headers, "Upload-Offset")?.ok_or_else(|| TusError::ParseHeader {
+            name: String::from("Upload-Offset"),
+            source: anyhow::anyhow!("header is required"),
+        })?;
+
+    let session_ref = state
+        .tus_sessions
+        .get(&session_id)
+        .ok_or(TusError::NotFound)?;
+    let mut session = session_ref.try_lock().map_err(|_| TusError::Busy)?;
+    if offset != session.offset {
+        return Err(TusError::OffsetMismatch(session.offset));
+    }
+    let Some(mut upload) = session.upload.take() else {
+        return Err(TusError::NotFound);
+    };
+    tracing::Span::current().record("id", &upload.metadata.id);
+
+    event!(Level::DEBUG, offset, "receiving data for tus session");
+    let mut body = pin!(body.into_data_stream());
+    let mut shutdown_rx = state.shutdown_tx.subscribe();
+    let res = loop {
+        let chunk = tokio::select! {
+            chunk = body.try_next() => chunk,
+            _ = shutdown_rx.recv() => {
+                event!(Level::INFO, "server is shutting down; stopping tus request");
+                break Ok(());
+            }
+        };
+        match chunk {
+            Ok(Some(c)) => {
+                if session.offset + c.len() as u64 > session.length {
+                    break Err(TusError::ExceedsLength);
+                }
+                if let Err(e) = upload.file.write_all(&c).await {
+                    break Err(TusError::InternalServer(
+                        anyhow::Error::new(e).context("error writing to file"),
+                    ));
+                }
+                session.offset += c.len() as u64;
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => {
+                // the data received so far is kept, so the client can resume
+                event!(Level::INFO, "tus request was interrupted: {e}");
+                break Ok(());
+            }
+        }
+    };
+    upload
+        .file
+        .flush()
+        .await
+        .context("error flushing upload file")?;
+    session.expires = Utc::now() + state.tus_session_timeout;
+
+    if let Err(e) = res {
+        event!(Level::INFO, "invalid tus request; deleting upload");
+        state.tus_sessions.remove(&session_id);
+        abort_upload(state, upload, session.length).await?;
+        return Err(e);
+    }
+
+    let offset = session.offset.to_string();
+    let expires = http_date(session.expires);
+    if session.offset == session.length {
+        event!(Level::INFO, "received whole file, finishing tus upload");
+        state.tus_sessions.remove(&session_id);
+        finish_upload(
+            state,
+            upload,
+            session.delete_key.clone(),
+            session.link_expiry,
+        )
+        .await?;
+    } else {
+        session.upload = Some(upload);
+    }
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [("Upload-Offset", offset), ("Upload-Expires", expires)],
+    ))
+}
+
+/// Cancel a tus upload session and delete the data received so far
+///
+/// # Request
+///
+/// `DELETE /api/v1/tus/:session`
+///
+/// # Response
+///
+/// - 204 No Content
+#[instrument(skip_all, fields(id))]
+pub async fn terminate(
+    State(state): State<&'static AppState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, TusError> {
+    check_version(&headers)?;
+    let session = state
+        .tus_sessions
+        .get(&session_id)
+        .ok_or(TusError::NotFound)?;
+    let mut session = session.try_lock().map_err(|_| TusError::Busy)?;
+    state.tus_sessions.remove(&session_id);
+    let upload = session.upload.take().ok_or(TusError::NotFound)?;
+    tracing::Span::current().record("id", &upload.metadata.id);
+    abort_upload(state, upload, session.length).await?;
+    event!(Level::INFO, "terminated tus session");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_metadata() {
+        let metadata =
+            parse_upload_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+    }
+    #[test]
+    fn upload_metadata_invalid_base64() {
+        assert!(parse_upload_metadata("filename not-base64!").is_err());
+    }
+}
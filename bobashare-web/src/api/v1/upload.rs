@@ -1,37 +1,137 @@
 //! API to create an upload
 
-use std::io::SeekFrom;
+use std::{io::SeekFrom, pin::pin, sync::atomic::Ordering};
 
 use anyhow::Context;
 use axum::{
-    body::Body,
-    extract::{Path, State},
+    body::{Body, Bytes},
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Path, State,
+    },
     response::{IntoResponse, Response},
     Json,
 };
 use axum_extra::{extract::WithRejection, typed_header::TypedHeaderRejection, TypedHeader};
-use bobashare::{generate_randomized_id, storage::file::CreateUploadError};
+use bobashare::{
+    generate_delete_key, hash_custom_delete_key, hash_delete_key, hash_password,
+    id::{content_hash_id, IdStrategy},
+    sanitize_filename,
+    sniff::{detect_mimetype, SNIFF_LENGTH},
+    storage::{
+        file::{ApiKeyUsageError, CreateUploadError, RenameUploadError},
+        handle::UploadHandle,
+    },
+};
 use chrono::{DateTime, TimeDelta, Utc};
 use displaydoc::Display;
-use futures_util::TryStreamExt;
-use headers::{ContentLength, ContentType};
-use hyper::{header, HeaderMap, StatusCode};
+use futures_util::{Stream, TryStreamExt};
+use headers::{ContentLength, ContentType, HeaderMapExt};
+use hyper::{header, header::HeaderValue, HeaderMap, StatusCode};
+use mime::Mime;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 use tracing::{event, instrument, Instrument, Level};
 
-use super::ApiErrorExt;
-use crate::{clamp_expiry, str_to_duration, AppState};
+use super::{parse_link_expiry, upload_urls, ApiErrorExt};
+use crate::{
+    api_key::{AuthError, UploadLimits},
+    clamp_expiry,
+    client_ip::ClientIp,
+    str_to_duration, AppState,
+};
+
+/// Maximum length of an upload's title, in characters
+pub const MAX_TITLE_LENGTH: usize = 256;
+/// Maximum length of an upload's description, in characters
+pub const MAX_DESCRIPTION_LENGTH: usize = 4096;
+/// Maximum number of tags on an upload
+pub const MAX_TAGS: usize = 16;
+/// Maximum length of each tag, in characters
+pub const MAX_TAG_LENGTH: usize = 64;
+/// Maximum length of a custom upload ID
+pub const MAX_CUSTOM_ID_LENGTH: usize = 64;
+/// How many more random IDs to try if the first one is already used, each one
+/// character longer than the last
+pub const MAX_ID_RETRIES: usize = 4;
+/// IDs that can't be used for custom uploads, since they would clash with
+/// other routes
+pub const RESERVED_IDS: [&str; 6] = ["about", "api", "paste", "raw", "static", "upload"];
+/// Maximum number of files in one multipart request, which along with the
+/// maximum file size limits how much can be sent in one request
+pub const MAX_FILES_PER_REQUEST: usize = 16;
+/// Maximum number of fields that aren't files in one multipart request, enough
+/// to set every option for every file
+pub const MAX_OPTION_FIELDS_PER_REQUEST: usize = OPTIONS.len() * MAX_FILES_PER_REQUEST;
+/// Maximum total size of the fields that aren't files in one multipart
+/// request, in bytes
+pub const MAX_OPTION_FIELDS_SIZE: usize = 256 * 1024;
+
+/// Check that a custom upload ID only contains ASCII letters, digits, `-` and
+/// `_`, is at most [`MAX_CUSTOM_ID_LENGTH`] long, and isn't one of the
+/// [`RESERVED_IDS`] (in any case).
+pub(crate) fn parse_custom_id(id: &str) -> Result<String, anyhow::Error> {
+    let id = id.trim();
+    if id.is_empty() {
+        anyhow::bail!("ID must not be empty");
+    }
+    if id.len() > MAX_CUSTOM_ID_LENGTH {
+        anyhow::bail!("ID must be at most {MAX_CUSTOM_ID_LENGTH} characters long");
+    }
+    if let Some(c) = id
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '-' && *c != '_')
+    {
+        anyhow::bail!(
+            "ID contains invalid character `{c}` (only letters, digits, `-` and `_` are allowed)"
+        );
+    }
+    if RESERVED_IDS.iter().any(|r| r.eq_ignore_ascii_case(id)) {
+        anyhow::bail!("ID `{id}` is reserved");
+    }
+    Ok(id.to_string())
+}
+
+/// Check that `text` is at most `max` characters long, returning [`None`] if
+/// it's empty.
+pub(crate) fn parse_text_field(text: &str, max: usize) -> Result<Option<String>, anyhow::Error> {
+    let text = text.trim();
+    if text.chars().count() > max {
+        anyhow::bail!("must be at most {max} characters long");
+    }
+    Ok(Some(text)
+        .filter(|t| !t.is_empty())
+        .map(ToString::to_string))
+}
+
+/// Parse a comma-separated list of tags, ignoring empty and duplicate tags.
+pub(crate) fn parse_tags(tags: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut parsed = Vec::new();
+    for tag in tags.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            anyhow::bail!("tag `{tag}` is longer than {MAX_TAG_LENGTH} characters");
+        }
+        if !parsed.iter().any(|t| t == tag) {
+            parsed.push(tag.to_string());
+        }
+    }
+    if parsed.len() > MAX_TAGS {
+        anyhow::bail!("there can be at most {MAX_TAGS} tags");
+    }
+    Ok(parsed)
+}
 
 /// The JSON API response after uploading a file
 #[derive(Debug, Clone, Serialize)]
 pub struct UploadResponse {
     /// ID of the upload (used in URL)
     pub id: String,
-    /// url to the upload
+    /// url to the upload (signed if the upload is private)
     pub url: String,
-    /// direct url to download the raw uploaded file
+    /// direct url to download the raw uploaded file (signed if the upload is
+    /// private)
     pub direct_url: String,
     /// the name of the file
     pub filename: String,
@@ -41,6 +141,17 @@ pub struct UploadResponse {
     pub expiry_date: Option<DateTime<Utc>>,
     /// key to delete the upload later before it's expired
     pub delete_key: String,
+    /// number of times the upload can be viewed before it's deleted, null if
+    /// unlimited
+    pub remaining_views: Option<u64>,
+    /// date the signed links stop working, null if the upload isn't private
+    pub link_expiry_date: Option<DateTime<Utc>>,
+    /// title of the upload, null if not set
+    pub title: Option<String>,
+    /// description of the upload, null if not set
+    pub description: Option<String>,
+    /// tags of the upload
+    pub tags: Vec<String>,
 }
 
 /// Errors that could occur during upload
@@ -48,10 +159,28 @@ pub struct UploadResponse {
 pub enum UploadError {
     /// an upload already exists with the same id
     AlreadyExists,
+    /// custom IDs are not allowed
+    CustomIdNotAllowed,
     /// error parsing `{name}` header
     ParseHeader { name: String, source: anyhow::Error },
+    /// error parsing `{name}` field
+    ParseField { name: String, source: anyhow::Error },
+    /// error reading multipart body
+    ReadMultipart(#[source] MultipartError),
+    /// no file was uploaded
+    MissingFile,
+    /// too many files in one request (at most {max})
+    TooManyFiles { max: usize },
+    /// too many fields in one request (at most {max})
+    TooManyFields { max: usize },
+    /// fields are too large in total (at most {max} bytes)
+    FieldsTooLarge { max: usize },
     /// file is too large ({size} > {max})
     TooLarge { size: u64, max: u64 },
+    /// error authenticating
+    Auth(#[from] AuthError),
+    /// upload would go over the API key's quota of {quota} bytes
+    QuotaExceeded { quota: u64 },
 
     /// upload was cancelled
     Cancelled(#[source] anyhow::Error),
@@ -67,16 +196,34 @@ impl From<TypedHeaderRejection> for UploadError {
         }
     }
 }
-impl IntoResponse for UploadError {
-    fn into_response(self) -> Response {
-        let code = match self {
+impl UploadError {
+    /// The status code to respond with
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
             Self::AlreadyExists => StatusCode::CONFLICT,
+            Self::CustomIdNotAllowed => StatusCode::FORBIDDEN,
             Self::ParseHeader { name: _, source: _ } => StatusCode::BAD_REQUEST,
+            Self::ParseField { name: _, source: _ } => StatusCode::BAD_REQUEST,
+            Self::ReadMultipart(e) => e.status(),
+            Self::MissingFile => StatusCode::BAD_REQUEST,
+            Self::TooManyFiles { max: _ } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyFields { max: _ } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::FieldsTooLarge { max: _ } => StatusCode::PAYLOAD_TOO_LARGE,
             Self::TooLarge { size: _, max: _ } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Auth(AuthError::InternalServer(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Auth(AuthError::ParseHeader(_)) => StatusCode::BAD_REQUEST,
+            Self::Auth(AuthError::Banned) => StatusCode::FORBIDDEN,
+            Self::Auth(AuthError::ProofOfWork(e)) => e.status_code(),
+            Self::Auth(_) => StatusCode::UNAUTHORIZED,
+            Self::QuotaExceeded { quota: _ } => StatusCode::PAYLOAD_TOO_LARGE,
             Self::Cancelled(_) => StatusCode::INTERNAL_SERVER_ERROR, // unused
             Self::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-
+        }
+    }
+}
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        let code = self.status_code();
         if let Self::Cancelled(_) = self {
             let error = anyhow::Error::new(self);
             event!(
@@ -85,167 +232,478 @@ impl IntoResponse for UploadError {
                 "returning empty response to cancelled upload"
             );
             ().into_response()
+        } else if code == StatusCode::UNAUTHORIZED {
+            let mut resp = self.into_response_with_code(code);
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            resp
         } else {
             self.into_response_with_code(code)
         }
     }
 }
 
-/// Create an upload
-///
-/// # Request
-///
-/// `PUT /api/v1/upload/:filename`
-///
-/// NOTE: The first URL will use the randomized upload ID as the filename.
-///
-/// ## Headers
-///
-/// - `Content-Type` (required) -- mimetype -- the mime type (file format) of
-///   the file. Note that it will be ignored if the file is plaintext.
-/// - `Bobashare-Expiry` (optional) -- number -- duration until the upload
-///   should expire
-///   - specify `0` for no expiry
-///   - examples (see [`str_to_duration`] for more information):
-///     - `1d` -- 1 day
-///     - `1h` -- 1 hour
-///     - `1m` -- 1 minute
-///     - `1s` -- 1 second
-///
-/// - `Bobashare-Delete-Key` (optional) -- string -- custom key to use for
-///   deleting the file later; if not provided, one will be randomly generated
-///
-/// ## Body
-///
-/// Should contain the contents of the file to upload
-///
-/// # Response
-///
-/// ## Success
-///
-/// - 201 Created
-/// - `Location` header containing the URL of the upload
-/// - JSON body created from [`UploadResponse`]
-#[instrument(skip(state, filename, headers, body), fields(id))]
-pub async fn put(
-    state: State<&'static AppState>,
-    filename: Path<String>,
-    WithRejection(TypedHeader(mimetype), _): WithRejection<TypedHeader<ContentType>, UploadError>,
-    WithRejection(TypedHeader(content_length), _): WithRejection<
-        TypedHeader<ContentLength>,
-        UploadError,
-    >,
-    headers: HeaderMap,
-    body: Body,
-) -> Result<impl IntoResponse, UploadError> {
-    // hyper will automatically make sure the body is <= the content-length, so we
-    // can rely on it here
-    //
-    // also note that hyper seems to intercept the Content-Length header and return
-    // its own empty response instead of using WithRejection here
-    if content_length.0 > state.max_file_size {
-        event!(
-            Level::INFO,
-            size = content_length.0,
-            max = state.max_file_size,
-            "file is too large"
-        );
-        return Err(UploadError::TooLarge {
-            size: content_length.0,
-            max: state.max_file_size,
-        });
+/// Names of the upload options, as the header used to set them and the
+/// multipart field used to set them
+pub(crate) const OPTIONS: [(&str, &str); 10] = [
+    ("Bobashare-Id", "id"),
+    ("Bobashare-Expiry", "expiry"),
+    ("Bobashare-Delete-Key", "delete_key"),
+    ("Bobashare-Max-Views", "max_views"),
+    ("Bobashare-Password", "password"),
+    ("Bobashare-Private", "private"),
+    ("Bobashare-Link-Expiry", "link_expiry"),
+    ("Bobashare-Title", "title"),
+    ("Bobashare-Description", "description"),
+    ("Bobashare-Tags", "tags"),
+];
+
+/// Options for a new upload, set with `Bobashare-*` headers or multipart
+/// fields (see [`OPTIONS`])
+#[derive(Debug, Clone)]
+pub(crate) struct UploadOptions {
+    /// custom ID, or [`None`] to generate a random one
+    pub(crate) id: Option<String>,
+    pub(crate) expiry: Option<TimeDelta>,
+    pub(crate) delete_key: Option<String>,
+    pub(crate) remaining_views: Option<u64>,
+    /// plaintext password, which is only hashed once the upload is created
+    pub(crate) password: Option<String>,
+    pub(crate) private: bool,
+    pub(crate) link_expiry: Option<TimeDelta>,
+    pub(crate) title: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) tags: Vec<String>,
+    /// limits from the API key the upload is created with
+    pub(crate) limits: UploadLimits,
+}
+impl UploadOptions {
+    /// The options used if none are set
+    pub(crate) fn new(state: &AppState, limits: UploadLimits) -> Self {
+        Self {
+            id: None,
+            expiry: Some(state.default_expiry),
+            delete_key: None,
+            remaining_views: None,
+            password: None,
+            private: false,
+            link_expiry: None,
+            title: None,
+            description: None,
+            tags: Vec::new(),
+            limits,
+        }
     }
-    let id = generate_randomized_id(state.id_length);
-    tracing::Span::current().record("id", &id);
-    event!(Level::DEBUG, "generated random ID for upload");
 
-    let mimetype = mimetype.into();
+    /// Read all the options from their headers
+    pub(crate) fn from_headers(
+        state: &AppState,
+        limits: UploadLimits,
+        headers: &HeaderMap,
+    ) -> Result<Self, UploadError> {
+        let mut options = Self::new(state, limits);
+        for (header, field) in OPTIONS {
+            let Some(value) = headers.get(header) else {
+                continue;
+            };
+            // some of these are free text, so accept UTF-8 instead of only
+            // visible ASCII
+            std::str::from_utf8(value.as_bytes())
+                .map_err(|e| anyhow::Error::new(e).context("error converting to UTF-8"))
+                .and_then(|v| options.set(field, v))
+                .map_err(|source| UploadError::ParseHeader {
+                    name: header.to_string(),
+                    source,
+                })?;
+        }
+        Ok(options)
+    }
 
-    let expiry = match headers.get("Bobashare-Expiry") {
-        // if header not found, use default expiry
-        None => {
-            event!(
-                Level::DEBUG,
-                "`Bobashare-Expiry` header not provided, using default"
-            );
-            Some(state.default_expiry)
+    /// Set the option with the multipart field name `field`
+    pub(crate) fn set(&mut self, field: &str, value: &str) -> Result<(), anyhow::Error> {
+        match field {
+            "id" => self.id = Some(parse_custom_id(value)?),
+            "expiry" => {
+                event!(Level::DEBUG, "requested expiry is {}", value);
+                let expiry = if value == "never" {
+                    None
+                } else {
+                    Some(
+                        TimeDelta::from_std(
+                            str_to_duration(value).context("error parsing duration string")?,
+                        )
+                        .context("error converting duration")?,
+                    )
+                };
+                // TODO: should we return an error if expiry is too large instead?
+                self.expiry = clamp_expiry(self.limits.max_expiry, expiry);
+            }
+            "delete_key" => {
+                event!(Level::DEBUG, "custom delete key was provided");
+                self.delete_key = Some(value.to_string());
+            }
+            "max_views" => match value.parse::<u64>().context("error parsing number")? {
+                0 => anyhow::bail!("maximum views must be at least 1"),
+                n => self.remaining_views = Some(n),
+            },
+            "password" => {
+                if value.is_empty() {
+                    anyhow::bail!("password must not be empty");
+                }
+                event!(Level::DEBUG, "upload will be password protected");
+                self.password = Some(value.to_string());
+            }
+            "private" => {
+                self.private = match value {
+                    "true" => true,
+                    "false" => false,
+                    p => anyhow::bail!("expected `true` or `false`, got `{p}`"),
+                }
+            }
+            "link_expiry" => self.link_expiry = Some(parse_link_expiry(value)?),
+            "title" => self.title = parse_text_field(value, MAX_TITLE_LENGTH)?,
+            "description" => self.description = parse_text_field(value, MAX_DESCRIPTION_LENGTH)?,
+            "tags" => self.tags = parse_tags(value)?,
+            _ => unreachable!("unknown upload option `{field}`"),
         }
-        // otherwise, clamp the requested expiry to the max
-        Some(e) => {
-            let expiry = e.to_str().map_err(|e| UploadError::ParseHeader {
-                name: String::from("Bobashare-Expiry"),
-                source: anyhow::Error::new(e).context("error converting to string"),
-            })?;
-
-            event!(Level::DEBUG, "`Bobashare-Expiry` header says {}", expiry);
-
-            let expiry = if expiry == "never" {
-                None
-            } else {
-                Some(
-                    TimeDelta::from_std(str_to_duration(expiry).map_err(|e| {
-                        UploadError::ParseHeader {
-                            name: String::from("Bobashare-Expiry"),
-                            source: anyhow::Error::new(e).context("error parsing duration string"),
-                        }
-                    })?)
-                    .map_err(|e| UploadError::ParseHeader {
-                        name: String::from("Bobashare-Expiry"),
-                        source: anyhow::Error::new(e).context("error converting duration"),
-                    })?,
-                )
-            };
+        Ok(())
+    }
+}
 
-            // TODO: should we return an error if expiry is too large instead?
-            clamp_expiry(state.max_expiry, expiry)
+/// Create a new upload with a random or custom ID and the given options,
+/// returning a locked handle to it that the file can be written to, along with
+/// its delete key (only a hash of which is stored).
+///
+/// The filename is cleaned up with [`sanitize_filename`]. If a random ID is
+/// already used, up to [`MAX_ID_RETRIES`] longer ones are tried.
+pub(crate) async fn create_upload(
+    state: &AppState,
+    filename: &str,
+    mimetype: Mime,
+    options: UploadOptions,
+) -> Result<(UploadHandle, String), UploadError> {
+    if options.id.is_some() && !options.limits.allow_custom_id {
+        event!(Level::INFO, "custom ID was requested but isn't allowed");
+        return Err(UploadError::CustomIdNotAllowed);
+    }
+    event!(
+        Level::DEBUG,
+        expiry = %options.expiry.map_or_else(|| String::from("never"), |e| e.to_string()),
+        remaining_views = options.remaining_views,
+        private = options.private,
+        link_expiry = ?options.link_expiry,
+        title = options.title,
+        description = options.description,
+        tags = ?options.tags,
+    );
+
+    let sanitized = sanitize_filename(filename);
+    if sanitized != filename {
+        event!(Level::INFO, filename = sanitized, "sanitized filename");
+    }
+    // Argon2 is slow on purpose, so hash with it on a blocking thread
+    let (delete_key, delete_key_hash) = match options.delete_key {
+        Some(key) => {
+            let hash = tokio::task::spawn_blocking({
+                let key = key.clone();
+                move || hash_custom_delete_key(&key)
+            })
+            .await
+            .context("error hashing delete key")?;
+            (key, hash)
+        }
+        None => {
+            let key = generate_delete_key();
+            let hash = hash_delete_key(&key);
+            (key, hash)
         }
     };
-    event!(Level::DEBUG, expiry = %expiry.map_or_else(|| String::from("never"), |e| e.to_string()));
-
-    let delete_key = headers
-        .get("Bobashare-Delete-Key")
-        .map(|k| {
-            k.to_str().map_err(|e| UploadError::ParseHeader {
-                name: String::from("Bobashare-Delete-Key"),
-                source: anyhow::Error::new(e).context("error converting to string"),
-            })
-        })
-        .transpose()?
-        .map(ToString::to_string);
-    if delete_key.is_some() {
-        event!(Level::DEBUG, delete_key, "custom delete key was provided");
-    } else {
-        event!(Level::DEBUG, "delete_key will be randomly generated");
-    }
+    let password_hash = match options.password {
+        Some(password) => Some(
+            tokio::task::spawn_blocking(move || hash_password(&password))
+                .await
+                .context("error hashing password")?,
+        ),
+        None => None,
+    };
+    let mut length = state
+        .id_strategy
+        .length_for(state.id_length, state.upload_count.load(Ordering::Relaxed));
+    let mut retries = 0;
+    let mut upload = loop {
+        let id = match &options.id {
+            Some(id) => {
+                event!(Level::DEBUG, id, "using custom ID for upload");
+                id.clone()
+            }
+            None => {
+                let id = state.id_strategy.generate(length);
+                event!(Level::DEBUG, id, "generated random ID for upload");
+                id
+            }
+        };
+        tracing::Span::current().record("id", &id);
 
-    let mut upload = state
-        .backend
-        .create_upload(&id, &filename, mimetype, expiry, delete_key)
-        .await
-        .map_err(|e| {
-            if let CreateUploadError::AlreadyExists = e {
-                UploadError::AlreadyExists
-            } else {
-                UploadError::InternalServer(
+        let res = state
+            .backend
+            .create_upload(
+                id.as_str(),
+                &sanitized,
+                mimetype.clone(),
+                options.expiry,
+                delete_key_hash.clone(),
+            )
+            .await;
+        match res {
+            Ok(upload) => break upload,
+            Err(CreateUploadError::AlreadyExists)
+                if options.id.is_none() && retries < MAX_ID_RETRIES =>
+            {
+                event!(
+                    Level::INFO,
+                    id,
+                    "ID is already used, retrying with a longer one"
+                );
+                retries += 1;
+                length += 1;
+            }
+            Err(CreateUploadError::AlreadyExists) => return Err(UploadError::AlreadyExists),
+            Err(e) => {
+                return Err(UploadError::InternalServer(
                     anyhow::Error::new(e).context("error while initializing upload"),
-                )
+                ))
             }
-        })?;
+        }
+    };
+    upload.metadata.remaining_views = options.remaining_views;
+    upload.metadata.password_hash = password_hash;
+    upload.metadata.private = options.private;
+    upload.metadata.title = options.title;
+    upload.metadata.description = options.description;
+    upload.metadata.tags = options.tags;
+    upload.metadata.api_key = options.limits.api_key;
+    upload.metadata.uploader_ip = options.limits.uploader_ip;
     event!(
         Level::TRACE,
         upload = format!("{upload:?}"),
         "created upload handle"
     );
+    Ok((upload, delete_key))
+}
+
+/// Finish an upload whose file has been completely written: detect its type
+/// (see [`bobashare::sniff`]), flush its metadata to disk and unlock it.
+pub(crate) async fn finish_upload(
+    state: &AppState,
+    mut upload: UploadHandle,
+    delete_key: String,
+    link_expiry: Option<TimeDelta>,
+) -> Result<UploadResponse, UploadError> {
+    let detect_mimetype_span = tracing::span!(Level::INFO, "detect_mimetype");
+    async {
+        tracing::event!(Level::INFO, "detecting the type of the upload");
+        let upload = &mut upload;
+        if let Err(err) = upload.file.seek(SeekFrom::Start(0)).await {
+            tracing::event!(Level::ERROR, ?err, "error seeking to beginning of file");
+            return;
+        };
+        // a single read can return less than is available, so keep reading
+        // until the buffer is full or the file ends
+        let mut buf = Vec::with_capacity(SNIFF_LENGTH);
+        if let Err(err) = (&mut upload.file)
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut buf)
+            .await
+        {
+            tracing::event!(Level::ERROR, ?err, "error reading start of file");
+            return;
+        }
+
+        let mimetype = detect_mimetype(&upload.metadata.mimetype, &upload.metadata.filename, &buf);
+        tracing::event!(
+            Level::INFO,
+            declared = %upload.metadata.mimetype,
+            detected = %mimetype,
+            "detected type of upload"
+        );
+        upload.metadata.mimetype = mimetype;
+    }
+    .instrument(detect_mimetype_span)
+    .await;
+
+    let metadata = upload
+        .flush()
+        .await
+        .context("error flushing upload metadata to disk")?;
+    event!(Level::DEBUG, "flushed upload metadata to disk");
+    state.upload_count.fetch_add(1, Ordering::Relaxed);
+
+    let (url, direct_url, link_expiry_date) = upload_urls(state, &metadata, link_expiry);
+    let (url, direct_url) = (url.to_string(), direct_url.to_string());
+    event!(
+        Level::INFO,
+        url,
+        filename = metadata.filename,
+        mimetype = %metadata.mimetype,
+        expiry = %metadata
+            .expiry_date
+            .map_or_else(|| String::from("never"), |e| e.to_string()),
+        "successfully created upload"
+    );
+    Ok(UploadResponse {
+        id: metadata.id,
+        url,
+        direct_url,
+        filename: metadata.filename,
+        mimetype: metadata.mimetype.to_string(),
+        expiry_date: metadata.expiry_date,
+        delete_key,
+        remaining_views: metadata.remaining_views,
+        link_expiry_date,
+        title: metadata.title,
+        description: metadata.description,
+        tags: metadata.tags,
+    })
+}
+
+/// Unlock and delete an upload that couldn't be finished, giving back the
+/// `reserved` bytes of its API key's usage (see [`reserve_quota`]).
+pub(crate) async fn abort_upload(
+    state: &AppState,
+    mut upload: UploadHandle,
+    reserved: u64,
+) -> Result<(), UploadError> {
+    let id = upload.metadata.id.clone();
+    // the whole reservation is released below, so deleting the upload
+    // shouldn't release the size of the partial file too
+    let api_key = upload.metadata.api_key.take();
+    upload
+        .flush()
+        .await
+        .context("error flushing cancelled upload before deletion")?;
+    state
+        .backend
+        .delete_upload(id)
+        .await
+        .context("error deleting cancelled upload")?;
+    event!(Level::INFO, "upload was deleted successfully");
+    release_quota(state, api_key.as_deref(), reserved).await;
+    Ok(())
+}
+
+/// Get the size of the largest file that can be uploaded with `limits`, and
+/// reserve that much of the API key's usage so concurrent uploads can't go over
+/// its quota together.
+///
+/// The size is less than the maximum file size if the quota is almost used up.
+/// If the size of the file is already known, it's checked against that, and
+/// only `length` bytes are reserved. Whatever isn't used should be given back
+/// with [`release_quota`].
+pub(crate) async fn reserve_quota(
+    state: &AppState,
+    limits: &UploadLimits,
+    length: Option<u64>,
+) -> Result<u64, UploadError> {
+    if let Some(length) = length {
+        check_size(length, limits.max_file_size, limits)?;
+    }
+    let max = length.unwrap_or(limits.max_file_size);
+    let Some(name) = &limits.api_key else {
+        return Ok(max);
+    };
+    let reserved = match state
+        .backend
+        .reserve_api_key_usage(name, max, limits.quota)
+        .await
+    {
+        Ok(reserved) => reserved,
+        Err(ApiKeyUsageError::QuotaExceeded { quota }) => {
+            return Err(UploadError::QuotaExceeded { quota })
+        }
+        Err(e) => {
+            return Err(UploadError::InternalServer(
+                anyhow::Error::new(e).context("error reserving API key usage"),
+            ))
+        }
+    };
+    if let Some(length) = length.filter(|&l| l > reserved) {
+        release_quota(state, Some(name), reserved).await;
+        check_size(length, reserved, limits)?;
+    }
+    Ok(reserved)
+}
+
+/// Give back `bytes` of the usage reserved with [`reserve_quota`] for the API
+/// key named `api_key`, if there is one. Errors are only logged, since the
+/// upload has already succeeded or failed by then.
+pub(crate) async fn release_quota(state: &AppState, api_key: Option<&str>, bytes: u64) {
+    let Some(name) = api_key else {
+        return;
+    };
+    if let Err(err) = state.backend.release_api_key_usage(name, bytes).await {
+        event!(
+            Level::ERROR,
+            name,
+            bytes,
+            "error releasing API key usage: {err}"
+        );
+    }
+}
+
+/// Make sure a file of `size` bytes is at most `max` (from [`reserve_quota`]).
+pub(crate) fn check_size(size: u64, max: u64, limits: &UploadLimits) -> Result<(), UploadError> {
+    if size <= max {
+        return Ok(());
+    }
+    event!(Level::INFO, size, max, "file is too large");
+    match limits.quota {
+        Some(quota) if max < limits.max_file_size => Err(UploadError::QuotaExceeded { quota }),
+        _ => Err(UploadError::TooLarge { size, max }),
+    }
+}
+
+/// Create an upload with the contents of `body`, streaming it to disk.
+///
+/// `length` is the size of the file, if the client said what it is. The
+/// upload is deleted if it's larger than the maximum file size, if reading
+/// `body` fails, or if the server shuts down before it's done.
+#[instrument(skip_all, fields(id))]
+pub(crate) async fn receive_upload<E>(
+    state: &AppState,
+    filename: &str,
+    mimetype: Mime,
+    options: UploadOptions,
+    length: Option<u64>,
+    body: impl Stream<Item = Result<Bytes, E>>,
+) -> Result<UploadResponse, UploadError>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let link_expiry = options.link_expiry;
+    let limits = options.limits.clone();
+    let api_key = limits.api_key.as_deref();
+    let use_content_hash = state.id_strategy == IdStrategy::ContentHash && options.id.is_none();
+    let max_size = reserve_quota(state, &limits, length).await?;
+    let (mut upload, delete_key) = match create_upload(state, filename, mimetype, options).await {
+        Ok(created) => created,
+        Err(e) => {
+            release_quota(state, api_key, max_size).await;
+            return Err(e);
+        }
+    };
 
     let mut file_writer = BufWriter::new(&mut upload.file);
     event!(Level::DEBUG, "streaming file to disk");
     let stream_file_task = async {
-        let mut body = body.into_data_stream();
+        let mut body = pin!(body);
+        let mut size = 0;
         loop {
             let chunk = body.try_next().await.context("error reading body");
             match chunk {
                 Ok(ch) => match ch {
                     Some(c) => {
+                        size += c.len() as u64;
+                        check_size(size, max_size, &limits)?;
                         event!(
                             Level::TRACE,
                             "writing chunk of {} bytes to file buffer",
@@ -263,97 +721,440 @@ pub async fn put(
                 }
             }
         }
-        Ok(())
+        file_writer
+            .flush()
+            .await
+            .context("error flushing file buffer")?;
+        Ok(size)
     };
 
     let mut shutdown_rx = state.shutdown_tx.subscribe();
-    tokio::select! {
-        res = stream_file_task => {
-            if let Err(e) = res {
+    let size = tokio::select! {
+        res = stream_file_task => match res {
+            Ok(size) => size,
+            Err(e) => {
                 event!(Level::INFO, "upload was cancelled; it will be deleted");
-                upload
-                    .flush()
-                    .await
-                    .context("error flushing cancelled upload before deletion")?;
-                state
-                    .backend
-                    .delete_upload(id)
-                    .await
-                    .context("error deleting cancelled upload")?;
-                event!(Level::INFO, "upload was deleted successfully");
+                abort_upload(state, upload, max_size).await?;
                 return Err(e);
             }
         },
         _ = shutdown_rx.recv() => {
             event!(Level::INFO, "server is shutting down; deleting lock");
+            release_quota(state, api_key, max_size).await;
             upload.drop_lock().await.context("error deleting lock of cancelled upload")?;
             return Err(UploadError::InternalServer(anyhow::anyhow!("server is shutting down")));
         }
     };
 
-    file_writer
-        .flush()
-        .await
-        .context("error flushing file buffer")?;
-
-    let detect_plaintext_span = tracing::span!(Level::INFO, "detect_plaintext");
-    async {
-        tracing::event!(Level::INFO, "detecting whether the upload is plaintext");
-        let upload = &mut upload;
-        if let Err(err) = upload.file.seek(SeekFrom::Start(0)).await {
-            tracing::event!(Level::ERROR, ?err, "error seeking to beginning of file");
-            return;
-        };
-        let mut buf = [0; 1024];
-        if let Err(err) = upload.file.read(&mut buf).await {
-            tracing::event!(Level::ERROR, ?err, "error reading first 1024 bytes of file");
-            return;
-        };
-
-        // TODO: would be nice to support other text encodings
-        if std::str::from_utf8(&buf).is_ok() {
-            tracing::event!(Level::INFO, "upload is plaintext");
-            upload.metadata.mimetype = mime::TEXT_PLAIN_UTF_8;
-        } else {
-            tracing::event!(Level::INFO, "upload is not plaintext");
+    if use_content_hash {
+        if let Err(e) = use_content_hash_id(state, &mut upload).await {
+            abort_upload(state, upload, max_size).await?;
+            return Err(e);
         }
     }
-    .instrument(detect_plaintext_span)
-    .await;
 
-    let metadata = upload
-        .flush()
+    // only the size of the file stays reserved
+    release_quota(state, api_key, max_size - size).await;
+    finish_upload(state, upload, delete_key, link_expiry).await
+}
+
+/// Change the ID of an upload whose file has been completely written to one
+/// derived from the hash of the file (see [`content_hash_id`]).
+///
+/// If the ID is already used (for example, by an upload of the same file),
+/// up to [`MAX_ID_RETRIES`] longer ones are tried, and then the random ID is
+/// kept.
+async fn use_content_hash_id(
+    state: &AppState,
+    upload: &mut UploadHandle,
+) -> Result<(), UploadError> {
+    upload
+        .file
+        .seek(SeekFrom::Start(0))
         .await
-        .context("error flushing upload metadata to disk")?;
-    event!(Level::DEBUG, "flushed upload metadata to disk");
+        .context("error seeking to beginning of file")?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = upload
+            .file
+            .read(&mut buf)
+            .await
+            .context("error reading file to hash it")?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let hash = hasher.finalize();
 
-    // SAFETY: this shouldn't fail because `metadata.id` should be valid in a URL
-    let url = state.base_url.join(&metadata.id).unwrap().to_string();
-    let direct_url = state.raw_url.join(&metadata.id).unwrap().to_string();
+    let length = state
+        .id_strategy
+        .length_for(state.id_length, state.upload_count.load(Ordering::Relaxed));
+    for length in length..=length + MAX_ID_RETRIES {
+        let id = content_hash_id(&hash, length);
+        match state.backend.rename_upload(upload, &id).await {
+            Ok(()) => {
+                tracing::Span::current().record("id", &id);
+                event!(Level::DEBUG, id, "changed ID to content hash");
+                return Ok(());
+            }
+            Err(RenameUploadError::AlreadyExists) => {
+                event!(Level::DEBUG, id, "content hash ID is already used");
+            }
+            Err(e) => {
+                return Err(UploadError::InternalServer(
+                    anyhow::Error::new(e).context("error changing ID to content hash"),
+                ))
+            }
+        }
+    }
     event!(
         Level::INFO,
-        url,
-        filename = metadata.filename,
-        mimetype = %metadata.mimetype,
-        expiry = %metadata
-            .expiry_date
-            .map_or_else(|| String::from("never"), |e| e.to_string()),
-        "successfully created upload"
+        "every content hash ID is already used, keeping random ID"
     );
+    Ok(())
+}
+
+/// Create an upload
+///
+/// # Request
+///
+/// `PUT /api/v1/upload/:filename`
+///
+/// NOTE: The first URL will use the randomized upload ID as the filename.
+///
+/// ## Headers
+///
+/// - `Content-Type` (required) -- mimetype -- the mime type (file format) of
+///   the file. Note that it's only used if the type can't be detected from the
+///   contents of the file (see [`bobashare::sniff`]).
+/// - `Authorization` (optional) -- `Bearer` followed by an API key, whose
+///   limits are used instead of the server's (see [`UploadLimits`]). Required
+///   if the server requires API keys.
+/// - `Bobashare-Proof-Of-Work` -- `{challenge}:{solution}` -- solution to a
+///   challenge from [`super::challenge::challenge`]. Required without an API
+///   key if the server has proof of work enabled, in which case the file can't
+///   be larger than the size the challenge was created for.
+/// - `Bobashare-Id` (optional) -- string -- custom ID to use instead of a
+///   random one: at most [`MAX_CUSTOM_ID_LENGTH`] ASCII letters, digits, `-`
+///   and `_`, and not one of the [`RESERVED_IDS`]. 403 Forbidden is returned
+///   unless the server or the API key allows custom IDs, and 409 Conflict if
+///   the ID is already used.
+/// - `Bobashare-Expiry` (optional) -- number -- duration until the upload
+///   should expire
+///   - specify `0` for no expiry
+///   - examples (see [`str_to_duration`] for more information):
+///     - `1d` -- 1 day
+///     - `1h` -- 1 hour
+///     - `1m` -- 1 minute
+///     - `1s` -- 1 second
+///
+/// - `Bobashare-Delete-Key` (optional) -- string -- custom key to use for
+///   deleting the file later; if not provided, one will be randomly generated
+/// - `Bobashare-Max-Views` (optional) -- number -- delete the upload after it
+///   has been viewed or downloaded this many times (`1` for burn-after-read)
+/// - `Bobashare-Password` (optional) -- string -- password that will be needed
+///   to view or download the upload
+/// - `Bobashare-Private` (optional) -- `true` or `false` -- if `true`, the upload
+///   can only be accessed through a signed link instead of by its ID alone. The
+///   URLs in the response will be signed; more can be created with
+///   [`super::link::link`].
+/// - `Bobashare-Link-Expiry` (optional) -- duration until the signed links in
+///   the response stop working; defaults to when the upload expires
+/// - `Bobashare-Title` (optional) -- string -- title to show instead of the
+///   filename (at most [`MAX_TITLE_LENGTH`] characters)
+/// - `Bobashare-Description` (optional) -- string -- longer description of the
+///   upload (at most [`MAX_DESCRIPTION_LENGTH`] characters)
+/// - `Bobashare-Tags` (optional) -- comma-separated list of tags (at most
+///   [`MAX_TAGS`], each at most [`MAX_TAG_LENGTH`] characters)
+///
+/// The title, description and tags may contain UTF-8.
+///
+/// ## Body
+///
+/// Should contain the contents of the file to upload. `Content-Length` is
+/// optional, so the body can be sent with chunked transfer encoding if its
+/// size isn't known ahead of time. Either way, the upload is stopped and
+/// deleted as soon as it's larger than the maximum file size.
+///
+/// # Response
+///
+/// ## Success
+///
+/// - 201 Created
+/// - `Location` header containing the URL of the upload
+/// - JSON body created from [`UploadResponse`]
+#[instrument(skip(state, filename, ip, headers, body))]
+pub async fn put(
+    state: State<&'static AppState>,
+    filename: Path<String>,
+    ClientIp(ip): ClientIp,
+    WithRejection(TypedHeader(mimetype), _): WithRejection<TypedHeader<ContentType>, UploadError>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, UploadError> {
+    // the size is also checked while streaming, but if the client told us how
+    // large the file is, we can reject it before reading any of it
+    //
+    // hyper will automatically make sure the body is <= the content-length
+    let limits = UploadLimits::from_headers(&state, ip, &headers).await?;
+    let length = headers.typed_get().map(|ContentLength(size)| size);
+    if let Some(size) = length {
+        check_size(size, limits.max_file_size, &limits)?;
+    }
+
+    let options = UploadOptions::from_headers(&state, limits, &headers)?;
+    let resp = receive_upload(
+        &state,
+        &filename,
+        mimetype.into(),
+        options,
+        length,
+        body.into_data_stream(),
+    )
+    .await?;
     Ok((
         StatusCode::CREATED,
         [
-            (header::CONTENT_LOCATION, direct_url.clone()),
-            (header::LOCATION, url.clone()),
+            (header::CONTENT_LOCATION, resp.direct_url.clone()),
+            (header::LOCATION, resp.url.clone()),
         ],
-        Json(UploadResponse {
-            id,
-            url,
-            direct_url,
-            filename: metadata.filename,
-            mimetype: metadata.mimetype.to_string(),
-            expiry_date: metadata.expiry_date,
-            delete_key: metadata.delete_key,
-        }),
+        Json(resp),
     ))
 }
+
+/// Maximum size of a multipart field that isn't a file, in bytes
+const MAX_OPTION_FIELD_SIZE: usize = 64 * 1024;
+
+/// Read a multipart field that isn't a file as text, making sure it isn't too
+/// large to keep in memory
+pub(crate) async fn read_option_field(
+    field: &mut Field<'_>,
+    name: &str,
+) -> Result<String, UploadError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(UploadError::ReadMultipart)? {
+        if value.len() + chunk.len() > MAX_OPTION_FIELD_SIZE {
+            return Err(UploadError::ParseField {
+                name: name.to_string(),
+                source: anyhow::anyhow!("field is larger than {MAX_OPTION_FIELD_SIZE} bytes"),
+            });
+        }
+        value.extend_from_slice(&chunk);
+    }
+    String::from_utf8(value).map_err(|e| UploadError::ParseField {
+        name: name.to_string(),
+        source: anyhow::Error::new(e).context("error converting to UTF-8"),
+    })
+}
+
+/// Create one or more uploads from a multipart form
+///
+/// # Request
+///
+/// `POST /api/v1/upload`
+///
+/// ## Headers
+///
+/// - `Content-Type` (required) -- `multipart/form-data` with a boundary
+/// - `Authorization` -- same as [`put`]
+/// - `Bobashare-Proof-Of-Work` -- same as [`put`], but the size the challenge
+///   was created for is the limit for all the files together
+/// - any of the `Bobashare-*` headers accepted by [`put`], which apply to every
+///   file in the request
+///
+/// ## Body
+///
+/// A `multipart/form-data` body. Every field with a filename is uploaded as a
+/// separate file (at most [`MAX_FILES_PER_REQUEST`]), using the `Content-Type`
+/// of the field (which is only used if the type can't be detected, like with
+/// [`put`]). The options of [`put`] can also be set with fields named after
+/// them, which override the headers:
+///
+/// - `id` -- same as `Bobashare-Id`, but only applies to the next file
+/// - `expiry` -- same as `Bobashare-Expiry`
+/// - `delete_key` -- same as `Bobashare-Delete-Key`
+/// - `max_views` -- same as `Bobashare-Max-Views`
+/// - `password` -- same as `Bobashare-Password`
+/// - `private` -- same as `Bobashare-Private`
+/// - `link_expiry` -- same as `Bobashare-Link-Expiry`
+/// - `title` -- same as `Bobashare-Title`
+/// - `description` -- same as `Bobashare-Description`
+/// - `tags` -- same as `Bobashare-Tags`
+///
+/// Since files are streamed to disk as they are received, these fields only
+/// apply to files that come after them. Other fields are ignored. There can be
+/// at most [`MAX_OPTION_FIELDS_PER_REQUEST`] fields that aren't files, and the
+/// options can be at most [`MAX_OPTION_FIELDS_SIZE`] bytes in total.
+///
+/// If any file fails to upload, the files before it in the same request are
+/// deleted too.
+///
+/// # Response
+///
+/// ## Success
+///
+/// - 201 Created
+/// - JSON body containing an array of [`UploadResponse`], in the same order as
+///   the files, even if only one file was uploaded
+/// - if one file was uploaded: `Location` header containing the URL of the
+///   upload
+#[instrument(skip(state, ip, headers, multipart))]
+pub async fn post(
+    State(state): State<&'static AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, UploadError> {
+    let limits = UploadLimits::from_headers(state, ip, &headers).await?;
+    let max_file_size = limits.max_file_size;
+    let mut options = UploadOptions::from_headers(state, limits, &headers)?;
+    let mut uploads = Vec::new();
+
+    let res = async {
+        // total size of the files received so far
+        let mut received = 0;
+        // number and total size of the other fields received so far
+        let (mut option_fields, mut option_fields_size) = (0, 0);
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(UploadError::ReadMultipart)?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            let Some(filename) = field.file_name().map(ToString::to_string) else {
+                option_fields += 1;
+                if option_fields > MAX_OPTION_FIELDS_PER_REQUEST {
+                    event!(Level::INFO, "too many fields in multipart request");
+                    return Err(UploadError::TooManyFields {
+                        max: MAX_OPTION_FIELDS_PER_REQUEST,
+                    });
+                }
+                if !OPTIONS.iter().any(|&(_, f)| f == name) {
+                    event!(Level::DEBUG, name, "ignoring unknown field");
+                    continue;
+                }
+                let value = read_option_field(&mut field, &name).await?;
+                option_fields_size += value.len();
+                if option_fields_size > MAX_OPTION_FIELDS_SIZE {
+                    event!(Level::INFO, "fields in multipart request are too large");
+                    return Err(UploadError::FieldsTooLarge {
+                        max: MAX_OPTION_FIELDS_SIZE,
+                    });
+                }
+                options
+                    .set(&name, &value)
+                    .map_err(|source| UploadError::ParseField { name, source })?;
+                continue;
+            };
+
+            if uploads.len() == MAX_FILES_PER_REQUEST {
+                event!(Level::INFO, "too many files in multipart request");
+                return Err(UploadError::TooManyFiles {
+                    max: MAX_FILES_PER_REQUEST,
+                });
+            }
+            // one proof of work only allows uploading as much as it was solved
+            // for, however many files that's split into
+            if let Some(max) = options.limits.max_request_size {
+                options.limits.max_file_size = max_file_size.min(max.saturating_sub(received));
+            }
+            let mimetype = field
+                .content_type()
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            event!(Level::DEBUG, name, filename, %mimetype, "receiving file");
+            let field = field.inspect_ok(|chunk| received += chunk.len() as u64);
+            uploads.push(
+                receive_upload(state, &filename, mimetype, options.clone(), None, field).await?,
+            );
+            // the same custom ID can't be used for more than one file
+            options.id = None;
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = res {
+        for upload in uploads {
+            event!(
+                Level::INFO,
+                id = upload.id,
+                "deleting upload from failed multipart request"
+            );
+            if let Err(err) = state.backend.delete_upload(&upload.id).await {
+                event!(Level::ERROR, id = upload.id, "error deleting upload: {err}");
+            }
+        }
+        return Err(e);
+    }
+
+    let mut resp_headers = HeaderMap::new();
+    match uploads.as_slice() {
+        [] => return Err(UploadError::MissingFile),
+        [upload] => {
+            // SAFETY: URLs are always valid header values
+            resp_headers.insert(
+                header::CONTENT_LOCATION,
+                HeaderValue::try_from(&upload.direct_url).unwrap(),
+            );
+            resp_headers.insert(
+                header::LOCATION,
+                HeaderValue::try_from(&upload.url).unwrap(),
+            );
+        }
+        _ => {}
+    }
+    Ok((StatusCode::CREATED, resp_headers, Json(uploads)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_trimmed_and_deduplicated() {
+        assert_eq!(
+            parse_tags(" rust, web ,,rust, ").unwrap(),
+            vec!["rust".to_string(), "web".to_string()]
+        );
+    }
+    #[test]
+    fn too_many_tags() {
+        let tags = (0..=MAX_TAGS).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert!(parse_tags(&tags.join(",")).is_err());
+    }
+    #[test]
+    fn tag_too_long() {
+        assert!(parse_tags(&"a".repeat(MAX_TAG_LENGTH + 1)).is_err());
+    }
+    #[test]
+    fn text_field_length_counts_characters() {
+        let title = "é".repeat(MAX_TITLE_LENGTH);
+        assert_eq!(
+            parse_text_field(&title, MAX_TITLE_LENGTH).unwrap(),
+            Some(title.clone())
+        );
+        assert!(parse_text_field(&format!("{title}é"), MAX_TITLE_LENGTH).is_err());
+        assert_eq!(parse_text_field("   ", MAX_TITLE_LENGTH).unwrap(), None);
+    }
+    #[test]
+    fn custom_ids() {
+        assert_eq!(parse_custom_id(" my-file_2 ").unwrap(), "my-file_2");
+        assert!(parse_custom_id("").is_err());
+        assert!(parse_custom_id(&"a".repeat(MAX_CUSTOM_ID_LENGTH + 1)).is_err());
+        assert!(parse_custom_id(".hidden").is_err());
+        assert!(parse_custom_id("../etc").is_err());
+        assert!(parse_custom_id("a/b").is_err());
+        assert!(parse_custom_id("café").is_err());
+    }
+    #[test]
+    fn reserved_ids() {
+        for id in RESERVED_IDS {
+            assert!(parse_custom_id(id).is_err());
+            assert!(parse_custom_id(&id.to_uppercase()).is_err());
+        }
+        assert!(parse_custom_id("raw2").is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+//! Authenticating uploads with API keys, which can have different limits than
+//! the server's
+//!
+//! Keys are sent in the `Authorization: Bearer` header, and are either defined
+//! in the config file or created with the admin CLI (which stores them in the
+//! backend, see [`FileBackend::read_api_keys`]).
+//!
+//! [`FileBackend::read_api_keys`]: bobashare::storage::file::FileBackend::read_api_keys
+
+use std::net::IpAddr;
+
+use anyhow::Context;
+use bobashare::{hash_api_key, ip::hash_ip, storage::api_key::ApiKey};
+use chrono::TimeDelta;
+use displaydoc::Display;
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use hyper::HeaderMap;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{event, instrument, Level};
+
+use crate::{
+    pow::{self, PowError},
+    str_to_duration, AppState,
+};
+
+/// An API key defined in the `api_keys` config option
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    /// name used to refer to the key
+    pub name: String,
+    /// the key itself
+    pub key: String,
+    /// maximum size of each upload in bytes
+    pub max_file_size: Option<u64>,
+    /// maximum expiry of each upload (see [`str_to_duration`] for the format)
+    pub max_expiry: Option<String>,
+    /// whether uploads can be made to never expire
+    #[serde(default)]
+    pub allow_never_expiry: bool,
+    /// maximum total size in bytes of the uploads created with the key that
+    /// haven't been deleted yet
+    pub quota: Option<u64>,
+    /// whether uploads can be given a custom ID
+    #[serde(default)]
+    pub allow_custom_id: bool,
+}
+impl TryFrom<ApiKeyConfig> for ApiKey {
+    type Error = anyhow::Error;
+
+    fn try_from(config: ApiKeyConfig) -> Result<Self, Self::Error> {
+        let max_expiry = config
+            .max_expiry
+            .map(|e| {
+                str_to_duration(&e)
+                    .context("error parsing `max_expiry`")
+                    .and_then(|e| TimeDelta::from_std(e).context("error converting `max_expiry`"))
+            })
+            .transpose()?;
+        Ok(Self {
+            max_file_size: config.max_file_size,
+            max_expiry,
+            allow_never_expiry: config.allow_never_expiry,
+            quota: config.quota,
+            allow_custom_id: config.allow_custom_id,
+            ..Self::new(config.name, hash_api_key(&config.key))
+        })
+    }
+}
+
+/// Find the API key matching `key`, either in the config or in the backend.
+pub async fn find_api_key(state: &AppState, key: &str) -> anyhow::Result<Option<ApiKey>> {
+    let key_hash = hash_api_key(key);
+    if let Some(key) = state.api_keys.iter().find(|k| k.key_hash == key_hash) {
+        return Ok(Some(key.clone()));
+    }
+    let stored_keys = state
+        .backend
+        .read_api_keys()
+        .await
+        .context("error reading API keys")?;
+    Ok(stored_keys.into_iter().find(|k| k.key_hash == key_hash))
+}
+
+/// Errors when authenticating with an API key
+#[derive(Debug, Error, Display)]
+pub enum AuthError {
+    /// an API key is required to upload
+    MissingKey,
+    /// the API key is invalid
+    InvalidKey,
+    /// you are banned from uploading
+    Banned,
+    /// error parsing `Authorization` header
+    ParseHeader(#[source] headers::Error),
+    /// error checking proof of work
+    ProofOfWork(#[from] PowError),
+
+    /// internal server error
+    InternalServer(#[from] anyhow::Error),
+}
+
+/// The limits that apply to a new upload, which depend on the API key it's
+/// created with
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    /// name of the API key, or [`None`] if no key was used
+    pub api_key: Option<String>,
+    /// IP address of the uploader, or a salted hash of it if
+    /// `uploader_ip_salt` is set
+    pub uploader_ip: Option<String>,
+    /// maximum size of the upload in bytes
+    pub max_file_size: u64,
+    /// maximum total size in bytes of all the files uploaded in the request,
+    /// when it's limited by the size a proof of work was solved for ([`None`]
+    /// if no proof of work was needed)
+    pub max_request_size: Option<u64>,
+    /// maximum expiry of the upload ([`None`] for no maximum)
+    pub max_expiry: Option<TimeDelta>,
+    /// maximum total size in bytes of the uploads created with the API key that
+    /// haven't been deleted yet ([`None`] if unlimited)
+    pub quota: Option<u64>,
+    /// whether the upload can be given a custom ID
+    pub allow_custom_id: bool,
+}
+impl UploadLimits {
+    /// Get the limits of uploads created with `key`, or the server's limits if
+    /// there is no key.
+    pub fn new(state: &AppState, key: Option<&ApiKey>) -> Self {
+        let Some(key) = key else {
+            return Self {
+                api_key: None,
+                uploader_ip: None,
+                max_file_size: state.max_file_size,
+                max_request_size: None,
+                max_expiry: state.max_expiry,
+                quota: None,
+                allow_custom_id: state.allow_custom_id,
+            };
+        };
+        Self {
+            api_key: Some(key.name.clone()),
+            uploader_ip: None,
+            max_file_size: key.max_file_size.unwrap_or(state.max_file_size),
+            max_request_size: None,
+            max_expiry: if key.allow_never_expiry {
+                None
+            } else {
+                key.max_expiry.or(state.max_expiry)
+            },
+            quota: key.quota,
+            allow_custom_id: key.allow_custom_id || state.allow_custom_id,
+        }
+    }
+
+    /// Check that the uploader at `ip` isn't banned, look up their API key,
+    /// and get the limits of uploads created with it.
+    ///
+    /// Fails if the uploader is banned, if the key is invalid, if there is no
+    /// key but the server requires one, or if there is no key and
+    /// `proof_of_work` isn't valid (see [`pow::check`]).
+    #[instrument(skip(state, key, proof_of_work))]
+    pub async fn authenticate(
+        state: &AppState,
+        ip: IpAddr,
+        key: Option<&str>,
+        proof_of_work: Option<&str>,
+    ) -> Result<Self, AuthError> {
+        let bans = state
+            .backend
+            .read_bans()
+            .await
+            .context("error reading bans")?;
+        if let Some(ban) = bans.iter().find(|b| b.range.contains(ip)) {
+            event!(Level::INFO, range = %ban.range, "banned uploader tried to upload");
+            return Err(AuthError::Banned);
+        }
+
+        let mut limits = match key {
+            None if state.require_api_key => {
+                event!(Level::INFO, "no API key was provided");
+                return Err(AuthError::MissingKey);
+            }
+            None => Self::new(state, None),
+            Some(key) => match find_api_key(state, key).await? {
+                Some(key) => {
+                    event!(Level::DEBUG, name = key.name, "authenticated with API key");
+                    Self::new(state, Some(&key))
+                }
+                None => {
+                    event!(Level::INFO, "invalid API key was provided");
+                    return Err(AuthError::InvalidKey);
+                }
+            },
+        };
+        pow::check(state, &mut limits, proof_of_work)?;
+        limits.uploader_ip = Some(match &state.uploader_ip_salt {
+            Some(salt) => hash_ip(ip, salt),
+            None => ip.to_string(),
+        });
+        Ok(limits)
+    }
+
+    /// Authenticate with the API key in the `Authorization: Bearer` header and
+    /// the proof of work in the `Bobashare-Proof-Of-Work` header (see
+    /// [`UploadLimits::authenticate`]).
+    pub async fn from_headers(
+        state: &AppState,
+        ip: IpAddr,
+        headers: &HeaderMap,
+    ) -> Result<Self, AuthError> {
+        let auth = headers
+            .typed_try_get::<Authorization<Bearer>>()
+            .map_err(AuthError::ParseHeader)?;
+        let proof_of_work = headers
+            .get("Bobashare-Proof-Of-Work")
+            .map(|p| p.to_str().map_err(|_| PowError::Malformed))
+            .transpose()?;
+        Self::authenticate(state, ip, auth.as_ref().map(|a| a.token()), proof_of_work).await
+    }
+}
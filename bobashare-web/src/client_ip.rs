@@ -0,0 +1,277 @@
+//! Finding the real IP address of clients behind trusted reverse proxies
+//!
+//! When a request comes from one of the `trusted_proxies`, the client's address
+//! is taken from the header chosen with `forwarded_header` instead (see
+//! [`ForwardedHeader`]). The [`resolve_client_ip`] middleware stores it in the
+//! request as a [`ClientIp`], which handlers can extract.
+//!
+//! Only that header is read, and clients can send it too, so the proxy has to
+//! overwrite it (or remove the client's one before adding its own). Otherwise,
+//! clients can pretend to be any address.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bobashare::ip::IpCidr;
+use displaydoc::Display;
+use hyper::{http::request::Parts, HeaderMap, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{event, Level, Span};
+
+use crate::{api::v1::ApiErrorExt, AppState};
+
+/// Which header trusted proxies send the client's address in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: <client>, <proxy>, ...`
+    #[default]
+    XForwardedFor,
+    /// `Forwarded: for=<client>, for=<proxy>, ...` ([RFC 7239])
+    ///
+    /// [RFC 7239]: https://datatracker.ietf.org/doc/html/rfc7239
+    Forwarded,
+}
+
+/// Parse a node from the `for` parameter of a `Forwarded` header, which can be
+/// quoted and have a port (IPv6 addresses are in brackets).
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.split(':').next()?.parse().ok()
+}
+
+/// Get the addresses in `header`, from the original client to the last proxy.
+/// The other header is ignored, since the proxy doesn't replace it.
+///
+/// Addresses that can't be parsed (such as obfuscated ones) are [`None`].
+fn forwarded_for(header: ForwardedHeader, headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    match header {
+        ForwardedHeader::XForwardedFor => headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|ip| ip.trim().parse().ok())
+            .collect(),
+        ForwardedHeader::Forwarded => headers
+            .get_all("Forwarded")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_forwarded_node(node))
+            })
+            .collect(),
+    }
+}
+
+/// Find the address of the client that sent a request through `peer`.
+///
+/// Goes backwards through the addresses forwarded in `header`, skipping the
+/// trusted proxies, and returns the first one that isn't trusted. If an address
+/// can't be parsed, the last proxy before it is used, since anything further
+/// back can't be trusted.
+pub fn client_ip(
+    trusted_proxies: &[IpCidr],
+    header: ForwardedHeader,
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|p| p.contains(ip));
+    let mut client = peer.to_canonical();
+    if !is_trusted(client) {
+        return client;
+    }
+    for ip in forwarded_for(header, headers).into_iter().rev() {
+        let Some(ip) = ip else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// IP address of the client that sent the request, resolved by
+/// [`resolve_client_ip`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Error when the [`ClientIp`] of a request is unknown
+#[derive(Debug, Error, Display)]
+pub enum ClientIpRejection {
+    /// the client's IP address is unknown
+    Missing,
+}
+impl IntoResponse for ClientIpRejection {
+    fn into_response(self) -> Response {
+        self.into_response_with_code(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = ClientIpRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if let Some(ip) = parts.extensions.get::<ClientIp>() {
+            return Ok(*ip);
+        }
+        // in case the middleware wasn't used
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| ClientIp(addr.ip().to_canonical()))
+            .ok_or(ClientIpRejection::Missing)
+    }
+}
+
+/// Middleware that finds the [`ClientIp`] of the request, stores it in the
+/// request extensions, and records it in the current span as `client_ip`
+pub async fn resolve_client_ip(
+    State(state): State<&'static AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(
+        &state.trusted_proxies,
+        state.forwarded_header,
+        peer.ip(),
+        request.headers(),
+    );
+    Span::current().record("client_ip", tracing::field::display(ip));
+    if ip != peer.ip().to_canonical() {
+        event!(Level::TRACE, %peer, %ip, "resolved forwarded client IP");
+    }
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_client() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("192.0.2.1"));
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        assert_eq!(
+            client_ip(
+                &trusted,
+                ForwardedHeader::XForwardedFor,
+                ip("198.51.100.1"),
+                &headers
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        // the client can put anything at the start of the header
+        headers.insert(
+            "X-Forwarded-For",
+            HeaderValue::from_static("203.0.113.9, 192.0.2.1, 10.0.0.2"),
+        );
+        let trusted = ["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+        assert_eq!(
+            client_ip(
+                &trusted,
+                ForwardedHeader::XForwardedFor,
+                ip("127.0.0.1"),
+                &headers
+            ),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Forwarded",
+            HeaderValue::from_static(
+                r#"for=192.0.2.60;proto=http, For="[2001:db8:cafe::17]:4711""#,
+            ),
+        );
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("192.0.2.1"));
+        let trusted = ["::1".parse().unwrap()];
+        assert_eq!(
+            client_ip(&trusted, ForwardedHeader::Forwarded, ip("::1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+    }
+
+    #[test]
+    fn other_header_is_ignored() {
+        let mut headers = HeaderMap::new();
+        // sent by the client, and passed through by a proxy that only sets
+        // `X-Forwarded-For`
+        headers.insert("Forwarded", HeaderValue::from_static("for=203.0.113.9"));
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("192.0.2.1"));
+        let trusted = ["127.0.0.1".parse().unwrap()];
+        assert_eq!(
+            client_ip(
+                &trusted,
+                ForwardedHeader::XForwardedFor,
+                ip("127.0.0.1"),
+                &headers
+            ),
+            ip("192.0.2.1")
+        );
+
+        // and the other way around
+        headers.insert("Forwarded", HeaderValue::from_static("for=192.0.2.1"));
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("203.0.113.9"));
+        assert_eq!(
+            client_ip(
+                &trusted,
+                ForwardedHeader::Forwarded,
+                ip("127.0.0.1"),
+                &headers
+            ),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn unknown_address_stops_at_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Forwarded",
+            HeaderValue::from_static("for=192.0.2.60, for=unknown, for=10.0.0.2"),
+        );
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        assert_eq!(
+            client_ip(
+                &trusted,
+                ForwardedHeader::Forwarded,
+                ip("10.0.0.1"),
+                &headers
+            ),
+            ip("10.0.0.2")
+        );
+    }
+}
@@ -1,22 +1,47 @@
 //! Webserver written with [`axum`] which provides a frontend and REST API for
 //! [`bobashare`]
 
-use std::{num::ParseIntError, path::PathBuf, str::FromStr, time::Duration as StdDuration};
+use std::{
+    num::ParseIntError,
+    path::PathBuf,
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration as StdDuration,
+};
 
-use bobashare::storage::file::FileBackend;
+use active_content::ActiveContentAction;
+use api::v1::tus::TusSessions;
+use bobashare::{
+    id::IdStrategy,
+    ip::IpCidr,
+    storage::{api_key::ApiKey, file::FileBackend, stats::StatsRecorder, upload::Upload},
+};
 use chrono::TimeDelta;
+use client_ip::ForwardedHeader;
 use displaydoc::Display;
+use mime::Mime;
+use pow::UsedChallenges;
 use pulldown_cmark::{html::push_html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use rate_limit::RateLimiters;
 use syntect::{
     html::{ClassStyle, ClassedHTMLGenerator},
     parsing::SyntaxSet,
 };
 use thiserror::Error;
 use tokio::sync::broadcast;
+use tracing::{event, Level};
 use url::Url;
+use views::display::FileOffload;
 
+pub mod active_content;
 pub mod api;
+pub mod api_key;
+pub mod client_ip;
+pub mod pow;
+pub mod range;
+pub mod rate_limit;
 pub mod static_routes;
+pub mod token;
 pub mod views;
 
 #[cfg(test)]
@@ -41,18 +66,69 @@ pub struct AppState {
     pub backend: FileBackend,
     /// how often between each cleanup
     pub cleanup_interval: StdDuration,
+    /// access stats that haven't been written to disk yet
+    pub stats: Arc<StatsRecorder>,
+    /// how often between each write of the collected access stats
+    pub stats_flush_interval: StdDuration,
+    /// resumable uploads that are in progress
+    pub tus_sessions: Arc<TusSessions>,
+    /// how long a resumable upload is kept without receiving any data
+    pub tus_session_timeout: TimeDelta,
     /// base URL (ex. `http://localhost:3000/`)
     pub base_url: Url,
     /// base URL for downloading raw upload files (ex. `http://localhost:3000/raw/`)
     pub raw_url: Url,
-    /// length of randomly generated IDs
+    /// minimum length of randomly generated IDs
     pub id_length: usize,
+    /// how IDs of new uploads are generated
+    pub id_strategy: IdStrategy,
+    /// number of uploads in the backend, counted by each cleanup and increased
+    /// by each new upload, so IDs can get longer as it fills up
+    pub upload_count: Arc<AtomicUsize>,
     /// default expiry time
     pub default_expiry: TimeDelta,
     /// maximum expiry time ([`None`] for no maximum)
     pub max_expiry: Option<TimeDelta>,
     /// maximum file size in bytes
     pub max_file_size: u64,
+    /// API keys defined in the config, in addition to the ones stored in the
+    /// backend (see [`api_key`])
+    pub api_keys: Vec<ApiKey>,
+    /// whether an API key is needed to upload
+    pub require_api_key: bool,
+    /// whether uploads can be given a custom ID without an API key that
+    /// allows it
+    pub allow_custom_id: bool,
+    /// salt used to hash the IP address of uploaders before storing it, or
+    /// [`None`] to store it as is
+    pub uploader_ip_salt: Option<String>,
+    /// reverse proxies that are trusted to send the client's IP address (see
+    /// [`client_ip`])
+    pub trusted_proxies: Vec<IpCidr>,
+    /// header the trusted proxies send the client's IP address in
+    pub forwarded_header: ForwardedHeader,
+    /// rate limiters for uploads, deletes and views
+    pub rate_limiters: Arc<RateLimiters>,
+    /// number of leading zero bits needed in proofs of work of uploads without
+    /// an API key (0 to disable proof of work, see [`pow`])
+    pub pow_difficulty: u8,
+    /// file size in bytes after which the proof of work needs one more bit
+    /// every time the size doubles ([`None`] to not scale with size)
+    pub pow_scale_size: Option<u64>,
+    /// proof-of-work challenges that have already been used
+    pub used_challenges: Arc<UsedChallenges>,
+    /// secret key used to sign access tokens (see [`token`])
+    pub secret_key: Vec<u8>,
+    /// types of uploads that browsers can run script in (see
+    /// [`active_content`])
+    pub active_content_types: Vec<Mime>,
+    /// how raw files with an active content type are served
+    pub active_content_action: ActiveContentAction,
+    /// whether raw files are sent by the web server in front of bobashare
+    pub file_offload: FileOffload,
+    /// path that upload files are found under by the web server, when
+    /// `file_offload` is enabled (ends with `/`)
+    pub file_offload_path: String,
 
     // syntax highlighting
     pub syntax_set: SyntaxSet,
@@ -140,6 +216,36 @@ pub fn clamp_expiry(max_expiry: Option<TimeDelta>, other: Option<TimeDelta>) ->
     }
 }
 
+/// Check a password against an upload's password, like
+/// [`Upload::check_password`], but on a blocking thread so the slow Argon2 hash
+/// doesn't hold up other requests.
+pub async fn check_password(upload: &Upload, password: &str) -> bool {
+    let Some(hash) = upload.password_hash.clone() else {
+        return true;
+    };
+    let password = password.to_string();
+    verify_blocking(move || bobashare::verify_password(&hash, &password)).await
+}
+
+/// Check a key against an upload's delete key, like
+/// [`Upload::check_delete_key`], but on a blocking thread, since keys chosen by
+/// the uploader are hashed with Argon2 too.
+pub async fn check_delete_key(upload: &Upload, key: &str) -> bool {
+    let hash = upload.delete_key_hash.clone();
+    let key = key.to_string();
+    verify_blocking(move || bobashare::verify_delete_key(&hash, &key)).await
+}
+
+/// Run `verify` on a blocking thread, failing if it panics
+async fn verify_blocking(verify: impl FnOnce() -> bool + Send + 'static) -> bool {
+    tokio::task::spawn_blocking(verify)
+        .await
+        .unwrap_or_else(|e| {
+            event!(Level::ERROR, "error checking password or key: {e}");
+            false
+        })
+}
+
 /// Error encountered in converting string to duration values with
 /// [`str_to_duration`]
 #[derive(Debug, Error, Display)]
@@ -2,20 +2,32 @@ use std::{
     future::IntoFuture,
     net::SocketAddr,
     path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
 };
 
 use anyhow::Context;
-use axum::{self, response::Redirect, routing::get, Router};
-use bobashare::storage::file::FileBackend;
+use axum::{self, middleware, response::Redirect, routing::get, Router};
+use bobashare::{
+    id::IdStrategy,
+    ip::IpCidr,
+    storage::{api_key::ApiKey, file::FileBackend},
+};
 use bobashare_web::{
-    api, render_markdown_with_syntax_set, static_routes, str_to_duration,
-    views::{self, ErrorResponse, ErrorTemplate, TemplateState},
+    active_content::{ActiveContentAction, DEFAULT_ACTIVE_CONTENT_TYPES},
+    api,
+    api_key::ApiKeyConfig,
+    client_ip::{self, ForwardedHeader},
+    rate_limit::{self, RateLimiter, RateLimiters},
+    render_markdown_with_syntax_set, static_routes, str_to_duration,
+    token::MIN_SECRET_KEY_LENGTH,
+    views::{self, display::FileOffload, ErrorResponse, ErrorTemplate, TemplateState},
     AppState,
 };
-use chrono::TimeDelta;
+use chrono::{TimeDelta, Utc};
 use clap::Parser;
 use config::Config;
 use hyper::{Request, StatusCode};
+use mime::Mime;
 use syntect::parsing::SyntaxSet;
 use tokio::{net::TcpListener, signal, sync::broadcast, time::sleep};
 use tower::ServiceBuilder;
@@ -71,11 +83,32 @@ async fn main() -> anyhow::Result<()> {
         .set_default("listen_addr", "127.0.0.1:3000").unwrap()
         .set_default("backend_path", "storage/").unwrap()
         .set_default("cleanup_interval", "1h").unwrap()
+        .set_default("stats_flush_interval", "1m").unwrap()
+        .set_default("tus_session_timeout", "1h").unwrap()
         .set_default("base_url", "http://localhost:3000/").unwrap()
+        .set_default("raw_url", None::<String>).unwrap()
         .set_default("id_length", 8).unwrap()
+        .set_default("id_strategy", "alphanumeric").unwrap()
         .set_default("default_expiry", "24h").unwrap()
         .set_default("max_expiry", Some("30d")).unwrap()
         .set_default("max_file_size", 1024 * 1024 * 1024).unwrap() // 1 GiB
+        .set_default("require_api_key", false).unwrap()
+        .set_default("allow_custom_id", false).unwrap()
+        .set_default("api_keys", Vec::<config::Value>::new()).unwrap()
+        .set_default("uploader_ip_salt", None::<String>).unwrap()
+        .set_default("trusted_proxies", Vec::<String>::new()).unwrap()
+        .set_default("forwarded_header", "x-forwarded-for").unwrap()
+        .set_default("upload_rate_limit", "60/10m").unwrap()
+        .set_default("delete_rate_limit", "30/10m").unwrap()
+        .set_default("view_rate_limit", "600/1m").unwrap()
+        .set_default("password_rate_limit", "10/10m").unwrap()
+        .set_default("pow_difficulty", 0).unwrap()
+        .set_default("pow_scale_size", None::<u64>).unwrap()
+        .set_default("secret_key", None::<String>).unwrap()
+        .set_default("active_content_types", DEFAULT_ACTIVE_CONTENT_TYPES.to_vec()).unwrap()
+        .set_default("active_content_action", "plain_text").unwrap()
+        .set_default("file_offload", "none").unwrap()
+        .set_default("file_offload_path", None::<String>).unwrap()
         .set_default("extra_footer_text", None::<String>).unwrap()
         .set_default("about_page", None::<String>).unwrap();
 
@@ -118,15 +151,40 @@ async fn main() -> anyhow::Result<()> {
     let instance_name = config.get_string("instance_name").unwrap();
     let backend =
         FileBackend::new(PathBuf::from(config.get_string("backend_path").unwrap())).await?;
+    // nothing is being uploaded yet, so the usage of the API keys can be
+    // counted again in case it drifted (like if the server crashed mid-upload)
+    backend
+        .recount_api_key_usage()
+        .await
+        .context("error counting usage of API keys")?;
     let cleanup_interval = str_to_duration(&config.get_string("cleanup_interval").unwrap())
         .context("error parsing `cleanup_interval`")?;
+    let stats_flush_interval = str_to_duration(&config.get_string("stats_flush_interval").unwrap())
+        .context("error parsing `stats_flush_interval`")?;
+    let tus_session_timeout = TimeDelta::from_std(
+        str_to_duration(&config.get_string("tus_session_timeout").unwrap())
+            .context("error parsing `tus_session_timeout`")?,
+    )
+    .unwrap();
     let base_url: Url = config
         .get_string("base_url")
         .unwrap()
         .parse()
         .context("error parsing `base_url`")?;
-    let raw_url = base_url.join("raw/").unwrap();
+    let mut raw_url = match config.get::<Option<String>>("raw_url").unwrap() {
+        Some(url) => url.parse::<Url>().context("error parsing `raw_url`")?,
+        None => base_url.join("raw/").unwrap(),
+    };
+    if !raw_url.path().ends_with('/') {
+        raw_url.set_path(&format!("{}/", raw_url.path()));
+    }
+    if raw_url.origin() == base_url.origin() && raw_url.path() == "/" {
+        anyhow::bail!("`raw_url` must have a path if it's on the same host as `base_url`");
+    }
     let id_length = config.get_int("id_length").unwrap().try_into().unwrap();
+    let id_strategy = config
+        .get::<IdStrategy>("id_strategy")
+        .context("error parsing `id_strategy`")?;
     let default_expiry = TimeDelta::from_std(
         str_to_duration(&config.get_string("default_expiry").unwrap())
             .context("error parsing `default_expiry`")?,
@@ -138,6 +196,96 @@ async fn main() -> anyhow::Result<()> {
     }
     .map(|d| TimeDelta::from_std(d).unwrap());
     let max_file_size = config.get_int("max_file_size").unwrap().try_into().unwrap();
+    let require_api_key = config
+        .get_bool("require_api_key")
+        .context("error parsing `require_api_key`")?;
+    let allow_custom_id = config
+        .get_bool("allow_custom_id")
+        .context("error parsing `allow_custom_id`")?;
+    let api_keys = config
+        .get::<Vec<ApiKeyConfig>>("api_keys")
+        .context("error parsing `api_keys`")?
+        .into_iter()
+        .map(|k| {
+            let name = k.name.clone();
+            ApiKey::try_from(k).with_context(|| format!("error parsing API key `{name}`"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let uploader_ip_salt = config.get::<Option<String>>("uploader_ip_salt").unwrap();
+    let trusted_proxies = config
+        .get::<Vec<IpCidr>>("trusted_proxies")
+        .context("error parsing `trusted_proxies`")?;
+    let forwarded_header = config
+        .get::<ForwardedHeader>("forwarded_header")
+        .context("error parsing `forwarded_header`")?;
+    let rate_limiter = |name: &str| -> anyhow::Result<Option<RateLimiter>> {
+        match config.get_string(name).unwrap().as_str() {
+            "none" => Ok(None),
+            limit => Ok(Some(RateLimiter::new(
+                limit
+                    .parse()
+                    .with_context(|| format!("error parsing `{name}`"))?,
+            ))),
+        }
+    };
+    let rate_limiters = RateLimiters {
+        upload: rate_limiter("upload_rate_limit")?,
+        delete: rate_limiter("delete_rate_limit")?,
+        view: rate_limiter("view_rate_limit")?,
+        password: rate_limiter("password_rate_limit")?,
+    };
+    let pow_difficulty = config
+        .get::<u8>("pow_difficulty")
+        .context("error parsing `pow_difficulty`")?;
+    let pow_scale_size = config
+        .get::<Option<u64>>("pow_scale_size")
+        .context("error parsing `pow_scale_size`")?;
+    let secret_key = match config.get::<Option<String>>("secret_key").unwrap() {
+        Some(key) if key.len() < MIN_SECRET_KEY_LENGTH => {
+            anyhow::bail!("`secret_key` must be at least {MIN_SECRET_KEY_LENGTH} bytes long");
+        }
+        Some(key) => key.into_bytes(),
+        None => {
+            event!(
+                Level::WARN,
+                "`secret_key` is not set, so a random one will be used; after a restart, \
+                 unlocked uploads will need their password entered again, signed links to \
+                 private uploads will stop working, and pending proof-of-work challenges \
+                 will need to be solved again"
+            );
+            let mut key = vec![0; MIN_SECRET_KEY_LENGTH];
+            rand::fill(key.as_mut_slice());
+            key
+        }
+    };
+
+    let active_content_types = config
+        .get::<Vec<String>>("active_content_types")
+        .context("error parsing `active_content_types`")?
+        .iter()
+        .map(|t| {
+            t.parse::<Mime>()
+                .with_context(|| format!("error parsing active content type `{t}`"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let active_content_action = config
+        .get::<ActiveContentAction>("active_content_action")
+        .context("error parsing `active_content_action`")?;
+
+    let file_offload = config
+        .get::<FileOffload>("file_offload")
+        .context("error parsing `file_offload`")?;
+    let mut file_offload_path = match config.get::<Option<String>>("file_offload_path").unwrap() {
+        Some(path) => path,
+        None => match file_offload {
+            FileOffload::Disabled => String::new(),
+            FileOffload::XAccelRedirect => String::from("/internal/"),
+            FileOffload::XSendfile => backend.path.to_string_lossy().into_owned(),
+        },
+    };
+    if file_offload != FileOffload::Disabled && !file_offload_path.ends_with('/') {
+        file_offload_path.push('/');
+    }
 
     let syntax_set = SyntaxSet::load_defaults_newlines();
 
@@ -167,12 +315,33 @@ async fn main() -> anyhow::Result<()> {
         instance_name,
         backend,
         cleanup_interval,
+        stats: Arc::default(),
+        stats_flush_interval,
+        tus_sessions: Arc::default(),
+        tus_session_timeout,
         base_url,
         raw_url,
         id_length,
+        id_strategy,
+        upload_count: Arc::default(),
         default_expiry,
         max_expiry,
         max_file_size,
+        api_keys,
+        require_api_key,
+        allow_custom_id,
+        uploader_ip_salt,
+        trusted_proxies,
+        forwarded_header,
+        rate_limiters: Arc::new(rate_limiters),
+        pow_difficulty,
+        pow_scale_size,
+        used_challenges: Arc::default(),
+        secret_key,
+        active_content_types,
+        active_content_action,
+        file_offload,
+        file_offload_path,
 
         syntax_set,
 
@@ -188,15 +357,34 @@ async fn main() -> anyhow::Result<()> {
         base_url = %state.base_url,
         raw_url = %state.raw_url,
         id_length = %state.id_length,
+        id_strategy = ?state.id_strategy,
         default_expiry = %state.default_expiry,
         max_expiry = %state.max_expiry.map_or_else(|| String::from("never"), |e| e.to_string()),
         max_file_size = %state.max_file_size,
+        api_keys = ?state.api_keys.iter().map(|k| &k.name).collect::<Vec<_>>(),
+        require_api_key = %state.require_api_key,
+        allow_custom_id = %state.allow_custom_id,
+        hash_uploader_ip = %state.uploader_ip_salt.is_some(),
+        trusted_proxies = ?state.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        forwarded_header = ?state.forwarded_header,
+        rate_limiters = ?state.rate_limiters,
+        pow_difficulty = %state.pow_difficulty,
+        pow_scale_size = ?state.pow_scale_size,
+        file_offload = ?state.file_offload,
+        file_offload_path = %state.file_offload_path,
         extra_footer_text = ?state.extra_footer_text,
         about_page = ?state.about_page,
         "generated state from config"
     );
 
-    let app = Router::new()
+    let not_found = || async {
+        ErrorResponse::from(ErrorTemplate {
+            code: StatusCode::NOT_FOUND,
+            message: "no route for the requested URL was found".into(),
+            state: TemplateState::from(&*state),
+        })
+    };
+    let mut app = Router::new()
         .nest("/api", api::router())
         .merge(views::router())
         .route(
@@ -211,13 +399,26 @@ async fn main() -> anyhow::Result<()> {
             }),
         )
         .nest_service("/static", get(static_routes::handler))
-        .fallback(|| async {
-            ErrorResponse::from(ErrorTemplate {
-                code: StatusCode::NOT_FOUND,
-                message: "no route for the requested URL was found".into(),
-                state: TemplateState::from(&*state),
-            })
-        })
+        .fallback(not_found);
+    if state.raw_url != state.base_url.join("raw/").unwrap() {
+        app = app.route("/raw/{id}", get(views::display::redirect_raw));
+    }
+    let raw_router = views::raw_router(state.raw_url.path());
+    let app = if state.raw_url.origin() == state.base_url.origin() {
+        app.merge(raw_router).with_state(&*state)
+    } else {
+        // raw files are only served on their own host, which serves nothing else;
+        // the host is checked before routing so the routers don't see each
+        // other's path parameters
+        let raw_router = raw_router.fallback(not_found).with_state(&*state);
+        Router::new()
+            .fallback_service(app.with_state(&*state))
+            .layer(middleware::from_fn_with_state(
+                (&*state, raw_router),
+                views::route_raw_host,
+            ))
+    };
+    let app = app
         .layer(
             ServiceBuilder::new()
                 .set_x_request_id(MakeRequestUuid)
@@ -229,16 +430,25 @@ async fn main() -> anyhow::Result<()> {
                                 "request",
                                 method = %request.method(),
                                 uri = ?request.uri(),
-                                id = ?request.headers().get("X-Request-ID").unwrap()
+                                id = ?request.headers().get("X-Request-ID").unwrap(),
+                                // recorded by `resolve_client_ip`
+                                client_ip = tracing::field::Empty,
                             )
                         })
                         .on_request(DefaultOnRequest::new().level(Level::INFO))
                         .on_response(DefaultOnResponse::new().level(Level::INFO)),
                 )
-                .propagate_x_request_id(),
+                .propagate_x_request_id()
+                .layer(middleware::from_fn_with_state(
+                    &*state,
+                    client_ip::resolve_client_ip,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    &*state,
+                    rate_limit::rate_limit,
+                )),
         )
-        .with_state(state)
-        .into_make_service();
+        .into_make_service_with_connect_info::<SocketAddr>();
 
     let listen_addr: SocketAddr = config
         .get_string("listen_addr")
@@ -268,13 +478,16 @@ async fn main() -> anyhow::Result<()> {
         let mut shutdown_rx = state.shutdown_tx.subscribe();
         loop {
             event!(Level::INFO, "running cleanup");
+            state.tus_sessions.expire(state).await;
+            state.rate_limiters.prune();
+            state.used_challenges.prune(Utc::now());
             tokio::select! {
-                r = state.backend.cleanup() => {
-                    if r.is_err() {
-                        event!(Level::ERROR, ?r, "error during cleanup task");
-                    } else {
-                        event!(Level::INFO, "cleanup done");
+                r = state.backend.cleanup() => match r {
+                    Ok(remaining) => {
+                        state.upload_count.store(remaining, Ordering::Relaxed);
+                        event!(Level::INFO, remaining, "cleanup done");
                     }
+                    Err(e) => event!(Level::ERROR, ?e, "error during cleanup task"),
                 },
                 _ = shutdown_rx.recv() => {
                     event!(Level::INFO, "received shutdown signal, stopping cleanup");
@@ -291,9 +504,28 @@ async fn main() -> anyhow::Result<()> {
                 _ = sleep(state.cleanup_interval) => {}
             }
         }
+        state.tus_sessions.abort_all(state).await;
     }
     .instrument(cleanup_span);
 
+    let stats_span = tracing::span!(Level::INFO, "bg_stats");
+    let stats_exec = async {
+        let mut shutdown_rx = state.shutdown_tx.subscribe();
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    event!(Level::INFO, "received shutdown signal, writing remaining stats");
+                    state.backend.flush_stats(&state.stats).await;
+                    break;
+                },
+                _ = sleep(state.stats_flush_interval) => {
+                    state.backend.flush_stats(&state.stats).await;
+                }
+            }
+        }
+    }
+    .instrument(stats_span);
+
     let shutdown_span = tracing::span!(Level::INFO, "shutdown_handler");
     // needed since the shutdown task might outlive main (supposedly?)
     let state2 = state.clone();
@@ -336,7 +568,7 @@ async fn main() -> anyhow::Result<()> {
     );
 
     // start everything
-    let join_results = tokio::join!(server_exec, cleanup_exec);
+    let join_results = tokio::join!(server_exec, cleanup_exec, stats_exec);
     join_results.0.context("error running server")?; // handle error in axum server
 
     Ok(())
@@ -0,0 +1,337 @@
+//! Hashcash-style proof-of-work challenges, which uploaders without an API key
+//! have to solve when `pow_difficulty` is set
+//!
+//! A client gets a [`Challenge`] from [`challenge`], then finds a solution
+//! such that the SHA-256 hash of `{challenge}:{solution}` starts with at least
+//! `difficulty` zero bits, and sends `{challenge}:{solution}` in the
+//! `Bobashare-Proof-Of-Work` header when uploading. Challenges are signed with
+//! the server's secret key (see [`token`]), so they only need to be stored
+//! after they are used, to make sure each one is only used once.
+//!
+//! [`challenge`]: crate::api::v1::challenge::challenge
+
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, TimeDelta, Utc};
+use displaydoc::Display;
+use hyper::StatusCode;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{event, instrument, Level};
+
+use crate::{
+    api::v1::ApiErrorExt,
+    api_key::UploadLimits,
+    token::{self, TokenScope},
+    AppState,
+};
+
+/// How long a challenge can be used for after it's created
+pub const CHALLENGE_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+/// Maximum length of a solution, in bytes
+pub const MAX_SOLUTION_LENGTH: usize = 64;
+
+/// Errors when creating or checking a proof of work
+#[derive(Debug, Error, Display)]
+pub enum PowError {
+    /// proof of work is not enabled on this server
+    Disabled,
+    /// a proof of work is required to upload without an API key
+    Missing,
+    /// proof of work is not in the correct format
+    Malformed,
+    /// challenge is invalid
+    InvalidChallenge(#[from] token::VerifyTokenError),
+    /// challenge has already been used
+    AlreadyUsed,
+    /// solution does not have enough leading zero bits (need {0})
+    InsufficientWork(u8),
+}
+impl PowError {
+    /// The status code to respond with
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Disabled => StatusCode::NOT_FOUND,
+            Self::Missing => StatusCode::BAD_REQUEST,
+            Self::Malformed => StatusCode::BAD_REQUEST,
+            Self::InvalidChallenge(_) => StatusCode::FORBIDDEN,
+            Self::AlreadyUsed => StatusCode::FORBIDDEN,
+            Self::InsufficientWork(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+impl IntoResponse for PowError {
+    fn into_response(self) -> Response {
+        let code = self.status_code();
+        self.into_response_with_code(code)
+    }
+}
+
+/// Get the number of leading zero bits needed to upload a file of `size` bytes.
+///
+/// The difficulty is `base`, plus one bit for every time the size doubles
+/// past `scale_size` if it's set. A `base` of zero means proof of work is
+/// disabled, so no bits are needed for any size.
+pub fn difficulty(base: u8, scale_size: Option<u64>, size: u64) -> u8 {
+    if base == 0 {
+        return 0;
+    }
+    let extra = scale_size
+        .filter(|&s| s > 0)
+        .and_then(|s| (size / s).checked_ilog2())
+        .map_or(0, |bits| bits + 1);
+    u8::try_from(u32::from(base) + extra).unwrap_or(u8::MAX)
+}
+
+/// Count the leading zero bits of a hash.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// A proof-of-work challenge, which allows uploading a file of up to
+/// `max_size` bytes once it's solved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// random hex string, so each challenge is different
+    pub nonce: String,
+    /// number of leading zero bits the solution needs
+    pub difficulty: u8,
+    /// maximum size of the upload in bytes
+    pub max_size: u64,
+}
+impl Challenge {
+    /// Create a new challenge with a random nonce.
+    pub fn new(difficulty: u8, max_size: u64) -> Self {
+        Self {
+            nonce: hex::encode(rand::random::<[u8; 16]>()),
+            difficulty,
+            max_size,
+        }
+    }
+
+    /// Sign the challenge so it's valid until `expiry`, returning the string
+    /// that is given to the client.
+    ///
+    /// The string is in the format `{nonce}:{difficulty}:{max_size}:{token}`,
+    /// where `token` is signed for the rest of it (see [`token::sign`]).
+    pub fn sign(&self, key: &[u8], expiry: DateTime<Utc>) -> String {
+        let token = token::sign(key, TokenScope::Challenge, &self.to_string(), expiry);
+        format!("{self}:{token}")
+    }
+
+    /// Verify the signature of a challenge created by [`Challenge::sign`],
+    /// returning the challenge and when it expires.
+    pub fn verify(key: &[u8], challenge: &str) -> Result<(Self, DateTime<Utc>), PowError> {
+        let (signed, token) = challenge.rsplit_once(':').ok_or(PowError::Malformed)?;
+        token::verify(key, TokenScope::Challenge, signed, token)?;
+
+        // the signature is valid, so these were created by the server
+        let mut parts = signed.split(':');
+        let (Some(nonce), Some(difficulty), Some(max_size), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(PowError::Malformed);
+        };
+        let expiry = token
+            .split_once('.')
+            .and_then(|(e, _)| e.parse().ok())
+            .and_then(|e| DateTime::from_timestamp(e, 0))
+            .ok_or(PowError::Malformed)?;
+        let challenge = Self {
+            nonce: nonce.to_string(),
+            difficulty: difficulty.parse().map_err(|_| PowError::Malformed)?,
+            max_size: max_size.parse().map_err(|_| PowError::Malformed)?,
+        };
+        Ok((challenge, expiry))
+    }
+}
+impl fmt::Display for Challenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.nonce, self.difficulty, self.max_size)
+    }
+}
+
+/// Check a proof of work in the format `{challenge}:{solution}`, returning
+/// the challenge and when it expires if the solution is valid.
+pub fn verify_solution(key: &[u8], proof: &str) -> Result<(Challenge, DateTime<Utc>), PowError> {
+    let (challenge_str, solution) = proof.rsplit_once(':').ok_or(PowError::Malformed)?;
+    if solution.is_empty() || solution.len() > MAX_SOLUTION_LENGTH {
+        return Err(PowError::Malformed);
+    }
+    let (challenge, expiry) = Challenge::verify(key, challenge_str)?;
+    let hash = Sha256::digest(proof.as_bytes());
+    if leading_zero_bits(&hash) < u32::from(challenge.difficulty) {
+        return Err(PowError::InsufficientWork(challenge.difficulty));
+    }
+    Ok((challenge, expiry))
+}
+
+/// Nonces of the challenges that have been used, so they can't be used again
+/// before they expire
+#[derive(Debug, Default)]
+pub struct UsedChallenges(Mutex<HashMap<String, DateTime<Utc>>>);
+impl UsedChallenges {
+    /// Mark the challenge with `nonce` as used until `expiry`, returning
+    /// whether it wasn't used already.
+    pub fn insert(&self, nonce: &str, expiry: DateTime<Utc>) -> bool {
+        let mut used = self.0.lock().unwrap();
+        if used.contains_key(nonce) {
+            return false;
+        }
+        used.insert(nonce.to_string(), expiry);
+        true
+    }
+
+    /// Forget the challenges that have expired, since they can't be used
+    /// anymore anyway.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        self.0.lock().unwrap().retain(|_, expiry| *expiry >= now);
+    }
+}
+
+/// Check the proof of work of an upload, if one is needed, and limit the size
+/// of the upload to the size the challenge was created for. If the request
+/// has more than one file, that's the limit for all of them together.
+///
+/// Uploads with an API key don't need a proof of work.
+#[instrument(skip(state, limits, proof))]
+pub fn check(
+    state: &AppState,
+    limits: &mut UploadLimits,
+    proof: Option<&str>,
+) -> Result<(), PowError> {
+    if state.pow_difficulty == 0 || limits.api_key.is_some() {
+        return Ok(());
+    }
+    let Some(proof) = proof else {
+        event!(Level::INFO, "no proof of work was provided");
+        return Err(PowError::Missing);
+    };
+
+    let (challenge, expiry) = verify_solution(&state.secret_key, proof)?;
+    // the config could have changed since the challenge was created
+    let required = difficulty(
+        state.pow_difficulty,
+        state.pow_scale_size,
+        challenge.max_size,
+    );
+    if challenge.difficulty < required {
+        return Err(PowError::InsufficientWork(required));
+    }
+    if !state.used_challenges.insert(&challenge.nonce, expiry) {
+        event!(Level::INFO, nonce = challenge.nonce, "challenge was reused");
+        return Err(PowError::AlreadyUsed);
+    }
+
+    event!(
+        Level::DEBUG,
+        nonce = challenge.nonce,
+        difficulty = challenge.difficulty,
+        max_size = challenge.max_size,
+        "proof of work is valid"
+    );
+    limits.max_file_size = limits.max_file_size.min(challenge.max_size);
+    limits.max_request_size = Some(challenge.max_size);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"super secret key";
+
+    /// Find a solution to `challenge` by trying every number.
+    fn solve(challenge: &str, difficulty: u8) -> String {
+        (0u64..)
+            .map(|n| format!("{challenge}:{n}"))
+            .find(|proof| {
+                leading_zero_bits(&Sha256::digest(proof.as_bytes())) >= u32::from(difficulty)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn difficulty_scales_with_size() {
+        assert_eq!(difficulty(0, Some(1024), 1 << 20), 0);
+        assert_eq!(difficulty(16, None, 1 << 30), 16);
+        assert_eq!(difficulty(16, Some(1024), 1023), 16);
+        assert_eq!(difficulty(16, Some(1024), 1024), 17);
+        assert_eq!(difficulty(16, Some(1024), 2047), 17);
+        assert_eq!(difficulty(16, Some(1024), 2048), 18);
+        assert_eq!(difficulty(16, Some(1024), 1 << 20), 27);
+        assert_eq!(difficulty(255, Some(1), u64::MAX), 255);
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn verify_solutions() {
+        let expiry = Utc::now() + CHALLENGE_LIFETIME;
+        let challenge = Challenge::new(8, 1024).sign(KEY, expiry);
+        let proof = solve(&challenge, 8);
+        let (verified, verified_expiry) = verify_solution(KEY, &proof).unwrap();
+        assert_eq!(verified.difficulty, 8);
+        assert_eq!(verified.max_size, 1024);
+        assert_eq!(verified_expiry.timestamp(), expiry.timestamp());
+
+        let wrong = (0u64..)
+            .map(|n| format!("{challenge}:{n}"))
+            .find(|proof| leading_zero_bits(&Sha256::digest(proof.as_bytes())) < 8)
+            .unwrap();
+        assert!(matches!(
+            verify_solution(KEY, &wrong),
+            Err(PowError::InsufficientWork(8))
+        ));
+
+        // the difficulty can't be changed without breaking the signature
+        let tampered = proof.replacen(":8:", ":0:", 1);
+        assert!(matches!(
+            verify_solution(KEY, &tampered),
+            Err(PowError::InvalidChallenge(_))
+        ));
+        assert!(matches!(
+            verify_solution(b"other key", &proof),
+            Err(PowError::InvalidChallenge(_))
+        ));
+        assert!(matches!(
+            verify_solution(KEY, "nothing"),
+            Err(PowError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn expired_challenge() {
+        let challenge = Challenge::new(0, 1024).sign(KEY, Utc::now() - TimeDelta::minutes(1));
+        assert!(matches!(
+            verify_solution(KEY, &format!("{challenge}:0")),
+            Err(PowError::InvalidChallenge(token::VerifyTokenError::Expired))
+        ));
+    }
+
+    #[test]
+    fn challenges_are_used_once() {
+        let used = UsedChallenges::default();
+        let now = Utc::now();
+        assert!(used.insert("abc", now + CHALLENGE_LIFETIME));
+        assert!(!used.insert("abc", now + CHALLENGE_LIFETIME));
+        assert!(used.insert("def", now - TimeDelta::minutes(1)));
+
+        used.prune(now);
+        assert!(!used.insert("abc", now + CHALLENGE_LIFETIME));
+        assert!(used.insert("def", now + CHALLENGE_LIFETIME));
+    }
+}
@@ -0,0 +1,228 @@
+//! Serving byte ranges of a file for `Range` requests, so media can be seeked
+//! and downloads can be resumed
+
+use std::{collections::VecDeque, io::SeekFrom, ops::Bound};
+
+use axum::body::Bytes;
+use futures_util::{stream, Stream};
+use headers::Range;
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
+
+/// Maximum number of ranges allowed in one request. Requests for more ranges
+/// are answered with the whole file instead.
+pub const MAX_RANGES: usize = 16;
+
+/// Size of each chunk read from the file while streaming a multipart response
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// What part of a file to send in response to a `Range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeSelection {
+    /// send the whole file as if there was no `Range` header
+    Full,
+    /// send these inclusive byte ranges
+    Partial(Vec<(u64, u64)>),
+    /// none of the requested ranges are inside the file
+    Unsatisfiable,
+}
+
+/// Resolve the ranges requested in a `Range` header against a file that is
+/// `len` bytes long.
+///
+/// Ranges that start past the end of the file are ignored, and ranges that end
+/// past it are shortened. Returns [`RangeSelection::Unsatisfiable`] if no
+/// ranges are left after that. The rest are sorted and the ones that overlap or
+/// touch are merged, so no part of the file is sent more than once.
+pub fn resolve_ranges(range: &Range, len: u64) -> RangeSelection {
+    let mut ranges = Vec::new();
+    for (start, end) in range.satisfiable_ranges(len) {
+        if ranges.len() == MAX_RANGES {
+            return RangeSelection::Full;
+        }
+        let start = match start {
+            Bound::Included(s) => s,
+            Bound::Excluded(s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match end {
+            Bound::Included(e) => e.min(len.saturating_sub(1)),
+            Bound::Excluded(e) => e.saturating_sub(1).min(len.saturating_sub(1)),
+            Bound::Unbounded => len.saturating_sub(1),
+        };
+        if start < len && start <= end {
+            ranges.push((start, end));
+        }
+    }
+
+    if ranges.is_empty() {
+        return RangeSelection::Unsatisfiable;
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    RangeSelection::Partial(merged)
+}
+
+/// A piece of a multipart response body
+enum Segment {
+    /// bytes that are sent as-is (part headers and boundaries)
+    Bytes(Bytes),
+    /// a range of the file, as the start offset and length
+    File { start: u64, len: u64 },
+}
+
+/// A `multipart/byteranges` response body containing multiple ranges of a
+/// file (RFC 9110 section 14.6)
+pub struct MultipartByteranges<S> {
+    /// value of the `Content-Type` header, including the boundary
+    pub content_type: String,
+    /// total length of the body in bytes
+    pub content_length: u64,
+    /// the body itself
+    pub body: S,
+}
+
+/// Build a `multipart/byteranges` body with each of `ranges` from `file`,
+/// which is `len` bytes long and has the MIME type `mimetype`.
+pub fn multipart_byteranges(
+    file: File,
+    ranges: &[(u64, u64)],
+    len: u64,
+    mimetype: &str,
+) -> MultipartByteranges<impl Stream<Item = io::Result<Bytes>>> {
+    let mut boundary = [0; 16];
+    rand::fill(&mut boundary);
+    let boundary = hex::encode(boundary);
+
+    let mut segments = VecDeque::new();
+    for &(start, end) in ranges {
+        segments.push_back(Segment::Bytes(Bytes::from(format!(
+            "\r\n--{boundary}\r\nContent-Type: {mimetype}\r\nContent-Range: bytes \
+             {start}-{end}/{len}\r\n\r\n"
+        ))));
+        segments.push_back(Segment::File {
+            start,
+            len: end - start + 1,
+        });
+    }
+    segments.push_back(Segment::Bytes(Bytes::from(format!(
+        "\r\n--{boundary}--\r\n"
+    ))));
+
+    let content_length = segments
+        .iter()
+        .map(|s| match s {
+            Segment::Bytes(b) => b.len() as u64,
+            Segment::File { len, .. } => *len,
+        })
+        .sum();
+
+    // read the ranges one after another from the same file handle, seeking to
+    // the start of each one
+    let body = stream::try_unfold(
+        (file, segments, 0),
+        |(mut file, mut segments, mut remaining)| async move {
+            if remaining == 0 {
+                match segments.pop_front() {
+                    None => return Ok(None),
+                    Some(Segment::Bytes(b)) => return Ok(Some((b, (file, segments, 0)))),
+                    Some(Segment::File { start, len }) => {
+                        file.seek(SeekFrom::Start(start)).await?;
+                        remaining = len;
+                    }
+                }
+            }
+            let mut buf = vec![0; remaining.min(CHUNK_SIZE) as usize];
+            file.read_exact(&mut buf).await?;
+            remaining -= buf.len() as u64;
+            Ok(Some((Bytes::from(buf), (file, segments, remaining))))
+        },
+    );
+
+    MultipartByteranges {
+        content_type: format!("multipart/byteranges; boundary={boundary}"),
+        content_length,
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use headers::{Header, HeaderValue};
+
+    use super::*;
+
+    fn range(value: &'static str) -> Range {
+        Range::decode(&mut [HeaderValue::from_static(value)].iter()).unwrap()
+    }
+
+    #[test]
+    fn single_range() {
+        assert_eq!(
+            resolve_ranges(&range("bytes=0-99"), 1000),
+            RangeSelection::Partial(vec![(0, 99)])
+        );
+    }
+    #[test]
+    fn open_ended_and_suffix_ranges() {
+        assert_eq!(
+            resolve_ranges(&range("bytes=900-, -10"), 1000),
+            RangeSelection::Partial(vec![(900, 999)])
+        );
+        assert_eq!(
+            resolve_ranges(&range("bytes=900-, -200"), 1000),
+            RangeSelection::Partial(vec![(800, 999)])
+        );
+    }
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(
+            resolve_ranges(&range("bytes=0-,0-,0-,0-,0-,0-,0-,0-"), 1000),
+            RangeSelection::Partial(vec![(0, 999)])
+        );
+        assert_eq!(
+            resolve_ranges(&range("bytes=500-599, 0-99, 100-199, 550-700, 800-"), 1000),
+            RangeSelection::Partial(vec![(0, 199), (500, 700), (800, 999)])
+        );
+    }
+    #[test]
+    fn end_past_file_is_shortened() {
+        assert_eq!(
+            resolve_ranges(&range("bytes=500-5000"), 1000),
+            RangeSelection::Partial(vec![(500, 999)])
+        );
+    }
+    #[test]
+    fn start_past_file_is_unsatisfiable() {
+        assert_eq!(
+            resolve_ranges(&range("bytes=1000-"), 1000),
+            RangeSelection::Unsatisfiable
+        );
+        assert_eq!(
+            resolve_ranges(&range("bytes=0-"), 0),
+            RangeSelection::Unsatisfiable
+        );
+    }
+    #[test]
+    fn too_many_ranges_sends_full_file() {
+        let header = (0..=MAX_RANGES)
+            .map(|i| format!("{i}-{i}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let header =
+            Range::decode(&mut [HeaderValue::from_str(&format!("bytes={header}")).unwrap()].iter())
+                .unwrap();
+        assert_eq!(resolve_ranges(&header, 1000), RangeSelection::Full);
+    }
+}
@@ -0,0 +1,431 @@
+//! Token bucket rate limiting of uploads, deletes, views and password attempts
+//!
+//! Each kind of request has its own [`RateLimiter`], with a bucket for every
+//! client. Clients are identified by their API key when uploading with one, and
+//! by their IP address (see [`ClientIp`]) otherwise. Password attempts are also
+//! limited per upload (see [`RateLimiters::check_password_attempt`]).
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::ParseIntError,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration as StdDuration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use displaydoc::Display;
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Method, StatusCode,
+};
+use thiserror::Error;
+use tracing::{event, instrument, Level};
+
+use crate::{
+    api::v1::ApiErrorExt, api_key::find_api_key, client_ip::ClientIp, str_to_duration, AppState,
+    StrToDurationError,
+};
+
+/// Errors when parsing a [`RateLimit`]
+#[derive(Debug, Error, Display)]
+pub enum ParseRateLimitError {
+    /// rate limit must be in the format `count/duration` (try: 10/1m)
+    Invalid,
+    /// error parsing request count
+    Count(#[from] ParseIntError),
+    /// request count must be greater than zero
+    ZeroCount,
+    /// error parsing duration
+    Duration(#[from] StrToDurationError),
+    /// duration must be greater than zero
+    ZeroDuration,
+}
+
+/// A limit of `burst` requests every `period`
+///
+/// Parsed from a string like `10/1m` (see [`str_to_duration`] for the duration
+/// format).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// how many requests can be made at once
+    pub burst: u32,
+    /// how long it takes for all of the requests to become available again
+    pub period: StdDuration,
+}
+impl RateLimit {
+    /// how many requests become available every second
+    fn per_second(&self) -> f64 {
+        f64::from(self.burst) / self.period.as_secs_f64()
+    }
+}
+impl FromStr for RateLimit {
+    type Err = ParseRateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, period) = s.split_once('/').ok_or(ParseRateLimitError::Invalid)?;
+        let burst = burst.parse()?;
+        if burst == 0 {
+            return Err(ParseRateLimitError::ZeroCount);
+        }
+        let period = str_to_duration(period)?;
+        if period.is_zero() {
+            return Err(ParseRateLimitError::ZeroDuration);
+        }
+        Ok(Self { burst, period })
+    }
+}
+
+/// What a client is identified by when rate limiting
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    /// IP address of the client
+    Ip(IpAddr),
+    /// name of the API key the client is using
+    ApiKey(String),
+    /// ID of the upload being accessed, shared by every client
+    Upload(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter, with a bucket for each [`RateLimitKey`]
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<RateLimitKey, Bucket>>,
+}
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a request from the bucket of `key`. If it's empty, returns how long
+    /// until a request becomes available.
+    pub fn check(&self, key: RateLimitKey, now: Instant) -> Result<(), StdDuration> {
+        let per_second = self.limit.per_second();
+        let burst = f64::from(self.limit.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(StdDuration::from_secs_f64(
+                (1.0 - bucket.tokens) / per_second,
+            ))
+        }
+    }
+
+    /// Remove the buckets that have refilled completely, since they're the same
+    /// as a new one.
+    pub fn prune(&self, now: Instant) {
+        let per_second = self.limit.per_second();
+        let burst = f64::from(self.limit.burst);
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            bucket.tokens + elapsed.as_secs_f64() * per_second < burst
+        });
+    }
+}
+
+/// Kinds of requests that are rate limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum RequestKind {
+    /// upload
+    Upload,
+    /// delete
+    Delete,
+    /// view
+    View,
+    /// password attempt
+    Password,
+}
+impl RequestKind {
+    /// Find out which kind of request is being made, or [`None`] if it isn't
+    /// rate limited.
+    ///
+    /// Creating links to private uploads counts as a delete, since it's also
+    /// authorized with the delete key. So do unlocking password-protected
+    /// uploads and getting an upload's info with the `Bobashare-Delete-Key` or
+    /// `Bobashare-Password` header, so they can't be used to guess keys and
+    /// passwords at the much higher view rate.
+    pub fn of(method: &Method, path: &str, headers: &HeaderMap) -> Option<Self> {
+        let segments = path
+            .strip_prefix('/')
+            .unwrap_or(path)
+            .split('/')
+            .collect::<Vec<_>>();
+        let authorized = ["Bobashare-Delete-Key", "Bobashare-Password"]
+            .iter()
+            .any(|h| headers.contains_key(*h));
+        match (method, segments.as_slice()) {
+            (&Method::GET | &Method::HEAD, ["static", ..] | ["api", _, "tus", ..]) => None,
+            (&Method::GET | &Method::HEAD, ["api", _, "info", _]) if authorized => {
+                Some(Self::Delete)
+            }
+            (&Method::GET | &Method::HEAD, _) => Some(Self::View),
+            (&Method::PUT, ["api", _, "upload", _]) => Some(Self::Upload),
+            (&Method::POST, ["api", _, "upload" | "tus"] | [""] | ["paste", ""]) => {
+                Some(Self::Upload)
+            }
+            (&Method::DELETE, ["api", _, "delete", _]) => Some(Self::Delete),
+            (&Method::POST, ["api", _, "link", _]) => Some(Self::Delete),
+            (&Method::POST, [id]) if !id.is_empty() => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// Rate limiters for each [`RequestKind`] ([`None`] if unlimited)
+#[derive(Debug, Default)]
+pub struct RateLimiters {
+    pub upload: Option<RateLimiter>,
+    pub delete: Option<RateLimiter>,
+    pub view: Option<RateLimiter>,
+    pub password: Option<RateLimiter>,
+}
+impl RateLimiters {
+    pub fn get(&self, kind: RequestKind) -> Option<&RateLimiter> {
+        match kind {
+            RequestKind::Upload => self.upload.as_ref(),
+            RequestKind::Delete => self.delete.as_ref(),
+            RequestKind::View => self.view.as_ref(),
+            RequestKind::Password => self.password.as_ref(),
+        }
+    }
+
+    /// Take a password attempt from the bucket of the client at `ip` and from
+    /// the bucket of the upload `id`.
+    ///
+    /// Limiting each upload as well means its password can't be guessed any
+    /// faster by spreading the attempts over many addresses.
+    pub fn check_password_attempt(&self, ip: IpAddr, id: &str) -> Result<(), TooManyRequests> {
+        let Some(limiter) = &self.password else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        for key in [RateLimitKey::Ip(ip), RateLimitKey::Upload(id.to_string())] {
+            if let Err(retry_after) = limiter.check(key.clone(), now) {
+                event!(
+                    Level::INFO,
+                    ?key,
+                    ?retry_after,
+                    "password attempt was rate limited"
+                );
+                return Err(TooManyRequests::new(RequestKind::Password, retry_after));
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the buckets that have refilled completely (see
+    /// [`RateLimiter::prune`]).
+    pub fn prune(&self) {
+        let now = Instant::now();
+        for limiter in [&self.upload, &self.delete, &self.view, &self.password]
+            .into_iter()
+            .flatten()
+        {
+            limiter.prune(now);
+        }
+    }
+}
+
+/// too many {kind} requests, try again in {retry_after} seconds
+#[derive(Debug, Error, Display)]
+pub struct TooManyRequests {
+    kind: RequestKind,
+    retry_after: u64,
+}
+impl TooManyRequests {
+    fn new(kind: RequestKind, retry_after: StdDuration) -> Self {
+        Self {
+            kind,
+            // round up so the client doesn't retry too early
+            retry_after: retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0),
+        }
+    }
+}
+impl IntoResponse for TooManyRequests {
+    fn into_response(self) -> Response {
+        let retry_after = HeaderValue::from(self.retry_after);
+        let mut resp = self.into_response_with_code(StatusCode::TOO_MANY_REQUESTS);
+        resp.headers_mut().insert(header::RETRY_AFTER, retry_after);
+        resp
+    }
+}
+
+/// Middleware that rejects requests from clients that have gone over the rate
+/// limit of that kind of request
+///
+/// Uploads with a valid API key in the `Authorization: Bearer` header are
+/// limited per key, and everything else is limited per IP address.
+#[instrument(skip_all)]
+pub async fn rate_limit(
+    State(state): State<&'static AppState>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(kind) = RequestKind::of(request.method(), request.uri().path(), request.headers())
+    else {
+        return next.run(request).await;
+    };
+    let Some(limiter) = state.rate_limiters.get(kind) else {
+        return next.run(request).await;
+    };
+
+    let mut key = RateLimitKey::Ip(ip);
+    if kind == RequestKind::Upload {
+        if let Some(Authorization(bearer)) = request.headers().typed_get::<Authorization<Bearer>>()
+        {
+            // invalid keys are limited by IP, so they can't be used to get more
+            // requests
+            match find_api_key(state, bearer.token()).await {
+                Ok(Some(api_key)) => key = RateLimitKey::ApiKey(api_key.name),
+                Ok(None) => {}
+                Err(err) => event!(Level::ERROR, "error looking up API key: {err:#}"),
+            }
+        }
+    }
+
+    if let Err(retry_after) = limiter.check(key.clone(), Instant::now()) {
+        event!(Level::INFO, ?key, %kind, ?retry_after, "request was rate limited");
+        return TooManyRequests::new(kind, retry_after).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!(
+            "10/1m".parse::<RateLimit>().unwrap(),
+            RateLimit {
+                burst: 10,
+                period: StdDuration::from_secs(60),
+            }
+        );
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("0/1m".parse::<RateLimit>().is_err());
+        assert!("10/0s".parse::<RateLimit>().is_err());
+        assert!("ten/1m".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limiter = RateLimiter::new("2/10s".parse().unwrap());
+        let key = RateLimitKey::Ip(IpAddr::from([127, 0, 0, 1]));
+        let start = Instant::now();
+        assert!(limiter.check(key.clone(), start).is_ok());
+        assert!(limiter.check(key.clone(), start).is_ok());
+        assert_eq!(
+            limiter.check(key.clone(), start),
+            Err(StdDuration::from_secs(5))
+        );
+        // other clients have their own bucket
+        assert!(limiter
+            .check(RateLimitKey::ApiKey(String::from("key")), start)
+            .is_ok());
+
+        let later = start + StdDuration::from_secs(5);
+        assert!(limiter.check(key.clone(), later).is_ok());
+        assert!(limiter.check(key.clone(), later).is_err());
+
+        limiter.prune(later + StdDuration::from_secs(10));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn password_attempts_limited_per_upload() {
+        let limiters = RateLimiters {
+            password: Some(RateLimiter::new("2/10m".parse().unwrap())),
+            ..Default::default()
+        };
+        let ip = |last| IpAddr::from([10, 0, 0, last]);
+        assert!(limiters.check_password_attempt(ip(1), "abc").is_ok());
+        assert!(limiters.check_password_attempt(ip(2), "abc").is_ok());
+        // a new address can't keep guessing the same upload's password
+        assert!(limiters.check_password_attempt(ip(3), "abc").is_err());
+        // but can still try other uploads, until its own bucket is empty
+        assert!(limiters.check_password_attempt(ip(3), "xyz").is_ok());
+        assert!(limiters.check_password_attempt(ip(3), "def").is_err());
+    }
+
+    #[test]
+    fn request_kinds() {
+        let kind = |method: Method, path| RequestKind::of(&method, path, &HeaderMap::new());
+        assert_eq!(
+            kind(Method::PUT, "/api/v1/upload/a.txt"),
+            Some(RequestKind::Upload)
+        );
+        assert_eq!(
+            kind(Method::POST, "/api/v1/upload"),
+            Some(RequestKind::Upload)
+        );
+        assert_eq!(
+            kind(Method::POST, "/api/latest/tus"),
+            Some(RequestKind::Upload)
+        );
+        assert_eq!(kind(Method::POST, "/"), Some(RequestKind::Upload));
+        assert_eq!(kind(Method::POST, "/paste/"), Some(RequestKind::Upload));
+        assert_eq!(
+            kind(Method::DELETE, "/api/v1/delete/abc"),
+            Some(RequestKind::Delete)
+        );
+        assert_eq!(
+            kind(Method::POST, "/api/v1/link/abc"),
+            Some(RequestKind::Delete)
+        );
+        assert_eq!(kind(Method::GET, "/abc"), Some(RequestKind::View));
+        assert_eq!(kind(Method::GET, "/raw/abc"), Some(RequestKind::View));
+        assert_eq!(kind(Method::POST, "/abc"), Some(RequestKind::Delete));
+        assert_eq!(
+            kind(Method::GET, "/api/v1/info/abc"),
+            Some(RequestKind::View)
+        );
+        assert_eq!(kind(Method::GET, "/static/css/main.css"), None);
+        assert_eq!(kind(Method::PATCH, "/api/v1/tus/abc"), None);
+        assert_eq!(kind(Method::HEAD, "/api/v1/tus/abc"), None);
+    }
+    #[test]
+    fn info_with_key_or_password_counts_as_delete() {
+        for header in ["Bobashare-Delete-Key", "Bobashare-Password"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header, HeaderValue::from_static("guess"));
+            assert_eq!(
+                RequestKind::of(&Method::GET, "/api/v1/info/abc", &headers),
+                Some(RequestKind::Delete)
+            );
+            // other views are still limited by their password attempts
+            assert_eq!(
+                RequestKind::of(&Method::GET, "/raw/abc", &headers),
+                Some(RequestKind::View)
+            );
+        }
+    }
+}
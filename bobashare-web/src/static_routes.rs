@@ -1,14 +1,44 @@
 //! Handler to serve static files
+//!
+//! Pages link to static files with [`url()`], which adds a version based on
+//! the hash of the file, so they can be cached forever and a new URL is used
+//! whenever they change.
 
 use axum::response::IntoResponse;
 use hyper::{header, HeaderMap, StatusCode, Uri};
-use rust_embed::RustEmbed;
+use rust_embed::{EmbeddedFile, RustEmbed};
 use tracing::{event, instrument, Level};
+use url::Url;
 
 #[derive(RustEmbed)]
 #[folder = "static/"]
 struct Asset;
 
+/// `Cache-Control` for static files requested with their current version
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Get the version of a static file, which is the start of its SHA-256 hash.
+fn version(file: &EmbeddedFile) -> String {
+    hex::encode(&file.metadata.sha256_hash()[..8])
+}
+
+/// Get the URL of the static file at `path` (relative to the `static/`
+/// directory), with its version in the query.
+///
+/// Files that don't exist don't get a version.
+pub fn url(base_url: &Url, path: &str) -> Url {
+    let mut url = base_url.join("static/").unwrap().join(path).unwrap();
+    if let Some(file) = Asset::get(path) {
+        url.set_query(Some(&format!("v={}", version(&file))));
+    }
+    url
+}
+
+/// Serve a static file.
+///
+/// If the request has the current version of the file (see [`url()`]), it can
+/// be cached forever, since a different URL will be used if it changes.
+/// Otherwise it has to be revalidated every time.
 #[instrument(skip(headers), fields(if_none_match = ?headers.get(header::IF_NONE_MATCH)))]
 pub async fn handler(uri: Uri, headers: HeaderMap) -> impl IntoResponse {
     let path = uri.path().trim_start_matches('/');
@@ -35,12 +65,22 @@ pub async fn handler(uri: Uri, headers: HeaderMap) -> impl IntoResponse {
                 }
             }
 
+            let versioned = uri
+                .query()
+                .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("v=")))
+                .is_some_and(|v| v == version(&f));
+            let cache_control = if versioned {
+                IMMUTABLE_CACHE_CONTROL
+            } else {
+                "no-cache"
+            };
+
             let mimetype = f.metadata.mimetype();
-            event!(Level::DEBUG, ?sha256, ?mimetype);
+            event!(Level::DEBUG, ?sha256, ?mimetype, versioned);
             (
                 [
                     (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
-                    (header::CACHE_CONTROL, "no-cache".to_string()),
+                    (header::CACHE_CONTROL, cache_control.to_string()),
                     (header::CONTENT_TYPE, mimetype.to_string()),
                     (header::ETAG, sha256),
                 ],
@@ -0,0 +1,165 @@
+//! Tokens signed with the server's secret key, used to grant access to an
+//! upload without sending its password on every request, or to share a private
+//! upload through a link that expires. They also sign proof-of-work
+//! challenges.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use displaydoc::Display;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a token granted by entering an upload's password is valid for
+pub const UNLOCK_TOKEN_LIFETIME: TimeDelta = TimeDelta::hours(1);
+/// Shortest secret key that tokens can be signed with, in bytes. Shorter keys
+/// could be guessed, letting anyone sign their own tokens.
+pub const MIN_SECRET_KEY_LENGTH: usize = 32;
+
+/// What a token grants access to. Tokens signed for one scope are never valid
+/// for another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    /// proves that the password of a password-protected upload was entered
+    Unlock,
+    /// grants access to a private upload through a signed link
+    Access,
+    /// a proof-of-work challenge created by the server (see [`crate::pow`])
+    Challenge,
+}
+impl TokenScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unlock => "unlock",
+            Self::Access => "access",
+            Self::Challenge => "challenge",
+        }
+    }
+}
+
+fn mac(key: &[u8], scope: TokenScope, id: &str, expiry: i64) -> HmacSha256 {
+    // SAFETY: HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(format!("{}:{id}:{expiry}", scope.as_str()).as_bytes());
+    mac
+}
+
+/// Sign a token that grants access to the upload `id` until `expiry`.
+///
+/// The token is in the format `{expiry}.{signature}`, where `expiry` is a unix
+/// timestamp and `signature` is a hex-encoded HMAC-SHA256.
+pub fn sign(key: &[u8], scope: TokenScope, id: &str, expiry: DateTime<Utc>) -> String {
+    let expiry = expiry.timestamp();
+    let signature = hex::encode(mac(key, scope, id, expiry).finalize().into_bytes());
+    format!("{expiry}.{signature}")
+}
+
+/// Errors when verifying a token with [`verify`]
+#[derive(Debug, Error, Display)]
+pub enum VerifyTokenError {
+    /// token is not in the correct format
+    Malformed,
+    /// token signature is invalid
+    InvalidSignature,
+    /// token has expired
+    Expired,
+}
+
+/// Verify a token created by [`sign`] for the upload `id`.
+///
+/// The signature is compared in constant time.
+pub fn verify(
+    key: &[u8],
+    scope: TokenScope,
+    id: &str,
+    token: &str,
+) -> Result<(), VerifyTokenError> {
+    let (expiry, signature) = token.split_once('.').ok_or(VerifyTokenError::Malformed)?;
+    let expiry = expiry
+        .parse::<i64>()
+        .map_err(|_| VerifyTokenError::Malformed)?;
+    let signature = hex::decode(signature).map_err(|_| VerifyTokenError::Malformed)?;
+
+    mac(key, scope, id, expiry)
+        .verify_slice(&signature)
+        .map_err(|_| VerifyTokenError::InvalidSignature)?;
+
+    if expiry < Utc::now().timestamp() {
+        return Err(VerifyTokenError::Expired);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"super secret key";
+
+    fn in_one_hour() -> DateTime<Utc> {
+        Utc::now() + TimeDelta::hours(1)
+    }
+
+    #[test]
+    fn valid_token() {
+        let token = sign(KEY, TokenScope::Unlock, "abc123", in_one_hour());
+        assert!(verify(KEY, TokenScope::Unlock, "abc123", &token).is_ok());
+    }
+    #[test]
+    fn wrong_id() {
+        let token = sign(KEY, TokenScope::Unlock, "abc123", in_one_hour());
+        assert!(matches!(
+            verify(KEY, TokenScope::Unlock, "xyz789", &token),
+            Err(VerifyTokenError::InvalidSignature)
+        ));
+    }
+    #[test]
+    fn wrong_key() {
+        let token = sign(KEY, TokenScope::Unlock, "abc123", in_one_hour());
+        assert!(matches!(
+            verify(b"other key", TokenScope::Unlock, "abc123", &token),
+            Err(VerifyTokenError::InvalidSignature)
+        ));
+    }
+    #[test]
+    fn wrong_scope() {
+        let token = sign(KEY, TokenScope::Unlock, "abc123", in_one_hour());
+        assert!(matches!(
+            verify(KEY, TokenScope::Access, "abc123", &token),
+            Err(VerifyTokenError::InvalidSignature)
+        ));
+    }
+    #[test]
+    fn tampered_expiry() {
+        let token = sign(KEY, TokenScope::Unlock, "abc123", in_one_hour());
+        let (_, signature) = token.split_once('.').unwrap();
+        let tampered = format!("{}.{signature}", i64::MAX);
+        assert!(matches!(
+            verify(KEY, TokenScope::Unlock, "abc123", &tampered),
+            Err(VerifyTokenError::InvalidSignature)
+        ));
+    }
+    #[test]
+    fn expired() {
+        let token = sign(
+            KEY,
+            TokenScope::Unlock,
+            "abc123",
+            Utc::now() - TimeDelta::minutes(1),
+        );
+        assert!(matches!(
+            verify(KEY, TokenScope::Unlock, "abc123", &token),
+            Err(VerifyTokenError::Expired)
+        ));
+    }
+    #[test]
+    fn malformed() {
+        for token in ["", "abc", "123.nothex", "notanumber.abcd"] {
+            assert!(matches!(
+                verify(KEY, TokenScope::Unlock, "abc123", token),
+                Err(VerifyTokenError::Malformed)
+            ));
+        }
+    }
+}
@@ -1,33 +1,69 @@
 //! Routes to display or download an upload in a browser
 
+use std::{
+    io::SeekFrom,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Context;
 use askama::Template;
 use axum::{
-    body::Body,
-    extract::{Path, Query, State},
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{Path, Query, RawQuery, State},
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use bobashare::{
+    sniff::charset_of,
+    storage::{
+        file::{ConsumeViewError, FileBackend, OpenUploadError},
+        handle::UploadHandle,
+        upload::Upload,
+    },
 };
-use bobashare::storage::{file::OpenUploadError, handle::UploadHandle};
 use chrono::{DateTime, TimeDelta, Utc};
 use displaydoc::Display;
-use hyper::{header, StatusCode};
+use futures_util::TryStreamExt;
+use headers::{
+    AcceptRanges, CacheControl, ContentLength, ContentRange, ETag, Expires, HeaderMapExt,
+    IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range,
+};
+use hyper::{
+    header,
+    header::{HeaderName, HeaderValue},
+    HeaderMap, StatusCode,
+};
 use mime::Mime;
 use serde::{Deserialize, Deserializer};
 use syntect::{html::ClassedHTMLGenerator, util::LinesWithEndings};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 use tracing::{event, instrument, Level};
 use url::Url;
 
 use super::{filters, prelude::*, render_template, ErrorResponse, ErrorTemplate, TemplateState};
-use crate::{render_markdown_with_syntax_set, AppState, CLASS_STYLE};
+use crate::{
+    active_content::{self, ActiveContentAction},
+    check_password,
+    client_ip::ClientIp,
+    range::{self, RangeSelection},
+    render_markdown_with_syntax_set,
+    token::{self, TokenScope, UNLOCK_TOKEN_LIFETIME},
+    AppState, CLASS_STYLE,
+};
 
 /// Errors when trying to view/download an upload
 #[derive(Debug, Error, Display)]
 pub enum ViewUploadError {
     /// an upload at the specified id was not found
     NotFound,
+    /// the upload is busy, try again in a moment
+    Busy,
+    /// too many password attempts, try again later
+    TooManyAttempts,
 
     /// internal server error
     InternalServer(#[from] anyhow::Error),
@@ -36,10 +72,35 @@ impl From<OpenUploadError> for ViewUploadError {
     fn from(err: OpenUploadError) -> Self {
         match err {
             OpenUploadError::NotFound(_) => Self::NotFound,
+            OpenUploadError::Locked => Self::Busy,
             _ => Self::InternalServer(anyhow::Error::new(err).context("error opening upload")),
         }
     }
 }
+impl ViewUploadError {
+    fn into_error_template(self, state: TemplateState<'_>) -> ErrorTemplate<'_> {
+        let code = match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            Self::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ErrorTemplate {
+            state,
+            code,
+            message: self.to_string(),
+        }
+    }
+}
+impl From<ConsumeViewError> for ViewUploadError {
+    fn from(err: ConsumeViewError) -> Self {
+        match err {
+            ConsumeViewError::NotFound(_) | ConsumeViewError::NoViewsRemaining => Self::NotFound,
+            ConsumeViewError::Locked => Self::Busy,
+            _ => Self::InternalServer(anyhow::Error::new(err).context("error consuming view")),
+        }
+    }
+}
 
 async fn open_upload<S: AsRef<str>>(
     state: &AppState,
@@ -61,6 +122,146 @@ async fn open_upload<S: AsRef<str>>(
     Ok(upload)
 }
 
+/// Use up one view of an upload that has a view limit, deleting it if that was
+/// the last one.
+///
+/// The file handle is already open, so it can still be read or streamed after
+/// the upload is deleted.
+async fn consume_view(state: &AppState, upload: &mut UploadHandle) -> Result<(), ViewUploadError> {
+    if upload.metadata.remaining_views.is_none() {
+        return Ok(());
+    }
+
+    upload.metadata.remaining_views = state.backend.consume_view(&upload.metadata.id).await?;
+    event!(
+        Level::INFO,
+        remaining_views = upload.metadata.remaining_views,
+        "consumed a view of the upload"
+    );
+    if upload.metadata.remaining_views == Some(0) {
+        event!(
+            Level::INFO,
+            "last view of upload was used; it will be deleted"
+        );
+        state
+            .backend
+            .delete_upload(&upload.metadata.id)
+            .await
+            .context("error deleting upload after its last view")?;
+    }
+    Ok(())
+}
+
+/// Check whether a request is allowed to know that an upload exists.
+///
+/// A private upload can only be accessed with a valid signed access token in the
+/// `access` query parameter (see [`crate::api::v1::link`]). Requests without one
+/// get the same response as if the upload didn't exist.
+fn check_link(
+    state: &AppState,
+    upload: &Upload,
+    access: Option<&str>,
+) -> Result<(), ViewUploadError> {
+    if !upload.private {
+        return Ok(());
+    }
+    let Some(access) = access else {
+        event!(
+            Level::INFO,
+            "upload is private and no access token was given"
+        );
+        return Err(ViewUploadError::NotFound);
+    };
+    token::verify(&state.secret_key, TokenScope::Access, &upload.id, access).map_err(|e| {
+        event!(
+            Level::INFO,
+            "upload is private and access token is invalid: {e}"
+        );
+        ViewUploadError::NotFound
+    })
+}
+
+/// Name of the cookie that holds the token unlocking a password-protected
+/// upload
+fn unlock_cookie_name(id: &str) -> String {
+    format!("bobashare-unlock-{id}")
+}
+
+/// Whether a request is allowed to see the contents of an upload
+#[derive(Debug)]
+enum Access {
+    /// the upload is not password protected
+    Public,
+    /// the request proved it knows the password; contains a token that can be
+    /// passed along in URLs to the raw file
+    Unlocked(String),
+    /// the password needs to be entered first
+    Locked,
+}
+
+/// Check whether a request can access an upload.
+///
+/// A password-protected upload can be unlocked with a valid token in the
+/// `token` query parameter, a valid token in the unlock cookie (set by
+/// [`unlock`]), or the password itself in the `Bobashare-Password` header.
+/// Passwords count as password attempts for rate limiting, and tokens don't.
+async fn check_access(
+    state: &AppState,
+    upload: &Upload,
+    jar: &CookieJar,
+    query_token: Option<&str>,
+    headers: &HeaderMap,
+    ip: IpAddr,
+) -> Result<Access, ViewUploadError> {
+    if !upload.is_password_protected() {
+        return Ok(Access::Public);
+    }
+
+    let cookie = jar.get(&unlock_cookie_name(&upload.id));
+    let tokens = query_token.into_iter().chain(cookie.map(Cookie::value));
+    for t in tokens {
+        match token::verify(&state.secret_key, TokenScope::Unlock, &upload.id, t) {
+            Ok(()) => return Ok(Access::Unlocked(t.to_string())),
+            Err(e) => event!(Level::DEBUG, "ignoring invalid unlock token: {e}"),
+        }
+    }
+
+    if let Some(password) = headers
+        .get("Bobashare-Password")
+        .and_then(|p| p.to_str().ok())
+    {
+        state
+            .rate_limiters
+            .check_password_attempt(ip, &upload.id)
+            .map_err(|_| ViewUploadError::TooManyAttempts)?;
+        if check_password(upload, password).await {
+            let expiry = Utc::now() + UNLOCK_TOKEN_LIFETIME;
+            return Ok(Access::Unlocked(token::sign(
+                &state.secret_key,
+                TokenScope::Unlock,
+                &upload.id,
+                expiry,
+            )));
+        }
+        event!(
+            Level::INFO,
+            "password in `Bobashare-Password` header was incorrect"
+        );
+    }
+
+    Ok(Access::Locked)
+}
+
+#[derive(Template)]
+#[template(path = "password.html.jinja", blocks = ["title"])]
+pub struct PasswordTemplate<'s> {
+    pub state: TemplateState<'s>,
+    pub id: String,
+    /// URL to submit the password form to
+    pub action: Url,
+    pub incorrect: bool,
+}
+
 #[derive(Template)]
 // `blocks = ["title"]` not required here as diplay.html.jinja overrides the preview_metadata to
 // customize the title separately from the embed meta tags
@@ -76,6 +277,13 @@ pub struct DisplayTemplate<'s> {
     pub contents: DisplayType,
     pub raw_url: Url,
     pub download_url: Url,
+    pub remaining_views: Option<u64>,
+    /// whether the contents aren't embedded until the viewer confirms, since
+    /// loading them would use the last view
+    pub confirm_last_view: bool,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
 }
 #[derive(Debug)]
 pub enum DisplayType {
@@ -93,29 +301,78 @@ pub enum DisplayType {
     Other,
     TooLarge,
 }
+impl DisplayType {
+    /// Whether the contents are sent in the display page itself, instead of
+    /// being embedded from (or linked to) the raw file
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Self::Text { .. } | Self::Markdown { .. })
+    }
+}
 
 /// Maximum file size that will be rendered
 const MAX_DISPLAY_SIZE: u64 = 1024 * 1024; // 1 MiB
 
+#[derive(Debug, Deserialize)]
+pub struct DisplayParams {
+    token: Option<String>,
+    access: Option<String>,
+}
+/// Get the URL of an upload's display page, keeping the access token if it's
+/// a private upload
+fn display_url(state: &AppState, id: &str, access: Option<&str>) -> Url {
+    // SAFETY: this shouldn't fail because `id` should be valid in a URL
+    let mut url = state.base_url.join(id).unwrap();
+    if let Some(a) = access {
+        url.query_pairs_mut().append_pair("access", a);
+    }
+    url
+}
 /// Display an upload as HTML
-#[instrument(skip(state))]
+///
+/// The page can be cached until the upload expires, unless it has a view limit
+/// or a password.
+#[instrument(skip(state, ip, jar, headers, token, access))]
 pub async fn display(
     State(state): State<&'static AppState>,
     Path(id): Path<String>,
+    Query(DisplayParams { token, access }): Query<DisplayParams>,
+    ClientIp(ip): ClientIp,
+    jar: CookieJar,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, ErrorResponse> {
     let tmpl_state = TemplateState::from(state);
-    let mut upload = open_upload(state, id).await.map_err(|e| match e {
-        ViewUploadError::NotFound => ErrorTemplate {
-            state: tmpl_state.clone(),
-            code: StatusCode::NOT_FOUND,
-            message: e.to_string(),
-        },
-        ViewUploadError::InternalServer(_) => ErrorTemplate {
-            state: tmpl_state.clone(),
-            code: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        },
-    })?;
+    let mut upload = open_upload(state, id)
+        .await
+        .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    check_link(state, &upload.metadata, access.as_deref())
+        .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+
+    let access_result = check_access(
+        state,
+        &upload.metadata,
+        &jar,
+        token.as_deref(),
+        &headers,
+        ip,
+    )
+    .await
+    .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    let unlock_token = match access_result {
+        Access::Public => None,
+        Access::Unlocked(t) => Some(t),
+        Access::Locked => {
+            event!(
+                Level::INFO,
+                "upload is password protected; asking for password"
+            );
+            return render_template(PasswordTemplate {
+                state: tmpl_state,
+                action: display_url(state, &upload.metadata.id, access.as_deref()),
+                id: upload.metadata.id,
+                incorrect: false,
+            });
+        }
+    };
     let size = upload
         .file
         .metadata()
@@ -128,7 +385,8 @@ pub async fn display(
         .len();
 
     let contents = {
-        let mimetype = upload.metadata.mimetype.clone();
+        // active content that's served as text is shown as text too
+        let mimetype = raw_mimetype(state, &upload.metadata.mimetype);
         match (mimetype.type_(), mimetype.subtype()) {
             (mime::TEXT, _) | (mime::APPLICATION, mime::JSON) => {
                 if size > MAX_DISPLAY_SIZE {
@@ -144,16 +402,26 @@ pub async fn display(
                         .unwrap_or_else(|| state.syntax_set.find_syntax_plain_text());
                     // should be alright to assume that 1,048,576 fits in usize on relevant
                     // platforms
-                    let mut contents = String::with_capacity(size as usize);
+                    let mut bytes = Vec::with_capacity(size as usize);
                     upload
                         .file
-                        .read_to_string(&mut contents)
+                        .read_to_end(&mut bytes)
                         .await
                         .map_err(|e| ErrorTemplate {
                             state: tmpl_state.clone(),
                             code: StatusCode::INTERNAL_SERVER_ERROR,
                             message: format!("error reading file contents: {e}"),
                         })?;
+                    // a BOM overrides the charset, and anything invalid in it is
+                    // replaced rather than failing to display the whole file
+                    let (contents, encoding, had_errors) = charset_of(&mimetype).decode(&bytes);
+                    if had_errors {
+                        event!(
+                            Level::WARN,
+                            charset = encoding.name(),
+                            "file contents aren't valid in its charset"
+                        );
+                    }
 
                     event!(
                         Level::DEBUG,
@@ -206,11 +474,30 @@ pub async fn display(
         }
     };
 
+    // text is sent inline, so rendering it counts as a view; everything else is
+    // embedded from the raw route, which counts the view itself
+    if contents.is_inline() {
+        consume_view(state, &mut upload)
+            .await
+            .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    }
+    let confirm_last_view = !contents.is_inline() && upload.metadata.remaining_views == Some(1);
+
     event!(Level::DEBUG, "rendering upload template");
-    let raw_url = state.raw_url.join(&upload.metadata.id).unwrap();
+    let mut raw_url = state.raw_url.join(&upload.metadata.id).unwrap();
+    if let Some(a) = access {
+        raw_url.query_pairs_mut().append_pair("access", &a);
+    }
+    if let Some(t) = unlock_token {
+        // the raw file might be embedded somewhere that the unlock cookie isn't
+        // sent, so pass the token along
+        raw_url.query_pairs_mut().append_pair("token", &t);
+    }
     let mut download_url = raw_url.clone();
-    download_url.set_query(Some("download"));
-    render_template(DisplayTemplate {
+    download_url.query_pairs_mut().append_key_only("download");
+    state.stats.record_view(&upload.metadata.id);
+    let cache_headers = cache_headers(&upload.metadata, Utc::now());
+    let mut resp = render_template(DisplayTemplate {
         raw_url,
         download_url,
         id: upload.metadata.id,
@@ -220,8 +507,15 @@ pub async fn display(
         size,
         mimetype: upload.metadata.mimetype,
         contents,
+        remaining_views: upload.metadata.remaining_views,
+        confirm_last_view,
+        title: upload.metadata.title,
+        description: upload.metadata.description,
+        tags: upload.metadata.tags,
         state: tmpl_state,
-    })
+    })?;
+    resp.headers_mut().extend(cache_headers);
+    Ok(resp)
 }
 
 fn string_is_true<'de, D>(_: D) -> Result<bool, D::Error>
@@ -234,27 +528,203 @@ where
 pub struct RawParams {
     #[serde(default, deserialize_with = "string_is_true")]
     download: bool,
+    token: Option<String>,
+    access: Option<String>,
+}
+/// Get the type that the raw file of an upload with type `mimetype` is served
+/// as, which is only different for active content (see [`active_content`]).
+fn raw_mimetype(state: &AppState, mimetype: &Mime) -> Mime {
+    if state.active_content_action == ActiveContentAction::PlainText
+        && active_content::is_active(&state.active_content_types, mimetype)
+    {
+        active_content::as_plain_text(mimetype)
+    } else {
+        mimetype.clone()
+    }
+}
+
+/// Create a `Content-Disposition` header (RFC 6266) for a file named
+/// `filename`, which is downloaded if `attachment` or opened in the browser
+/// otherwise.
+///
+/// The `filename` parameter is an ASCII version of the name, with anything
+/// that isn't safe to put in it replaced with `_`. If that's different from
+/// the real name, the real name is also sent percent-encoded as UTF-8 in the
+/// `filename*` parameter (RFC 5987), which browsers use instead.
+fn content_disposition(attachment: bool, filename: &str) -> HeaderValue {
+    let disposition = if attachment { "attachment" } else { "inline" };
+    // `%` is replaced too, since some browsers decode percent-encoding in it
+    let fallback = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if !matches!(c, '"' | '\\' | '%') => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let mut value = format!("{disposition}; filename=\"{fallback}\"");
+    if fallback != filename {
+        value.push_str("; filename*=UTF-8''");
+        for byte in filename.bytes() {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                value.push(char::from(byte));
+            } else {
+                value.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    // SAFETY: everything in the value is visible ASCII
+    HeaderValue::try_from(value).unwrap()
+}
+
+/// Whether raw files are sent by the web server in front of bobashare instead,
+/// which is told where the file is with a header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOffload {
+    /// send files normally
+    #[default]
+    #[serde(rename = "none")]
+    Disabled,
+    /// `X-Accel-Redirect` with the path of an internal location (nginx)
+    XAccelRedirect,
+    /// `X-Sendfile` with the path of the file on disk (Apache, lighttpd)
+    XSendfile,
+}
+impl FileOffload {
+    /// Get the name of the header that tells the web server which file to send,
+    /// or [`None`] if files aren't offloaded.
+    pub fn header_name(self) -> Option<HeaderName> {
+        match self {
+            Self::Disabled => None,
+            Self::XAccelRedirect => Some(HeaderName::from_static("x-accel-redirect")),
+            Self::XSendfile => Some(HeaderName::from_static("x-sendfile")),
+        }
+    }
+}
+
+/// How long uploads that never expire can be cached for (a year, which is the
+/// longest that caches are expected to keep anything)
+const MAX_CACHE_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Get the `Cache-Control` and `Expires` headers for a response that shows
+/// `upload`, so that it's never cached after it expires.
+///
+/// Uploads with a view limit or a password aren't stored at all, since every
+/// request for them has to be counted or checked, and private uploads are only
+/// cached by the browser. Uploads that never expire can be cached for
+/// [`MAX_CACHE_AGE`], and are immutable since their files never change.
+fn cache_headers(upload: &Upload, now: DateTime<Utc>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if upload.remaining_views.is_some() || upload.password_hash.is_some() {
+        headers.typed_insert(CacheControl::new().with_no_store());
+        return headers;
+    }
+
+    let cache_control = if upload.private {
+        CacheControl::new().with_private()
+    } else {
+        CacheControl::new().with_public()
+    };
+    match upload.expiry_date {
+        Some(expiry) => {
+            let max_age = (expiry - now).to_std().unwrap_or_default();
+            // round down, so it's never cached for longer
+            headers
+                .typed_insert(cache_control.with_max_age(Duration::from_secs(max_age.as_secs())));
+            headers.typed_insert(Expires::from(SystemTime::from(expiry)));
+        }
+        None => headers.typed_insert(cache_control.with_max_age(MAX_CACHE_AGE).with_immutable()),
+    }
+    headers
+}
+
+/// Get the entity tag of an upload's file, used for conditional requests.
+///
+/// Upload files never change after they're created, so this only depends on
+/// the metadata.
+fn upload_etag(upload: &Upload, size: u64) -> ETag {
+    format!("\"{:x}-{size:x}\"", upload.creation_date.timestamp_micros())
+        .parse()
+        .unwrap()
 }
+
+/// Choose which parts of an upload's file to send for a request with
+/// `headers`.
+///
+/// Uploads with a view limit are always sent whole, since every request for
+/// them uses up a view, so a `Range` can't be used to get the file in pieces
+/// without being counted.
+fn select_ranges(
+    upload: &Upload,
+    headers: &HeaderMap,
+    etag: &ETag,
+    last_modified: &LastModified,
+    size: u64,
+) -> RangeSelection {
+    if upload.remaining_views.is_some() {
+        return RangeSelection::Full;
+    }
+    // the range is ignored if the file changed since If-Range
+    match headers.typed_get::<Range>() {
+        Some(range)
+            if headers
+                .typed_get::<IfRange>()
+                .is_none_or(|r| !r.is_modified(Some(etag), Some(last_modified))) =>
+        {
+            range::resolve_ranges(&range, size)
+        }
+        _ => RangeSelection::Full,
+    }
+}
+
 /// Download the raw upload file
-#[instrument(skip(state))]
+///
+/// Supports `Range` requests (including `If-Range` and multiple ranges), and
+/// `If-None-Match`/`If-Modified-Since` conditional requests. Requests for
+/// ranges that don't start from the beginning of the file aren't counted as
+/// another download, so seeking in a video or resuming a download doesn't
+/// inflate the stats. Uploads with a view limit don't support ranges, and
+/// every request for them uses up a view.
+///
+/// Files are sandboxed and never sniffed as another type, and active content
+/// is served as text or downloaded (see [`active_content`]). They can be
+/// cached until the upload expires, unless it has a view limit or a password.
+#[instrument(skip(state, ip, jar, headers, token, access))]
 pub async fn raw(
     State(state): State<&'static AppState>,
     Path(id): Path<String>,
-    Query(RawParams { download }): Query<RawParams>,
-) -> Result<impl IntoResponse, ErrorResponse> {
+    Query(RawParams {
+        download,
+        token,
+        access,
+    }): Query<RawParams>,
+    ClientIp(ip): ClientIp,
+    jar: CookieJar,
+    headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
     let tmpl_state = TemplateState::from(state);
-    let upload = open_upload(state, id).await.map_err(|e| match e {
-        ViewUploadError::NotFound => ErrorTemplate {
-            state: tmpl_state.clone(),
-            code: StatusCode::NOT_FOUND,
-            message: e.to_string(),
-        },
-        ViewUploadError::InternalServer(_) => ErrorTemplate {
+    let mut upload = open_upload(state, id)
+        .await
+        .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    check_link(state, &upload.metadata, access.as_deref())
+        .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    let access_result = check_access(
+        state,
+        &upload.metadata,
+        &jar,
+        token.as_deref(),
+        &headers,
+        ip,
+    )
+    .await
+    .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+    if let Access::Locked = access_result {
+        Err(ErrorTemplate {
             state: tmpl_state.clone(),
-            code: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        },
-    })?;
+            code: StatusCode::FORBIDDEN,
+            message: "this upload is password protected".to_string(),
+        })?;
+    }
 
     let size = upload
         .file
@@ -268,30 +738,414 @@ pub async fn raw(
         .len();
     event!(Level::DEBUG, size, "found size of upload file",);
 
-    let body = Body::from_stream(ReaderStream::new(upload.file));
+    let etag = upload_etag(&upload.metadata, size);
+    let last_modified = LastModified::from(SystemTime::from(upload.metadata.creation_date));
+    let mut resp_headers = cache_headers(&upload.metadata, Utc::now());
+    resp_headers.typed_insert(etag.clone());
+    resp_headers.typed_insert(last_modified);
+    if upload.metadata.remaining_views.is_none() {
+        resp_headers.typed_insert(AcceptRanges::bytes());
+    }
+
+    let mimetype = raw_mimetype(state, &upload.metadata.mimetype);
+    let download = download
+        || (state.active_content_action == ActiveContentAction::Download
+            && active_content::is_active(&state.active_content_types, &mimetype));
+    resp_headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if active_content::can_sandbox(&mimetype) {
+        resp_headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(active_content::CONTENT_SECURITY_POLICY),
+        );
+    }
+
+    // If-Modified-Since is ignored when If-None-Match is sent
+    let not_modified = match headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+        None => headers
+            .typed_get::<IfModifiedSince>()
+            .is_some_and(|since| !since.is_modified(last_modified.into())),
+    };
+    if not_modified {
+        event!(Level::DEBUG, "upload file not modified");
+        return Ok((StatusCode::NOT_MODIFIED, resp_headers).into_response());
+    }
+
+    let selection = select_ranges(&upload.metadata, &headers, &etag, &last_modified, size);
+    event!(Level::DEBUG, ?selection);
+    if selection == RangeSelection::Unsatisfiable {
+        resp_headers.typed_insert(ContentRange::unsatisfied_bytes(size));
+        return Ok((StatusCode::RANGE_NOT_SATISFIABLE, resp_headers).into_response());
+    }
+
+    // never true for uploads with a view limit, so every request uses a view
+    let continuation = matches!(&selection, RangeSelection::Partial(r) if r[0].0 != 0);
+    if !continuation {
+        consume_view(state, &mut upload)
+            .await
+            .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+        state.stats.record_download(&upload.metadata.id);
+    }
+
+    let mimetype = mimetype.to_string();
+    resp_headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition(download, &upload.metadata.filename),
+    );
+
+    if let Some(offload_header) = state.file_offload.header_name() {
+        // the file is already deleted if that was its last view, so only the
+        // open handle can still read it
+        if upload.metadata.remaining_views != Some(0) {
+            let path = format!(
+                "{}{}",
+                state.file_offload_path,
+                FileBackend::upload_file_relative_path(&upload.metadata.id).display()
+            );
+            event!(
+                Level::INFO,
+                "type" = %upload.metadata.mimetype,
+                %offload_header,
+                path,
+                "offloading upload file to the web server"
+            );
+            resp_headers.insert(
+                offload_header.clone(),
+                HeaderValue::try_from(path).map_err(|e| ErrorTemplate {
+                    state: tmpl_state.clone(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: format!("error creating {offload_header} header: {e}"),
+                })?,
+            );
+            resp_headers.insert(
+                header::CONTENT_TYPE,
+                // SAFETY: MIME types are always valid header values
+                HeaderValue::try_from(mimetype).unwrap(),
+            );
+            // the web server sends the file (or the requested ranges of it)
+            // instead of this response's body
+            let length = match selection {
+                RangeSelection::Partial(ranges) => {
+                    ranges.iter().map(|(start, end)| end - start + 1).sum()
+                }
+                RangeSelection::Full | RangeSelection::Unsatisfiable => size,
+            };
+            state.stats.record_bytes_served(&upload.metadata.id, length);
+            return Ok((StatusCode::OK, resp_headers).into_response());
+        }
+    }
+
+    let stats_id = upload.metadata.id.clone();
+    let record_bytes = move |chunk: &Bytes| {
+        state
+            .stats
+            .record_bytes_served(&stats_id, chunk.len().try_into().unwrap())
+    };
+    let (status, content_type, length, body) = match selection {
+        RangeSelection::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            let mut file = upload.file;
+            file.seek(SeekFrom::Start(start))
+                .await
+                .map_err(|e| ErrorTemplate {
+                    state: tmpl_state.clone(),
+                    code: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: format!("error seeking to start of range: {e}"),
+                })?;
+            // SAFETY: resolve_ranges only returns ranges inside the file
+            resp_headers.typed_insert(ContentRange::bytes(start..=end, size).unwrap());
+            let length = end - start + 1;
+            let body = ReaderStream::new(file.take(length)).inspect_ok(record_bytes);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                mimetype,
+                length,
+                Body::from_stream(body),
+            )
+        }
+        RangeSelection::Partial(ranges) => {
+            let multipart = range::multipart_byteranges(upload.file, &ranges, size, &mimetype);
+            (
+                StatusCode::PARTIAL_CONTENT,
+                multipart.content_type,
+                multipart.content_length,
+                Body::from_stream(multipart.body.inspect_ok(record_bytes)),
+            )
+        }
+        RangeSelection::Full | RangeSelection::Unsatisfiable => {
+            let body = ReaderStream::new(upload.file).inspect_ok(record_bytes);
+            (StatusCode::OK, mimetype, size, Body::from_stream(body))
+        }
+    };
 
     event!(
         Level::INFO,
         "type" = %upload.metadata.mimetype,
-        length = size,
+        %status,
+        length,
         filename = upload.metadata.filename,
         "successfully streaming upload file to client"
     );
-    Ok((
-        StatusCode::OK,
-        [
-            (header::CONTENT_TYPE, upload.metadata.mimetype.to_string()),
-            (header::CONTENT_LENGTH, size.to_string()),
-            (
-                header::CONTENT_DISPOSITION,
-                // if params.download {
-                if download {
-                    format!("attachment; filename=\"{}\"", upload.metadata.filename)
-                } else {
-                    format!("inline; filename=\"{}\"", upload.metadata.filename)
-                },
-            ),
-        ],
-        body,
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        // SAFETY: MIME types and multipart boundaries are always valid header values
+        HeaderValue::try_from(content_type).unwrap(),
+    );
+    resp_headers.typed_insert(ContentLength(length));
+    Ok((status, resp_headers, body).into_response())
+}
+
+/// Redirect to the raw upload file, for requests to `/raw/{id}` when raw files
+/// are served from a different [`AppState::raw_url`]
+#[instrument(skip(state, query))]
+pub async fn redirect_raw(
+    State(state): State<&'static AppState>,
+    Path(id): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Redirect {
+    let mut url = state.raw_url.clone();
+    // SAFETY: raw_url is an http(s) URL, which always has path segments
+    url.path_segments_mut().unwrap().pop_if_empty().push(&id);
+    url.set_query(query.as_deref());
+    event!(Level::DEBUG, %url, "redirecting to raw url");
+    Redirect::permanent(url.as_str())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockForm {
+    password: String,
+}
+/// Check the password of a password-protected upload, and if it's correct,
+/// remember that with a cookie and redirect back to the upload
+#[instrument(skip(state, access, ip, jar, form))]
+pub async fn unlock(
+    State(state): State<&'static AppState>,
+    Path(id): Path<String>,
+    Query(DisplayParams { access, .. }): Query<DisplayParams>,
+    ClientIp(ip): ClientIp,
+    jar: CookieJar,
+    Form(form): Form<UnlockForm>,
+) -> Result<Response, ErrorResponse> {
+    let tmpl_state = TemplateState::from(state);
+    // don't open the upload here, so entering the password doesn't use up a view
+    let metadata = state
+        .backend
+        .read_upload_metadata(&id)
+        .await
+        .map_err(|e| ViewUploadError::from(e).into_error_template(tmpl_state.clone()))?;
+    if metadata.is_expired() {
+        // it will be deleted when the display page opens it
+        return Err(ViewUploadError::NotFound
+            .into_error_template(tmpl_state)
+            .into());
+    }
+    check_link(state, &metadata, access.as_deref())
+        .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+
+    let url = display_url(state, &id, access.as_deref());
+    state
+        .rate_limiters
+        .check_password_attempt(ip, &id)
+        .map_err(|_| ViewUploadError::TooManyAttempts.into_error_template(tmpl_state.clone()))?;
+    if !check_password(&metadata, &form.password).await {
+        event!(Level::INFO, "entered password was incorrect");
+        let mut resp = render_template(PasswordTemplate {
+            state: tmpl_state,
+            action: url,
+            id,
+            incorrect: true,
+        })?;
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(resp);
+    }
+
+    event!(
+        Level::INFO,
+        "entered password was correct; setting unlock cookie"
+    );
+    let expiry = Utc::now() + UNLOCK_TOKEN_LIFETIME;
+    let cookie = Cookie::build((
+        unlock_cookie_name(&id),
+        token::sign(&state.secret_key, TokenScope::Unlock, &id, expiry),
     ))
+    .path("/")
+    .http_only(true)
+    .secure(state.base_url.scheme() == "https")
+    .same_site(SameSite::Lax);
+    Ok((jar.add(cookie), Redirect::to(url.as_str())).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disposition(attachment: bool, filename: &str) -> String {
+        content_disposition(attachment, filename)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn upload(expiry_date: Option<DateTime<Utc>>) -> Upload {
+        Upload {
+            id: String::from("abc"),
+            filename: String::from("abc.txt"),
+            mimetype: mime::TEXT_PLAIN_UTF_8,
+            creation_date: DateTime::UNIX_EPOCH,
+            expiry_date,
+            delete_key_hash: String::new(),
+            remaining_views: None,
+            password_hash: None,
+            private: false,
+            title: None,
+            description: None,
+            tags: Vec::new(),
+            api_key: None,
+            uploader_ip: None,
+        }
+    }
+    fn cache_control(upload: &Upload, now: DateTime<Utc>) -> String {
+        cache_headers(upload, now)[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+    fn ranges(upload: &Upload, range: &str) -> RangeSelection {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        let last_modified = LastModified::from(SystemTime::from(upload.creation_date));
+        select_ranges(
+            upload,
+            &headers,
+            &upload_etag(upload, 100),
+            &last_modified,
+            100,
+        )
+    }
+
+    #[test]
+    fn ranges_of_unlimited_uploads() {
+        let upload = upload(None);
+        assert_eq!(
+            ranges(&upload, "bytes=1-"),
+            RangeSelection::Partial(vec![(1, 99)])
+        );
+        assert_eq!(
+            ranges(&upload, "bytes=50-,0-9"),
+            RangeSelection::Partial(vec![(0, 9), (50, 99)])
+        );
+    }
+    #[test]
+    fn view_limited_uploads_are_sent_whole() {
+        let mut limited = upload(None);
+        limited.remaining_views = Some(2);
+        // otherwise these wouldn't use up a view
+        assert_eq!(ranges(&limited, "bytes=1-"), RangeSelection::Full);
+        assert_eq!(ranges(&limited, "bytes=1-,0-0"), RangeSelection::Full);
+        assert_eq!(ranges(&limited, "bytes=0-9"), RangeSelection::Full);
+    }
+    #[test]
+    fn cached_until_expiry() {
+        let now = DateTime::UNIX_EPOCH + TimeDelta::days(1);
+        let upload = upload(Some(now + TimeDelta::milliseconds(90_500)));
+        assert_eq!(cache_control(&upload, now), "public, max-age=90");
+        assert_eq!(
+            cache_headers(&upload, now)[header::EXPIRES],
+            "Fri, 02 Jan 1970 00:01:30 GMT"
+        );
+        // in case it expires while it's being served
+        assert_eq!(
+            cache_control(&upload, now + TimeDelta::hours(1)),
+            "public, max-age=0"
+        );
+    }
+    #[test]
+    fn cached_forever_without_expiry() {
+        let headers = cache_headers(&upload(None), Utc::now());
+        assert_eq!(
+            headers[header::CACHE_CONTROL],
+            "public, immutable, max-age=31536000"
+        );
+        assert!(!headers.contains_key(header::EXPIRES));
+    }
+    #[test]
+    fn restricted_uploads_are_not_shared() {
+        let now = Utc::now();
+        let mut private = upload(None);
+        private.private = true;
+        assert_eq!(
+            cache_control(&private, now),
+            "private, immutable, max-age=31536000"
+        );
+
+        let mut limited = upload(None);
+        limited.remaining_views = Some(3);
+        assert_eq!(cache_control(&limited, now), "no-store");
+        let mut protected = upload(Some(now + TimeDelta::hours(1)));
+        protected.password_hash = Some(String::from("hash"));
+        assert_eq!(cache_control(&protected, now), "no-store");
+        assert!(!cache_headers(&protected, now).contains_key(header::EXPIRES));
+    }
+    #[test]
+    fn offload_headers() {
+        assert_eq!(FileOffload::Disabled.header_name(), None);
+        assert_eq!(
+            FileOffload::XAccelRedirect.header_name().unwrap(),
+            "x-accel-redirect"
+        );
+        assert_eq!(FileOffload::XSendfile.header_name().unwrap(), "x-sendfile");
+        assert_eq!(
+            FileBackend::upload_file_relative_path("abc"),
+            std::path::Path::new("abc/abc")
+        );
+    }
+    #[test]
+    fn ascii_filenames() {
+        assert_eq!(
+            disposition(false, "photo.jpg"),
+            "inline; filename=\"photo.jpg\""
+        );
+        assert_eq!(
+            disposition(true, "my file (1).tar.gz"),
+            "attachment; filename=\"my file (1).tar.gz\""
+        );
+    }
+    #[test]
+    fn non_ascii_filenames() {
+        assert_eq!(
+            disposition(true, "résumé.pdf"),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+        assert_eq!(
+            disposition(false, "日本.txt"),
+            "inline; filename=\"__.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt"
+        );
+    }
+    #[test]
+    fn hostile_filenames() {
+        // quotes can't end the parameter early
+        assert_eq!(
+            disposition(true, "a\"; filename=\"evil.exe"),
+            "attachment; filename=\"a_; filename=_evil.exe\"; \
+             filename*=UTF-8''a%22%3B%20filename%3D%22evil.exe"
+        );
+        // line breaks can't start another header, even in old uploads that
+        // weren't sanitized
+        assert_eq!(
+            disposition(true, "a\r\nSet-Cookie: x=y"),
+            "attachment; filename=\"a__Set-Cookie: x=y\"; \
+             filename*=UTF-8''a%0D%0ASet-Cookie%3A%20x%3Dy"
+        );
+        assert_eq!(
+            disposition(true, "100%.txt"),
+            "attachment; filename=\"100_.txt\"; filename*=UTF-8''100%25.txt"
+        );
+        assert_eq!(
+            disposition(true, "back\\slash"),
+            "attachment; filename=\"back_slash\"; filename*=UTF-8''back%5Cslash"
+        );
+    }
 }
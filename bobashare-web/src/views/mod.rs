@@ -4,19 +4,26 @@ use std::path::Path;
 
 use askama::Template;
 use axum::{
+    extract::{DefaultBodyLimit, Request, State},
+    handler::Handler,
     http,
+    middleware::Next,
     response::{Html, IntoResponse, Response},
     routing::get,
     Router,
 };
 use chrono::TimeDelta;
-use http::header::{HeaderName, HeaderValue};
+use http::{
+    header::{self, HeaderName, HeaderValue},
+    uri::Authority,
+};
 use hyper::StatusCode;
+use tower::Service;
 use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::{event, Level};
 use url::Url;
 
-use crate::AppState;
+use crate::{static_routes, AppState};
 
 pub mod about;
 pub mod display;
@@ -36,6 +43,8 @@ pub struct TemplateState<'s> {
     base_url: &'s Url,
     max_file_size: u64,
     max_expiry: Option<TimeDelta>,
+    require_api_key: bool,
+    pow_enabled: bool,
     extra_footer_text: Option<&'s str>,
     about_page: Option<&'s Path>,
 
@@ -50,6 +59,8 @@ impl<'s> From<&'s AppState> for TemplateState<'s> {
             base_url: &state.base_url,
             max_file_size: state.max_file_size,
             max_expiry: state.max_expiry,
+            require_api_key: state.require_api_key,
+            pow_enabled: state.pow_difficulty > 0,
             extra_footer_text: state.extra_footer_text.as_deref(),
             about_page: state.about_page.as_deref(),
             current_navigation: None, // will be set to Some in individual handlers
@@ -57,6 +68,13 @@ impl<'s> From<&'s AppState> for TemplateState<'s> {
     }
 }
 
+impl TemplateState<'_> {
+    /// Get the versioned URL of a static file (see [`static_routes::url`])
+    pub fn static_url(&self, path: &str) -> Url {
+        static_routes::url(self.base_url, path)
+    }
+}
+
 // which page is current navigated to, for navbar formatting
 #[derive(Debug, Clone, Copy)]
 #[non_exhaustive]
@@ -142,15 +160,106 @@ pub fn router() -> Router<&'static AppState> {
         HeaderValue::from_static("noindex"),
     );
     Router::new()
-        .route("/", get(upload::upload))
-        .route("/paste/", get(upload::paste))
-        .route("/about/", get(about::about))
         .route(
-            "/{id}",
-            get(display::display).layer(x_robots_tag_no_index.clone()),
+            "/",
+            get(upload::upload)
+                // the size of the file is limited while streaming it instead
+                .post(upload::upload_form.layer(DefaultBodyLimit::disable())),
         )
         .route(
-            "/raw/{id}",
-            get(display::raw).layer(x_robots_tag_no_index.clone()),
+            "/paste/",
+            get(upload::paste).post(upload::paste_form.layer(DefaultBodyLimit::disable())),
         )
+        .route("/about/", get(about::about))
+        .route(
+            "/{id}",
+            get(display::display)
+                .post(display::unlock)
+                .layer(x_robots_tag_no_index),
+        )
+}
+
+/// Routes for raw upload files, which are at `raw_path` (the path of
+/// [`AppState::raw_url`]) followed by the ID
+pub fn raw_router(raw_path: &str) -> Router<&'static AppState> {
+    Router::new().route(
+        &format!("{raw_path}{{id}}"),
+        get(display::raw).layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static("x-robots-tag"),
+            HeaderValue::from_static("noindex"),
+        )),
+    )
+}
+
+/// Check whether a request was sent to the host of `raw_url`.
+///
+/// The host is taken from the request URI (HTTP/2) or the `Host` header
+/// (HTTP/1.1); a missing port is the default one for the scheme of `raw_url`.
+pub fn is_raw_host(raw_url: &Url, request: &Request) -> bool {
+    let authority = request.uri().authority().cloned().or_else(|| {
+        request
+            .headers()
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()
+    });
+    authority.is_some_and(|a| {
+        // setting the port removes it if it's the default one, like in raw_url
+        let mut url = raw_url.clone();
+        a.host()
+            .eq_ignore_ascii_case(raw_url.host_str().unwrap_or_default())
+            && url.set_port(a.port_u16()).is_ok()
+            && url.port() == raw_url.port()
+    })
+}
+
+/// Middleware that sends requests for the host of [`AppState::raw_url`] to the
+/// [`raw_router`] instead, so nothing else is served there. This is only used
+/// when raw files are on a different origin than the rest of the app.
+pub async fn route_raw_host(
+    State((state, mut raw_router)): State<(&'static AppState, Router)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !is_raw_host(&state.raw_url, &request) {
+        return next.run(request).await;
+    }
+    match raw_router.call(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str) -> Request {
+        Request::builder()
+            .uri("/abc")
+            .header(header::HOST, host)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn raw_host() {
+        let raw_url = Url::parse("https://usercontent.example/").unwrap();
+        assert!(is_raw_host(&raw_url, &request("usercontent.example")));
+        assert!(is_raw_host(&raw_url, &request("UserContent.Example")));
+        assert!(is_raw_host(&raw_url, &request("usercontent.example:443")));
+        assert!(!is_raw_host(&raw_url, &request("usercontent.example:8080")));
+        assert!(!is_raw_host(&raw_url, &request("share.example")));
+        assert!(!is_raw_host(
+            &raw_url,
+            &Request::new(axum::body::Body::empty())
+        ));
+
+        let raw_url = Url::parse("http://localhost:3001/files/").unwrap();
+        assert!(is_raw_host(&raw_url, &request("localhost:3001")));
+        assert!(!is_raw_host(&raw_url, &request("localhost:3000")));
+        assert!(!is_raw_host(&raw_url, &request("localhost")));
+    }
 }
@@ -1,10 +1,33 @@
+//! Routes to upload files or paste text from a browser
+//!
+//! The forms are normally submitted by `upload.js` and `paste.js` through the
+//! API, but they can also be posted directly to [`upload_form`] and
+//! [`paste_form`] so uploading works without JavaScript.
+
+use std::net::IpAddr;
+
 use askama::Template;
-use axum::{extract::State, response::IntoResponse};
-use chrono::TimeDelta;
+use axum::{
+    body::Bytes,
+    extract::{multipart::Field, Multipart, State},
+    response::IntoResponse,
+};
+use chrono::{TimeDelta, Utc};
+use futures_util::TryStreamExt;
+use hyper::StatusCode;
 use tracing::{event, instrument, Level};
 
-use super::{filters, render_template, CurrentNavigation, ErrorResponse, TemplateState};
-use crate::AppState;
+use super::{
+    filters, render_template, CurrentNavigation, ErrorResponse, ErrorTemplate, TemplateState,
+};
+use crate::{
+    api::v1::upload::{
+        read_option_field, receive_upload, UploadError, UploadOptions, UploadResponse,
+    },
+    api_key::UploadLimits,
+    client_ip::ClientIp,
+    AppState,
+};
 
 #[derive(Debug, Clone)]
 pub struct ExpiryUnit {
@@ -121,3 +144,198 @@ pub async fn paste(
         state,
     })
 }
+
+/// Page shown after uploading with one of the forms without JavaScript
+#[derive(Template)]
+#[template(path = "uploaded.html.jinja", blocks = ["title"])]
+pub struct UploadedTemplate<'s> {
+    pub state: TemplateState<'s>,
+    pub upload: UploadResponse,
+    pub expiry_relative: Option<TimeDelta>,
+}
+
+impl UploadError {
+    fn into_error_template(self, state: TemplateState<'_>) -> ErrorTemplate<'_> {
+        ErrorTemplate {
+            state,
+            code: self.status_code(),
+            // include the cause, since it says what was wrong with the form
+            message: format!("{:#}", anyhow::Error::new(self)),
+        }
+    }
+}
+
+/// Fields of a form that set the options of the upload
+#[derive(Debug, Default)]
+struct FormOptions {
+    api_key: Option<String>,
+    expiry_number: Option<String>,
+    expiry_unit: Option<String>,
+}
+impl FormOptions {
+    /// Read `field` if it's one of the options, returning whether it was one
+    async fn read_field(&mut self, field: &mut Field<'_>, name: &str) -> Result<bool, UploadError> {
+        let option = match name {
+            "api_key" => &mut self.api_key,
+            "expiry_number" => &mut self.expiry_number,
+            "expiry_unit" => &mut self.expiry_unit,
+            _ => return Ok(false),
+        };
+        *option = Some(read_option_field(field, name).await?);
+        Ok(true)
+    }
+
+    /// Authenticate with the API key if there is one, and build the upload
+    /// options
+    async fn into_upload_options(
+        self,
+        state: &AppState,
+        ip: IpAddr,
+    ) -> Result<UploadOptions, UploadError> {
+        let api_key = self.api_key.as_deref().filter(|k| !k.is_empty());
+        let limits = UploadLimits::authenticate(state, ip, api_key, None).await?;
+        let mut options = UploadOptions::new(state, limits);
+        let expiry = match (self.expiry_number, self.expiry_unit.as_deref()) {
+            (_, Some("never")) => String::from("never"),
+            (Some(number), Some(unit)) => format!("{number}{unit}"),
+            _ => return Ok(options),
+        };
+        options
+            .set("expiry", &expiry)
+            .map_err(|source| UploadError::ParseField {
+                name: String::from("expiry"),
+                source,
+            })?;
+        Ok(options)
+    }
+}
+
+/// Render the page with the URL and delete key of a finished upload
+#[allow(clippy::result_large_err)]
+fn render_uploaded(
+    state: TemplateState<'_>,
+    upload: UploadResponse,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let expiry_relative = upload.expiry_date.map(|e| e - Utc::now());
+    let resp = render_template(UploadedTemplate {
+        state,
+        upload,
+        expiry_relative,
+    })?;
+    Ok((StatusCode::CREATED, resp))
+}
+
+/// Upload a file with the upload form, without JavaScript
+///
+/// The form must be `multipart/form-data` with the `api_key`, `expiry_number`
+/// and `expiry_unit` fields before the `file` field, since the file is streamed
+/// to disk as soon as it's reached.
+#[instrument(skip(state, ip, multipart))]
+pub async fn upload_form(
+    State(state): State<&'static AppState>,
+    ClientIp(ip): ClientIp,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let tmpl_state = TemplateState::from(state);
+    let upload = async {
+        let mut form_options = FormOptions::default();
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(UploadError::ReadMultipart)?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if form_options.read_field(&mut field, &name).await? {
+                continue;
+            }
+            match name.as_str() {
+                "file" => {
+                    // browsers send an empty file if none was selected
+                    let filename = field.file_name().unwrap_or_default().to_string();
+                    if filename.is_empty() {
+                        return Err(UploadError::MissingFile);
+                    }
+                    let mimetype = field
+                        .content_type()
+                        .and_then(|m| m.parse().ok())
+                        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+                    let options = form_options.into_upload_options(state, ip).await?;
+                    event!(Level::DEBUG, filename, %mimetype, "receiving file from form");
+                    return receive_upload(state, &filename, mimetype, options, None, field).await;
+                }
+                _ => event!(Level::DEBUG, name, "ignoring unknown field"),
+            }
+        }
+        Err(UploadError::MissingFile)
+    }
+    .await
+    .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+
+    render_uploaded(tmpl_state, upload)
+}
+
+/// Create a paste with the paste form, without JavaScript
+///
+/// The form must be `multipart/form-data` with the `filename`, `extension`,
+/// `api_key`, `expiry_number` and `expiry_unit` fields before the `content`
+/// field, since the content is streamed to disk as soon as it's reached.
+#[instrument(skip(state, ip, multipart))]
+pub async fn paste_form(
+    State(state): State<&'static AppState>,
+    ClientIp(ip): ClientIp,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let tmpl_state = TemplateState::from(state);
+    let upload = async {
+        let (mut filename, mut extension) = (String::new(), String::new());
+        let mut form_options = FormOptions::default();
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(UploadError::ReadMultipart)?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if form_options.read_field(&mut field, &name).await? {
+                continue;
+            }
+            match name.as_str() {
+                "filename" => filename = read_option_field(&mut field, &name).await?,
+                "extension" => extension = read_option_field(&mut field, &name).await?,
+                "content" => {
+                    if filename.is_empty() {
+                        return Err(UploadError::ParseField {
+                            name: String::from("filename"),
+                            source: anyhow::anyhow!("field is required"),
+                        });
+                    }
+                    if !extension.is_empty() {
+                        filename = format!("{filename}.{extension}");
+                    }
+                    let options = form_options.into_upload_options(state, ip).await?;
+                    // browsers submit textareas with CRLF line endings, and
+                    // turn every line break into one, so there are no other
+                    // carriage returns to keep
+                    let content = field.map_ok(|chunk| {
+                        Bytes::from_iter(chunk.into_iter().filter(|&b| b != b'\r'))
+                    });
+                    event!(Level::DEBUG, filename, "receiving paste from form");
+                    return receive_upload(
+                        state,
+                        &filename,
+                        mime::TEXT_PLAIN,
+                        options,
+                        None,
+                        content,
+                    )
+                    .await;
+                }
+                _ => event!(Level::DEBUG, name, "ignoring unknown field"),
+            }
+        }
+        Err(UploadError::MissingFile)
+    }
+    .await
+    .map_err(|e| e.into_error_template(tmpl_state.clone()))?;
+
+    render_uploaded(tmpl_state, upload)
+}
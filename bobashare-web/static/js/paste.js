@@ -2,12 +2,16 @@ window.onload = () => {
     const pasteScriptElem = document.getElementById("paste-script-element");
     const uploadEndpoint = pasteScriptElem.getAttribute("data-upload-api-endpoint");
     const deleteEndpoint = pasteScriptElem.getAttribute("data-delete-api-endpoint");
+    /* only set if the server requires a proof of work (see pow.js) */
+    const challengeEndpoint = pasteScriptElem.getAttribute("data-challenge-api-endpoint");
 
     const form = document.getElementById("paste-form");
 
     const textarea = document.getElementById("paste-textarea");
     const filenameInput = document.getElementById("paste-filename");
     const extensionInput = document.getElementById("paste-extension");
+    /* only shown if the server requires an API key */
+    const apiKeyInput = document.getElementById("paste-api-key");
     const expiryNumInput = document.getElementById("paste-expiry-number");
     const expiryUnitInput = document.getElementById("paste-expiry-unit");
     const submitButton = document.getElementById("paste-submit");
@@ -29,6 +33,8 @@ window.onload = () => {
         req.open("PUT", uploadEndpoint + `${filenameInput.value}.${extensionInput.value}`);
         req.setRequestHeader("Content-Type", "text/plain");
         req.setRequestHeader("Bobashare-Expiry", expiryNumInput.value + expiryUnitInput.value);
+        if (apiKeyInput)
+            req.setRequestHeader("Authorization", "Bearer " + apiKeyInput.value);
         req.responseType = "json";
         req.onreadystatechange = () => {
             if (req.readyState != XMLHttpRequest.DONE) {
@@ -45,6 +51,19 @@ window.onload = () => {
                 alert("paste failed: " + req.response.message);
             }
         }
-        req.send(textarea.value);
+
+        const body = new Blob([textarea.value]);
+        if (challengeEndpoint && !apiKeyInput) {
+            proofOfWork(challengeEndpoint, body.size).then(proof => {
+                req.setRequestHeader("Bobashare-Proof-Of-Work", proof);
+                req.send(body);
+            }, err => {
+                submitButton.disabled = false;
+                console.error("proof of work failed", err);
+                alert("paste failed: " + err.message);
+            });
+        } else {
+            req.send(body);
+        }
     }
 }
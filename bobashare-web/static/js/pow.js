@@ -0,0 +1,131 @@
+/*
+ * Solves the proof-of-work challenges that the server can require before
+ * uploading without an API key.
+ *
+ * On a page, this defines proofOfWork(), which gets a challenge and solves it
+ * in a web worker running this same script. SHA-256 is implemented here since
+ * crypto.subtle is only available over HTTPS.
+ */
+
+const SHA256_K = new Uint32Array([
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+]);
+
+const rotr = (x, n) => (x >>> n) | (x << (32 - n));
+
+/* SHA-256 hash of the bytes in `data`, as 8 big-endian 32-bit words */
+function sha256(data) {
+    const length = (data.length + 9 + 63) & ~63;
+    const buf = new Uint8Array(length);
+    buf.set(data);
+    buf[data.length] = 0x80;
+    const view = new DataView(buf.buffer);
+    view.setUint32(length - 4, data.length * 8);
+
+    const hash = new Uint32Array([
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ]);
+    const w = new Uint32Array(64);
+    for (let block = 0; block < length; block += 64) {
+        for (let i = 0; i < 16; i++)
+            w[i] = view.getUint32(block + i * 4);
+        for (let i = 16; i < 64; i++) {
+            const s0 = rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >>> 3);
+            const s1 = rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >>> 10);
+            w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+        }
+
+        let [a, b, c, d, e, f, g, h] = hash;
+        for (let i = 0; i < 64; i++) {
+            const s1 = rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25);
+            const ch = (e & f) ^ (~e & g);
+            const t1 = (h + s1 + ch + SHA256_K[i] + w[i]) | 0;
+            const s0 = rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22);
+            const maj = (a & b) ^ (a & c) ^ (b & c);
+            const t2 = (s0 + maj) | 0;
+            h = g;
+            g = f;
+            f = e;
+            e = (d + t1) | 0;
+            d = c;
+            c = b;
+            b = a;
+            a = (t1 + t2) | 0;
+        }
+        hash[0] += a;
+        hash[1] += b;
+        hash[2] += c;
+        hash[3] += d;
+        hash[4] += e;
+        hash[5] += f;
+        hash[6] += g;
+        hash[7] += h;
+    }
+    return hash;
+}
+
+function leadingZeroBits(hash) {
+    let bits = 0;
+    for (const word of hash) {
+        bits += Math.clz32(word);
+        if (word !== 0)
+            break;
+    }
+    return bits;
+}
+
+/* find a solution by counting up until the hash has enough zero bits */
+function solve(challenge, difficulty) {
+    const encoder = new TextEncoder();
+    for (let n = 0; ; n++) {
+        const solution = n.toString();
+        if (leadingZeroBits(sha256(encoder.encode(`${challenge}:${solution}`))) >= difficulty)
+            return solution;
+    }
+}
+
+if (typeof document === "undefined") {
+    /* running in the worker started by proofOfWork() */
+    onmessage = event => {
+        postMessage(solve(event.data.challenge, event.data.difficulty));
+    }
+}
+
+const powScriptSrc = typeof document === "undefined" ? null : document.currentScript.src;
+
+/*
+ * Get a challenge from `endpoint` for a file of `size` bytes and solve it,
+ * resolving to the value of the Bobashare-Proof-Of-Work header. Solving stops
+ * if `signal` (an AbortSignal) is aborted.
+ */
+function proofOfWork(endpoint, size, signal) {
+    return fetch(`${endpoint}?size=${size}`, { signal })
+        .then(resp => resp.json().then(body => {
+            if (!resp.ok)
+                throw new Error(body.message);
+            return body;
+        }))
+        .then(({ challenge, difficulty }) => new Promise((resolve, reject) => {
+            const worker = new Worker(powScriptSrc);
+            signal?.addEventListener("abort", () => {
+                worker.terminate();
+                reject(signal.reason);
+            });
+            worker.onmessage = event => {
+                worker.terminate();
+                resolve(`${challenge}:${event.data}`);
+            }
+            worker.onerror = event => {
+                worker.terminate();
+                reject(new Error(event.message));
+            }
+            worker.postMessage({ challenge, difficulty });
+        }));
+}
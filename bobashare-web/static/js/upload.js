@@ -2,6 +2,8 @@ window.onload = () => {
     const uploadScriptElem = document.getElementById("upload-script-element");
     const uploadEndpoint = uploadScriptElem.getAttribute("data-upload-api-endpoint");
     const deleteEndpoint = uploadScriptElem.getAttribute("data-delete-api-endpoint");
+    /* only set if the server requires a proof of work (see pow.js) */
+    const challengeEndpoint = uploadScriptElem.getAttribute("data-challenge-api-endpoint");
 
     const form = document.getElementById("upload-form");
     const filesDiv = document.getElementById("uploads-container");
@@ -35,6 +37,8 @@ window.onload = () => {
         fileInput.onchange();
     });
 
+    /* only shown if the server requires an API key */
+    const apiKeyInput = document.getElementById("upload-api-key");
     const expiryNumInput = document.getElementById("upload-expiry-number");
     const expiryUnitInput = document.getElementById("upload-expiry-unit");
     expiryUnitInput.onchange = () => {
@@ -63,10 +67,20 @@ window.onload = () => {
         filesDiv.appendChild(tmpl);
         const uploadElem = filesDiv.lastElementChild;
 
+        const showFailure = message => {
+            const failTmpl = fileFailTemplate.content.cloneNode(true);
+            failTmpl.querySelector(".upload-filename").innerText = file.name;
+            filesDiv.replaceChild(failTmpl, uploadElem);
+            alert(`upload of ${file.name} failed: ` + message);
+        }
+
         const req = new XMLHttpRequest();
+        const solving = new AbortController();
         req.open("PUT", uploadEndpoint + file.name);
         req.setRequestHeader("Content-Type", file.type || "application/octet-stream");
         req.setRequestHeader("Bobashare-Expiry", expiryNumInput.value + expiryUnitInput.value);
+        if (apiKeyInput)
+            req.setRequestHeader("Authorization", "Bearer " + apiKeyInput.value);
         req.responseType = "json";
         req.upload.onprogress = event => {
             // const progress = (event.loaded / event.total) * 100;
@@ -85,6 +99,12 @@ window.onload = () => {
             // other workarounds are less fun
             req.onreadystatechange = null;
             req.abort();
+            // no events are fired if the request wasn't sent yet, because the
+            // challenge is still being solved
+            if (req.readyState === XMLHttpRequest.OPENED) {
+                solving.abort();
+                req.onabort();
+            }
         }
         req.onabort = () => {
             const cancelTmpl = fileCancelledTemplate.content.cloneNode(true);
@@ -131,14 +151,25 @@ window.onload = () => {
                     }
                 }
             } else {
-                const failTmpl = fileFailTemplate.content.cloneNode(true);
-                failTmpl.querySelector(".upload-filename").innerText = file.name;
-                filesDiv.replaceChild(failTmpl, uploadElem);
                 console.error(`upload of ${file.name} failed`, req);
-                alert(`upload of ${file.name} failed: ` + (req.response.message ?? req.response));
+                showFailure(req.response.message ?? req.response);
             }
         }
-        req.send(file);
+
+        if (challengeEndpoint && !apiKeyInput) {
+            progressElem.innerText = "solving challenge";
+            proofOfWork(challengeEndpoint, file.size, solving.signal).then(proof => {
+                req.setRequestHeader("Bobashare-Proof-Of-Work", proof);
+                req.send(file);
+            }, err => {
+                if (solving.signal.aborted)
+                    return;
+                console.error(`proof of work for ${file.name} failed`, err);
+                showFailure(err.message);
+            });
+        } else {
+            req.send(file);
+        }
         fileInput.value = null;
     }
 }
@@ -0,0 +1,167 @@
+//! Strategies for generating the IDs of new uploads
+
+use rand::{rng, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::generate_randomized_id;
+
+/// Letters and digits that can't be mistaken for each other (no `0`, `O`, `1`,
+/// `l` or `I`)
+pub const UNAMBIGUOUS_ALPHABET: &[u8] =
+    b"23456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ";
+/// Consonants used in pronounceable IDs (only ones that are hard to mishear)
+pub const PRONOUNCEABLE_CONSONANTS: &[u8] = b"bdfghjkmnprstvz";
+/// Vowels used in pronounceable IDs
+pub const PRONOUNCEABLE_VOWELS: &[u8] = b"aeiou";
+
+/// How many more IDs there should be than existing uploads for a new random
+/// ID, as a power of two (2^10, so about 1 in 1000 chance of a collision)
+const COLLISION_MARGIN_BITS: f64 = 10.0;
+
+/// How the IDs of new uploads are generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdStrategy {
+    /// random ASCII letters and digits (see [`generate_randomized_id`])
+    #[default]
+    Alphanumeric,
+    /// random letters and digits from [`UNAMBIGUOUS_ALPHABET`]
+    Unambiguous,
+    /// alternating consonants and vowels, like `bafutoki`
+    Pronounceable,
+    /// derived from the SHA-256 hash of the file (see [`content_hash_id`])
+    ///
+    /// The file isn't known when the upload is created, so it starts with an
+    /// ID like [`IdStrategy::Unambiguous`], which is replaced once the file
+    /// has been received.
+    ContentHash,
+}
+impl IdStrategy {
+    /// Generate a random ID with a specified length.
+    pub fn generate(self, length: usize) -> String {
+        let mut rng = rng();
+        match self {
+            Self::Alphanumeric => generate_randomized_id(length),
+            Self::Unambiguous | Self::ContentHash => (0..length)
+                .map(|_| char::from(*UNAMBIGUOUS_ALPHABET.choose(&mut rng).unwrap()))
+                .collect(),
+            Self::Pronounceable => (0..length)
+                .map(|i| {
+                    let letters = if i % 2 == 0 {
+                        PRONOUNCEABLE_CONSONANTS
+                    } else {
+                        PRONOUNCEABLE_VOWELS
+                    };
+                    char::from(*letters.choose(&mut rng).unwrap())
+                })
+                .collect(),
+        }
+    }
+
+    /// How many bits of randomness an ID of `length` characters has.
+    pub fn bits(self, length: usize) -> f64 {
+        let alphabet_bits = |alphabet: &[u8]| (alphabet.len() as f64).log2();
+        match self {
+            Self::Alphanumeric => length as f64 * 62_f64.log2(),
+            Self::Unambiguous | Self::ContentHash => {
+                length as f64 * alphabet_bits(UNAMBIGUOUS_ALPHABET)
+            }
+            Self::Pronounceable => {
+                length.div_ceil(2) as f64 * alphabet_bits(PRONOUNCEABLE_CONSONANTS)
+                    + (length / 2) as f64 * alphabet_bits(PRONOUNCEABLE_VOWELS)
+            }
+        }
+    }
+
+    /// The length to generate IDs with when there are `uploads` existing
+    /// uploads.
+    ///
+    /// This is `min_length`, unless that would make new IDs too likely to
+    /// collide with existing ones, in which case it's the shortest length that
+    /// doesn't.
+    pub fn length_for(self, min_length: usize, uploads: usize) -> usize {
+        let needed = (uploads.max(1) as f64).log2() + COLLISION_MARGIN_BITS;
+        let mut length = min_length.max(1);
+        while self.bits(length) < needed {
+            length += 1;
+        }
+        length
+    }
+}
+
+/// Create an ID from the hash of a file, as the first `length` characters of
+/// the hash encoded with [`UNAMBIGUOUS_ALPHABET`].
+///
+/// If `length` is longer than the encoded hash, the whole hash is used.
+pub fn content_hash_id(hash: &[u8], length: usize) -> String {
+    let base = UNAMBIGUOUS_ALPHABET.len() as u32;
+    let mut digits = hash.to_vec();
+    let mut encoded = Vec::new();
+    // repeatedly divide the hash (as a big-endian number) by the base
+    while digits.iter().any(|&d| d != 0) {
+        let mut remainder = 0;
+        for digit in &mut digits {
+            let value = (remainder << 8) | u32::from(*digit);
+            *digit = (value / base) as u8;
+            remainder = value % base;
+        }
+        encoded.push(UNAMBIGUOUS_ALPHABET[remainder as usize]);
+    }
+    encoded.reverse();
+    encoded.truncate(length);
+    String::from_utf8(encoded).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_have_requested_length() {
+        for strategy in [
+            IdStrategy::Alphanumeric,
+            IdStrategy::Unambiguous,
+            IdStrategy::Pronounceable,
+            IdStrategy::ContentHash,
+        ] {
+            assert_eq!(strategy.generate(11).len(), 11);
+        }
+    }
+    #[test]
+    fn unambiguous_ids() {
+        let id = IdStrategy::Unambiguous.generate(1000);
+        assert!(!id.contains(['0', 'O', '1', 'l', 'I']));
+    }
+    #[test]
+    fn pronounceable_ids_alternate() {
+        let id = IdStrategy::Pronounceable.generate(9);
+        for (i, c) in id.bytes().enumerate() {
+            if i % 2 == 0 {
+                assert!(PRONOUNCEABLE_CONSONANTS.contains(&c));
+            } else {
+                assert!(PRONOUNCEABLE_VOWELS.contains(&c));
+            }
+        }
+    }
+    #[test]
+    fn length_grows_with_uploads() {
+        let strategy = IdStrategy::Alphanumeric;
+        assert_eq!(strategy.length_for(8, 0), 8);
+        assert_eq!(strategy.length_for(8, 1_000_000), 8);
+        assert_eq!(strategy.length_for(2, 0), 2);
+        // 62^3 is about 2^17.9, which isn't enough for 2^8 uploads
+        assert_eq!(strategy.length_for(2, 256), 4);
+        assert!(IdStrategy::Pronounceable.length_for(2, 256) > 4);
+    }
+    #[test]
+    fn content_hash_ids() {
+        assert_eq!(content_hash_id(&[0, 0], 4), "");
+        assert_eq!(content_hash_id(&[0, 57], 4), "32");
+        assert_eq!(content_hash_id(&[255; 32], 8).len(), 8);
+        assert_eq!(content_hash_id(&[255; 32], 100).len(), 44);
+        assert_eq!(
+            content_hash_id(&[1, 2, 3], 3),
+            content_hash_id(&[1, 2, 3], 4)[..3]
+        );
+    }
+}
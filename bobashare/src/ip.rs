@@ -0,0 +1,146 @@
+//! IP address ranges and hashing, used to ban uploaders and keep track of who
+//! created an upload
+
+use std::{
+    fmt,
+    net::{AddrParseError, IpAddr},
+    num::ParseIntError,
+    str::FromStr,
+};
+
+use displaydoc::Display;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Errors when parsing an [`IpCidr`]
+#[derive(Debug, Error, Display)]
+pub enum ParseIpCidrError {
+    /// error parsing IP address
+    Address(#[from] AddrParseError),
+    /// error parsing prefix length
+    PrefixLength(#[from] ParseIntError),
+    /// prefix length {0} is too long for the address
+    PrefixTooLong(u8),
+}
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `::1/128`
+///
+/// A single address without a prefix length is also accepted. IPv4-mapped IPv6
+/// addresses are treated as IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+impl IpCidr {
+    /// Check whether `ip` is in the range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for IpCidr {
+    type Err = ParseIpCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>()?, Some(len.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let addr = addr.to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(ParseIpCidrError::PrefixTooLong(prefix_len));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+impl Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for IpCidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Hash an IP address with SHA-256 and a salt, returning it as a hex string
+/// that can be stored instead of the address.
+///
+/// The salt is the same for every address, so uploads from the same address
+/// can still be found by hashing it again.
+pub fn hash_ip(ip: IpAddr, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_canonical().to_string().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(net.contains(ip("::ffff:10.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let single: IpCidr = "::1".parse().unwrap();
+        assert_eq!(single.to_string(), "::1/128");
+        assert!(single.contains(ip("::1")));
+        assert!(!single.contains(ip("::2")));
+
+        let all: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("192.0.2.1")));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_is_stored_as_string() {
+        let net: IpCidr = "192.0.2.0/24".parse().unwrap();
+        let serialized = serde_json::to_string(&net).unwrap();
+        assert_eq!(serialized, r#""192.0.2.0/24""#);
+        assert_eq!(serde_json::from_str::<IpCidr>(&serialized).unwrap(), net);
+    }
+
+    #[test]
+    fn hashes_depend_on_salt() {
+        assert_eq!(
+            hash_ip(ip("192.0.2.1"), "a"),
+            hash_ip(ip("::ffff:192.0.2.1"), "a")
+        );
+        assert_ne!(hash_ip(ip("192.0.2.1"), "a"), hash_ip(ip("192.0.2.1"), "b"));
+        assert_ne!(hash_ip(ip("192.0.2.1"), "a"), hash_ip(ip("192.0.2.2"), "a"));
+    }
+}
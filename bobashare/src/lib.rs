@@ -1,13 +1,22 @@
 //! A simple ephemeral file sharing service. This crate contains the backend
 //! API.
 
+pub mod id;
+pub mod ip;
 pub mod serde;
+pub mod sniff;
 pub mod storage;
 
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use rand::{
     distr::{Alphanumeric, SampleString},
     rng,
 };
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 
 /// Generate a randomized (alphanumeric) ID for an upload with a specified
 /// length.
@@ -15,7 +24,245 @@ pub fn generate_randomized_id(length: usize) -> String {
     Alphanumeric.sample_string(&mut rng(), length)
 }
 
+/// Maximum length of the filename of an upload in bytes, which is the most
+/// that common filesystems allow
+pub const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Clean up the filename of a new upload, so it can be shown and sent in
+/// headers safely:
+///
+/// - only the last component is kept if it's a path (with `/` or `\`)
+/// - control characters (including line breaks) and characters that change
+///   the direction of text (which can disguise the extension) are removed
+/// - whitespace is trimmed from both ends
+/// - it's cut to [`MAX_FILENAME_LENGTH`] bytes, keeping the extension if it's
+///   short
+///
+/// If nothing is left (or only dots), the filename is `upload`.
+pub fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .filter(|c| {
+            !c.is_control()
+                && !matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+        })
+        .collect::<String>();
+    let name = name.trim();
+    if name.chars().all(|c| c == '.') {
+        return String::from("upload");
+    }
+    if name.len() <= MAX_FILENAME_LENGTH {
+        return name.to_string();
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if name.len() - i <= 16 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let mut end = MAX_FILENAME_LENGTH - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{extension}", &stem[..end])
+}
+
 /// Generate alphanumeric delete key for an upload. Length is 32.
 pub fn generate_delete_key() -> String {
     Alphanumeric.sample_string(&mut rng(), 32)
 }
+
+/// Check whether a delete key could have come from [`generate_delete_key`],
+/// which means it's 32 ASCII letters and digits.
+pub fn is_generated_delete_key(key: &str) -> bool {
+    key.len() == 32 && key.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// Hash a delete key with SHA-256 and a random salt, returning it as
+/// `{salt}${hash}` (both hex-encoded) so it can be stored in upload metadata.
+///
+/// A fast hash is only enough for keys from [`generate_delete_key`], which are
+/// long and random. Keys chosen by uploaders can be short or easy to guess, so
+/// they should be hashed with [`hash_custom_delete_key`] instead.
+pub fn hash_delete_key(key: &str) -> String {
+    let mut salt = [0; 16];
+    rand::fill(&mut salt);
+    let hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update(key.as_bytes())
+        .finalize();
+    format!("{}${}", hex::encode(salt), hex::encode(hash))
+}
+
+/// Hash a delete key chosen by the uploader with Argon2, like a password (see
+/// [`hash_password`]), so it's slow to brute-force if the metadata leaks.
+pub fn hash_custom_delete_key(key: &str) -> String {
+    hash_password(key)
+}
+
+/// Check a delete key against a hash created by [`hash_delete_key`] or
+/// [`hash_custom_delete_key`].
+///
+/// The hashes are compared in constant time. Returns [`false`] if the key is
+/// wrong or if the hash can't be parsed.
+pub fn verify_delete_key(hash: &str, key: &str) -> bool {
+    // PHC strings start with `$`, and hex-encoded salts never do
+    if hash.starts_with('$') {
+        return verify_password(hash, key);
+    }
+    let Some((salt, expected)) = hash.split_once('$') else {
+        return false;
+    };
+    let (Ok(salt), Ok(expected)) = (hex::decode(salt), hex::decode(expected)) else {
+        return false;
+    };
+    let hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update(key.as_bytes())
+        .finalize();
+    hash.as_slice().ct_eq(&expected).into()
+}
+
+/// Generate a random alphanumeric API key. Length is 40.
+pub fn generate_api_key() -> String {
+    Alphanumeric.sample_string(&mut rng(), 40)
+}
+
+/// Hash an API key with SHA-256, returning it as a hex string that can be
+/// stored instead of the key.
+///
+/// Unlike passwords, keys are long and random, so a fast unsalted hash is
+/// enough, and lets a key be looked up by its hash.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Hash a password with Argon2 and a random salt, returning it as a PHC string
+/// that can be stored in upload metadata.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; 16];
+    rand::fill(&mut salt);
+    // SAFETY: 16 bytes is within the allowed salt length
+    let salt = SaltString::encode_b64(&salt).unwrap();
+    // SAFETY: this only fails on invalid parameters, and we use the defaults
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Check a password against a hash created by [`hash_password`].
+///
+/// Returns [`false`] if the password is wrong or if the hash can't be parsed.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correct_password_verifies() {
+        let hash = hash_password("hunter2");
+        assert!(verify_password(&hash, "hunter2"));
+    }
+    #[test]
+    fn incorrect_password_does_not_verify() {
+        let hash = hash_password("hunter2");
+        assert!(!verify_password(&hash, "hunter3"));
+    }
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(hash_password("hunter2"), hash_password("hunter2"));
+    }
+    #[test]
+    fn invalid_hash_does_not_verify() {
+        assert!(!verify_password("hunter2", "hunter2"));
+    }
+
+    #[test]
+    fn delete_keys_verify() {
+        let key = generate_delete_key();
+        let hash = hash_delete_key(&key);
+        assert!(verify_delete_key(&hash, &key));
+        assert!(!verify_delete_key(&hash, "wrong key"));
+        assert!(!verify_delete_key(&hash, ""));
+        assert_ne!(hash, hash_delete_key(&key));
+    }
+    #[test]
+    fn custom_delete_keys_verify() {
+        let hash = hash_custom_delete_key("short");
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_delete_key(&hash, "short"));
+        assert!(!verify_delete_key(&hash, "wrong key"));
+        assert!(!verify_delete_key(&hash, ""));
+    }
+    #[test]
+    fn generated_delete_keys_are_recognized() {
+        assert!(is_generated_delete_key(&generate_delete_key()));
+        assert!(!is_generated_delete_key("short"));
+        assert!(!is_generated_delete_key(&"-".repeat(32)));
+        assert!(!is_generated_delete_key(&"a".repeat(33)));
+    }
+    #[test]
+    fn invalid_delete_key_hash_does_not_verify() {
+        assert!(!verify_delete_key("key", "key"));
+        assert!(!verify_delete_key("nothex$nothex", "key"));
+        assert!(!verify_delete_key("$", ""));
+    }
+
+    #[test]
+    fn normal_filenames_are_kept() {
+        assert_eq!(sanitize_filename("photo.jpg"), "photo.jpg");
+        assert_eq!(sanitize_filename(".bashrc"), ".bashrc");
+        assert_eq!(
+            sanitize_filename("résumé (final).pdf"),
+            "résumé (final).pdf"
+        );
+        assert_eq!(sanitize_filename("日本語.txt"), "日本語.txt");
+    }
+    #[test]
+    fn paths_are_removed() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Windows\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_filename("dir/"), "upload");
+        assert_eq!(sanitize_filename(".."), "upload");
+        assert_eq!(sanitize_filename(""), "upload");
+        assert_eq!(sanitize_filename("  \t "), "upload");
+    }
+    #[test]
+    fn hostile_characters_are_removed() {
+        assert_eq!(
+            sanitize_filename("a.txt\r\nSet-Cookie: x=y"),
+            "a.txtSet-Cookie: x=y"
+        );
+        assert_eq!(sanitize_filename("nul\0byte\x7f.txt"), "nulbyte.txt");
+        assert_eq!(
+            sanitize_filename("invoice\u{202e}fdp.exe"),
+            "invoicefdp.exe"
+        );
+        // quotes are kept, since they're escaped when the name is sent
+        assert_eq!(
+            sanitize_filename("a\"; filename=\"evil.exe"),
+            "a\"; filename=\"evil.exe"
+        );
+    }
+    #[test]
+    fn long_filenames_are_shortened() {
+        let name = sanitize_filename(&format!("{}.txt", "a".repeat(300)));
+        assert_eq!(name.len(), MAX_FILENAME_LENGTH);
+        assert!(name.ends_with("a.txt"));
+
+        let name = sanitize_filename(&"é".repeat(200));
+        assert_eq!(name, "é".repeat(127));
+
+        let name = sanitize_filename(&format!("a.{}", "b".repeat(300)));
+        assert_eq!(name.len(), MAX_FILENAME_LENGTH);
+    }
+}
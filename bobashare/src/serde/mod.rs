@@ -5,14 +5,15 @@ use mime::Mime;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use self::v0::UploadV0;
+use self::{v0::UploadV0, v1::UploadV1};
 use super::storage::upload::Upload;
+use crate::{hash_custom_delete_key, hash_delete_key, is_generated_delete_key};
 
 #[cfg(test)]
 mod tests;
 
 /// The latest upload metadata version
-pub type LatestUploadMetadata = UploadV0;
+pub type LatestUploadMetadata = UploadV1;
 
 /// All the versions of upload metadata
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -20,7 +21,9 @@ pub type LatestUploadMetadata = UploadV0;
 #[non_exhaustive]
 pub enum UploadMetadata {
     #[serde(rename = "0")]
-    V0(UploadV0),
+    V0(Box<UploadV0>),
+    #[serde(rename = "1")]
+    V1(Box<UploadV1>),
 
     #[serde(other)]
     Unknown,
@@ -28,19 +31,27 @@ pub enum UploadMetadata {
 impl UploadMetadata {
     /// Convert an upload into the latest metadata version
     pub fn from_upload(upload: Upload) -> Self {
-        Self::V0(LatestUploadMetadata {
+        Self::V1(Box::new(LatestUploadMetadata {
             filename: upload.filename,
             mimetype: upload.mimetype.to_string(),
             creation_date: upload.creation_date,
             expiry_date: upload.expiry_date,
-            delete_key: upload.delete_key,
-        })
+            delete_key_hash: upload.delete_key_hash,
+            remaining_views: upload.remaining_views,
+            password_hash: upload.password_hash,
+            private: upload.private,
+            title: upload.title,
+            description: upload.description,
+            tags: upload.tags,
+            api_key: upload.api_key,
+            uploader_ip: upload.uploader_ip,
+        }))
     }
 }
 
-/// Errors when migrating from [`UploadV0`]
+/// Errors when migrating from [`UploadV1`]
 #[derive(Debug, Error, Display)]
-pub enum MigrateErrorV0 {
+pub enum MigrateErrorV1 {
     /// error parsing `mimetype` field
     ParseMime(#[from] mime::FromStrError),
 }
@@ -49,10 +60,10 @@ pub enum MigrateErrorV0 {
 #[derive(Debug, Error, Display)]
 #[non_exhaustive]
 pub enum MigrateError {
-    /// error migrating from V0
+    /// error migrating from V1
     // TODO: should we say this from perspective of migrating FROM 0 to X
     // or migrating TO X from 0
-    V0(#[from] MigrateErrorV0),
+    V1(#[from] MigrateErrorV1),
 
     /// unknown upload version
     UnknownVersion,
@@ -68,18 +79,54 @@ impl UploadMetadata {
         Ok(match self {
             Self::Unknown => return Err(MigrateError::UnknownVersion),
 
+            // hash the plaintext delete key, with Argon2 unless it was
+            // generated (see `hash_delete_key`); the other fields were added
+            // in V1
+            Self::V0(data) => {
+                let delete_key_hash = if is_generated_delete_key(&data.delete_key) {
+                    hash_delete_key(&data.delete_key)
+                } else {
+                    hash_custom_delete_key(&data.delete_key)
+                };
+                let (upload, _) = Self::V1(Box::new(UploadV1 {
+                    filename: data.filename,
+                    mimetype: data.mimetype,
+                    creation_date: data.creation_date,
+                    expiry_date: data.expiry_date,
+                    delete_key_hash,
+                    remaining_views: None,
+                    password_hash: None,
+                    private: false,
+                    title: None,
+                    description: None,
+                    tags: Vec::new(),
+                    api_key: None,
+                    uploader_ip: None,
+                }))
+                .into_migrated_upload(id)?;
+                (upload, true)
+            }
+
             // latest
-            Self::V0(data) => (
+            Self::V1(data) => (
                 Upload {
                     id,
                     filename: data.filename,
                     mimetype: data
                         .mimetype
                         .parse::<Mime>()
-                        .map_err(MigrateErrorV0::from)?,
+                        .map_err(MigrateErrorV1::from)?,
                     creation_date: data.creation_date,
                     expiry_date: data.expiry_date,
-                    delete_key: data.delete_key,
+                    delete_key_hash: data.delete_key_hash,
+                    remaining_views: data.remaining_views,
+                    password_hash: data.password_hash,
+                    private: data.private,
+                    title: data.title,
+                    description: data.description,
+                    tags: data.tags,
+                    api_key: data.api_key,
+                    uploader_ip: data.uploader_ip,
                 },
                 false, // already latest
             ),
@@ -88,11 +135,11 @@ impl UploadMetadata {
 }
 
 pub mod v0 {
-    //! Version 1
+    //! Version 0
     use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
 
-    /// A serializable form of [`Upload`], version 1
+    /// A serializable form of [`Upload`], version 0
     ///
     /// [`Upload`]: crate::storage::upload::Upload
     #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -109,3 +156,51 @@ pub mod v0 {
         pub delete_key: String,
     }
 }
+
+pub mod v1 {
+    //! Version 1, where the delete key is stored as a salted hash
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    /// A serializable form of [`Upload`], version 1
+    ///
+    /// [`Upload`]: crate::storage::upload::Upload
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct UploadV1 {
+        /// name of the uploaded file
+        pub filename: String,
+        /// MIME type of the uploaded file
+        pub mimetype: String,
+        /// date the upload was created
+        pub creation_date: DateTime<Utc>,
+        /// date the upload expires, or [`None`] if never
+        pub expiry_date: Option<DateTime<Utc>>,
+        /// salted hash of the secret key needed to delete the upload before
+        /// its expiry
+        pub delete_key_hash: String,
+        /// how many more views are allowed before the upload is deleted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub remaining_views: Option<u64>,
+        /// salted hash of the password needed to view the upload
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub password_hash: Option<String>,
+        /// whether the upload can only be accessed through a signed link
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub private: bool,
+        /// title shown instead of the filename
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub title: Option<String>,
+        /// longer description of the upload
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub description: Option<String>,
+        /// tags used to describe the upload
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub tags: Vec<String>,
+        /// name of the API key the upload was created with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub api_key: Option<String>,
+        /// IP address of the uploader, or a salted hash of it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub uploader_ip: Option<String>,
+    }
+}
@@ -5,6 +5,10 @@ use chrono::{DateTime, TimeDelta, Utc};
 
 use crate::storage::upload::Upload;
 
+pub(crate) const DELETE_KEY: &str = "jasdflyhdas87nmgc7gf7342gcir874g23";
+pub(crate) const DELETE_KEY_HASH: &str =
+    "8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279";
+
 pub(crate) fn upload_id() -> String {
     String::from("abc123xyz/")
 }
@@ -25,7 +29,15 @@ pub(crate) fn example_upload_no_expiry() -> Upload {
         mimetype: "text/x-python".parse().unwrap(),
         creation_date: creation_date(),
         expiry_date: None,
-        delete_key: String::from("jasdflyhdas87nmgc7gf7342gcir874g23"),
+        delete_key_hash: String::from(DELETE_KEY_HASH),
+        remaining_views: None,
+        password_hash: None,
+        private: false,
+        title: None,
+        description: None,
+        tags: Vec::new(),
+        api_key: None,
+        uploader_ip: None,
     }
 }
 pub(crate) fn example_upload_with_expiry() -> Upload {
@@ -34,9 +46,43 @@ pub(crate) fn example_upload_with_expiry() -> Upload {
     upload
 }
 
+pub(crate) fn example_upload_with_view_limit() -> Upload {
+    let mut upload = example_upload_no_expiry();
+    upload.remaining_views = Some(3);
+    upload
+}
+
+pub(crate) fn example_upload_with_password() -> Upload {
+    let mut upload = example_upload_no_expiry();
+    upload.password_hash = Some(String::from(
+        "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHRzYWx0$eXLyC3MPtZwW4cMi0X9XCXXm9UyYgeS9oaNjP8VxDXk",
+    ));
+    upload
+}
+
+pub(crate) fn example_upload_private() -> Upload {
+    let mut upload = example_upload_no_expiry();
+    upload.private = true;
+    upload
+}
+
+pub(crate) fn example_upload_with_description() -> Upload {
+    let mut upload = example_upload_no_expiry();
+    upload.title = Some("My script".to_string());
+    upload.description = Some("Prints hello world".to_string());
+    upload.tags = vec!["python".to_string(), "example".to_string()];
+    upload
+}
+
 pub(crate) const EXAMPLE_UNKNOWN_VERSION: &str = r#"{"version":"-1"}"#;
 pub(crate) const EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED: &str = r#"{"version":"0","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key":"jasdflyhdas87nmgc7gf7342gcir874g23"}"#;
 pub(crate) const EXAMPLE_UPLOADV0_WITH_EXPIRY_SERIALIZED: &str = r#"{"version":"0","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":"2022-09-02T01:02:19.824375631Z","delete_key":"jasdflyhdas87nmgc7gf7342gcir874g23"}"#;
+pub(crate) const EXAMPLE_UPLOADV1_NO_EXPIRY_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279"}"#;
+pub(crate) const EXAMPLE_UPLOADV1_WITH_EXPIRY_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":"2022-09-02T01:02:19.824375631Z","delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279"}"#;
+pub(crate) const EXAMPLE_UPLOADV1_WITH_PASSWORD_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279","password_hash":"$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHRzYWx0$eXLyC3MPtZwW4cMi0X9XCXXm9UyYgeS9oaNjP8VxDXk"}"#;
+pub(crate) const EXAMPLE_UPLOADV1_PRIVATE_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279","private":true}"#;
+pub(crate) const EXAMPLE_UPLOADV1_WITH_DESCRIPTION_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279","title":"My script","description":"Prints hello world","tags":["python","example"]}"#;
+pub(crate) const EXAMPLE_UPLOADV1_WITH_VIEW_LIMIT_SERIALIZED: &str = r#"{"version":"1","filename":"code.py","mimetype":"text/x-python","creation_date":"2022-08-29T01:02:19.824375631Z","expiry_date":null,"delete_key_hash":"8c1f5e0a7b3d2c6e9f4a1b0d3e5c7a92$fc69e48e65497f9a66c9ba535d74532d63e8b0e93f69625207a3b901f667b279","remaining_views":3}"#;
 
 mod serialize {
     use pretty_assertions::assert_eq;
@@ -50,7 +96,7 @@ mod serialize {
 
         let output = serde_json::to_string(&metadata).unwrap();
 
-        assert_eq!(output, constants::EXAMPLE_UPLOADV0_WITH_EXPIRY_SERIALIZED);
+        assert_eq!(output, constants::EXAMPLE_UPLOADV1_WITH_EXPIRY_SERIALIZED);
     }
 
     #[test]
@@ -60,7 +106,53 @@ mod serialize {
 
         let output = serde_json::to_string(&metadata).unwrap();
 
-        assert_eq!(output, constants::EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED);
+        assert_eq!(output, constants::EXAMPLE_UPLOADV1_NO_EXPIRY_SERIALIZED);
+    }
+
+    #[test]
+    fn serialize_into_latest_with_view_limit() {
+        let upload = constants::example_upload_with_view_limit();
+        let metadata = UploadMetadata::from_upload(upload);
+
+        let output = serde_json::to_string(&metadata).unwrap();
+
+        assert_eq!(
+            output,
+            constants::EXAMPLE_UPLOADV1_WITH_VIEW_LIMIT_SERIALIZED
+        );
+    }
+
+    #[test]
+    fn serialize_into_latest_with_password() {
+        let upload = constants::example_upload_with_password();
+        let metadata = UploadMetadata::from_upload(upload);
+
+        let output = serde_json::to_string(&metadata).unwrap();
+
+        assert_eq!(output, constants::EXAMPLE_UPLOADV1_WITH_PASSWORD_SERIALIZED);
+    }
+
+    #[test]
+    fn serialize_into_latest_private() {
+        let upload = constants::example_upload_private();
+        let metadata = UploadMetadata::from_upload(upload);
+
+        let output = serde_json::to_string(&metadata).unwrap();
+
+        assert_eq!(output, constants::EXAMPLE_UPLOADV1_PRIVATE_SERIALIZED);
+    }
+
+    #[test]
+    fn serialize_into_latest_with_description() {
+        let upload = constants::example_upload_with_description();
+        let metadata = UploadMetadata::from_upload(upload);
+
+        let output = serde_json::to_string(&metadata).unwrap();
+
+        assert_eq!(
+            output,
+            constants::EXAMPLE_UPLOADV1_WITH_DESCRIPTION_SERIALIZED
+        );
     }
 }
 
@@ -86,28 +178,137 @@ mod deserialize {
     mod v0 {
         use pretty_assertions::assert_eq;
 
+        use crate::{
+            serde::{tests as constants, UploadMetadata},
+            storage::upload::Upload,
+        };
+
+        /// Migrate `serialized`, checking that the delete key was hashed, and
+        /// replace the hash with the one in the example uploads.
+        fn migrate(serialized: &str) -> Upload {
+            let metadata: UploadMetadata = serde_json::from_str(serialized).unwrap();
+            let (mut upload, migrated) = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+            assert!(migrated);
+            assert!(upload.check_delete_key(constants::DELETE_KEY));
+            upload.delete_key_hash = String::from(constants::DELETE_KEY_HASH);
+            upload
+        }
+
+        #[test]
+        fn deserialize_upload_with_expiry() {
+            let output = migrate(constants::EXAMPLE_UPLOADV0_WITH_EXPIRY_SERIALIZED);
+
+            assert_eq!(output, constants::example_upload_with_expiry());
+        }
+
+        #[test]
+        fn deserialize_upload_no_expiry() {
+            let output = migrate(constants::EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED);
+
+            assert_eq!(output, constants::example_upload_no_expiry());
+        }
+
+        #[test]
+        fn delete_key_hashed_by_how_it_was_made() {
+            let metadata: UploadMetadata =
+                serde_json::from_str(constants::EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED).unwrap();
+            let (upload, _) = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+            // the example key isn't one that would be generated
+            assert!(upload.delete_key_hash.starts_with("$argon2"));
+
+            let key = crate::generate_delete_key();
+            let serialized = constants::EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED
+                .replace(constants::DELETE_KEY, &key);
+            let metadata: UploadMetadata = serde_json::from_str(&serialized).unwrap();
+            let (upload, _) = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+            assert!(!upload.delete_key_hash.starts_with('$'));
+            assert!(upload.check_delete_key(&key));
+        }
+    }
+
+    mod v1 {
+        use pretty_assertions::assert_eq;
+
         use crate::serde::{tests as constants, UploadMetadata};
 
         #[test]
         fn deserialize_upload_with_expiry() {
             let metadata: UploadMetadata =
-                serde_json::from_str(constants::EXAMPLE_UPLOADV0_WITH_EXPIRY_SERIALIZED).unwrap();
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_WITH_EXPIRY_SERIALIZED).unwrap();
             let output = metadata
                 .into_migrated_upload(constants::upload_id())
                 .unwrap();
 
+            assert!(!output.1);
             assert_eq!(output.0, constants::example_upload_with_expiry());
         }
 
         #[test]
         fn deserialize_upload_no_expiry() {
             let metadata: UploadMetadata =
-                serde_json::from_str(constants::EXAMPLE_UPLOADV0_NO_EXPIRY_SERIALIZED).unwrap();
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_NO_EXPIRY_SERIALIZED).unwrap();
             let output = metadata
                 .into_migrated_upload(constants::upload_id())
                 .unwrap();
 
+            assert!(!output.1);
             assert_eq!(output.0, constants::example_upload_no_expiry());
         }
+
+        #[test]
+        fn deserialize_upload_with_view_limit() {
+            let metadata: UploadMetadata =
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_WITH_VIEW_LIMIT_SERIALIZED)
+                    .unwrap();
+            let output = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+
+            assert!(!output.1);
+            assert_eq!(output.0, constants::example_upload_with_view_limit());
+        }
+
+        #[test]
+        fn deserialize_upload_with_password() {
+            let metadata: UploadMetadata =
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_WITH_PASSWORD_SERIALIZED).unwrap();
+            let output = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+
+            assert!(!output.1);
+            assert_eq!(output.0, constants::example_upload_with_password());
+        }
+
+        #[test]
+        fn deserialize_upload_private() {
+            let metadata: UploadMetadata =
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_PRIVATE_SERIALIZED).unwrap();
+            let output = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+
+            assert!(!output.1);
+            assert_eq!(output.0, constants::example_upload_private());
+        }
+
+        #[test]
+        fn deserialize_upload_with_description() {
+            let metadata: UploadMetadata =
+                serde_json::from_str(constants::EXAMPLE_UPLOADV1_WITH_DESCRIPTION_SERIALIZED)
+                    .unwrap();
+            let output = metadata
+                .into_migrated_upload(constants::upload_id())
+                .unwrap();
+
+            assert!(!output.1);
+            assert_eq!(output.0, constants::example_upload_with_description());
+        }
     }
 }
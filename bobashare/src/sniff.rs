@@ -0,0 +1,518 @@
+//! Detecting the MIME type of an upload from the start of its contents
+//!
+//! The type of an upload is chosen with [`detect_mimetype`], in this order:
+//!
+//! 1. If the file starts with the magic bytes of a known format (see
+//!    [`sniff`]), that format is used, since it's what the file really is.
+//!    There are two exceptions, where the claimed type (see below) is kept
+//!    instead:
+//!    - it has the same subtype in a different top-level type (like
+//!      `audio/mp4` for an MP4 file), because a container doesn't say whether
+//!      it contains video
+//!    - the file is a ZIP archive and the claimed type is a more specific
+//!      `application/*` type (like a `.docx` or `.jar` file), because many
+//!      formats are ZIP archives
+//! 2. Otherwise, if the start of the file looks like text, it's `text/plain`
+//!    with the charset found by [`detect_charset`].
+//! 3. Otherwise, the claimed type is used.
+//!
+//! The claimed type is the type the client declared, unless that's
+//! `application/octet-stream` (which clients like `curl -T` send for every
+//! file), in which case it's the type guessed from the filename extension.
+
+use encoding_rs::{DecoderResult, Encoding, GBK, SHIFT_JIS, UTF_8, WINDOWS_1252};
+use mime::Mime;
+
+/// How many bytes from the start of a file are needed to detect its type
+pub const SNIFF_LENGTH: usize = 1024;
+
+/// Check whether `bytes` starts with `magic` at `offset`.
+fn has_magic(bytes: &[u8], offset: usize, magic: &[u8]) -> bool {
+    bytes
+        .get(offset..offset + magic.len())
+        .is_some_and(|b| b == magic)
+}
+
+/// Check whether `needle` appears anywhere in `bytes`.
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Detect the type of a file in the ISO base media format (MP4 and friends)
+/// from its major brand.
+fn sniff_ftyp(brand: &[u8]) -> &'static str {
+    match brand {
+        b"avif" | b"avis" => "image/avif",
+        b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1" => "image/heic",
+        b"qt  " => "video/quicktime",
+        b"M4A " | b"M4B " => "audio/mp4",
+        b if b.starts_with(b"3g") => "video/3gpp",
+        _ => "video/mp4",
+    }
+}
+
+/// Detect the type of a ZIP archive, using the `mimetype` file that EPUB and
+/// OpenDocument files store uncompressed at the start.
+fn sniff_zip(bytes: &[u8]) -> &str {
+    let u16_at = |i: usize| {
+        bytes
+            .get(i..i + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    // the local file header has the size of the first file at 18, the lengths
+    // of its name and extra field at 26 and 28, and then its name at 30
+    let mimetype = has_magic(bytes, 30, b"mimetype")
+        .then(|| {
+            let size = usize::from(u16_at(18)?);
+            let start = 30 + usize::from(u16_at(26)?) + usize::from(u16_at(28)?);
+            bytes.get(start..start + size)
+        })
+        .flatten()
+        .and_then(|m| std::str::from_utf8(m).ok())
+        .filter(|m| m.starts_with("application/") && m.parse::<Mime>().is_ok());
+    mimetype.unwrap_or("application/zip")
+}
+
+/// Check for a BMP file header, which only has a two byte magic so the rest
+/// of the header is checked too.
+fn is_bmp(bytes: &[u8]) -> bool {
+    has_magic(bytes, 0, b"BM")
+        && bytes.get(6..10).is_some_and(|r| r == [0; 4])
+        && bytes
+            .get(14..18)
+            .map(|s| u32::from_le_bytes(s.try_into().unwrap()))
+            .is_some_and(|s| [12, 40, 52, 56, 64, 108, 124].contains(&s))
+}
+
+/// Check for a Windows executable, which starts with `MZ` and has a pointer to
+/// the `PE` signature at 0x3c.
+fn is_pe(bytes: &[u8]) -> bool {
+    has_magic(bytes, 0, b"MZ")
+        && bytes
+            .get(0x3c..0x40)
+            .map(|o| u32::from_le_bytes(o.try_into().unwrap()) as usize)
+            .is_some_and(|offset| has_magic(bytes, offset, b"PE\0\0"))
+}
+
+/// Find the MIME type of a file from the magic bytes at the start of it.
+///
+/// Returns [`None`] if it isn't one of the binary formats that are recognized
+/// (text formats aren't detected). `bytes` should be the first
+/// [`SNIFF_LENGTH`] bytes of the file, or all of it if it's shorter.
+pub fn sniff(bytes: &[u8]) -> Option<Mime> {
+    let riff = |kind: &[u8]| has_magic(bytes, 0, b"RIFF") && has_magic(bytes, 8, kind);
+    let mimetype = match bytes {
+        // images
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        _ if riff(b"WEBP") => "image/webp",
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => "image/tiff",
+        [0, 0, 1, 0, n, 0, ..] if *n > 0 => "image/x-icon",
+        [0xff, 0x0a, ..] => "image/jxl",
+        _ if has_magic(bytes, 0, b"\0\0\0\x0cJXL \r\n\x87\n") => "image/jxl",
+        [b'8', b'B', b'P', b'S', 0, 1 | 2, ..] => "image/vnd.adobe.photoshop",
+        _ if is_bmp(bytes) => "image/bmp",
+        _ if has_magic(bytes, 4, b"ftyp") => sniff_ftyp(bytes.get(8..12).unwrap_or_default()),
+
+        // video and audio
+        [0x1a, 0x45, 0xdf, 0xa3, ..] if contains(bytes, b"webm") => "video/webm",
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "video/x-matroska",
+        _ if riff(b"AVI ") => "video/x-msvideo",
+        [b'O', b'g', b'g', b'S', ..] if contains(bytes, b"theora") => "video/ogg",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        _ if riff(b"WAVE") => "audio/wav",
+        [b'f', b'L', b'a', b'C', ..] => "audio/flac",
+        [b'I', b'D', b'3', 2..=4, 0, ..] => "audio/mpeg",
+        // MPEG layer III frame sync
+        [0xff, 0xfb | 0xfa | 0xf3 | 0xf2 | 0xe3 | 0xe2, ..] => "audio/mpeg",
+        // AAC ADTS frame sync
+        [0xff, 0xf1 | 0xf9, ..] => "audio/aac",
+        [b'M', b'T', b'h', b'd', 0, 0, 0, 6, ..] => "audio/midi",
+
+        // documents
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        _ if has_magic(bytes, 0, b"SQLite format 3\0") => "application/vnd.sqlite3",
+
+        // archives
+        [b'P', b'K', 3, 4, ..] => sniff_zip(bytes),
+        [b'P', b'K', 5, 6, ..] => "application/zip",
+        [0x1f, 0x8b, 8, ..] => "application/gzip",
+        [b'B', b'Z', b'h', b'1'..=b'9', 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, ..]
+        | [b'B', b'Z', b'h', b'1'..=b'9', 0x17, 0x72, 0x45, 0x38, 0x50, 0x90, ..] => {
+            "application/x-bzip2"
+        }
+        [0xfd, b'7', b'z', b'X', b'Z', 0, ..] => "application/x-xz",
+        [b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c, ..] => "application/x-7z-compressed",
+        [b'R', b'a', b'r', b'!', 0x1a, 0x07, ..] => "application/vnd.rar",
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => "application/zstd",
+        _ if has_magic(bytes, 257, b"ustar") => "application/x-tar",
+
+        // programs and fonts
+        [0, b'a', b's', b'm', ..] => "application/wasm",
+        [0x7f, b'E', b'L', b'F', ..] => "application/x-executable",
+        _ if is_pe(bytes) => "application/vnd.microsoft.portable-executable",
+        [b'w', b'O', b'F', b'F', ..] => "font/woff",
+        [b'w', b'O', b'F', b'2', ..] => "font/woff2",
+        [0, 1, 0, 0, 0, ..] => "font/ttf",
+
+        _ => return None,
+    };
+    // SAFETY: all of the types above are valid
+    Some(mimetype.parse().unwrap())
+}
+
+/// Decide the MIME type of an upload named `filename` that starts with
+/// `bytes`, which the client said is `declared` (see the [module
+/// documentation](self) for how).
+///
+/// `bytes` should be the first [`SNIFF_LENGTH`] bytes of the file, or all of
+/// it if it's shorter.
+pub fn detect_mimetype(declared: &Mime, filename: &str, bytes: &[u8]) -> Mime {
+    let claimed = if *declared == mime::APPLICATION_OCTET_STREAM {
+        mime_guess::from_path(filename).first_or_octet_stream()
+    } else {
+        declared.clone()
+    };
+
+    if let Some(sniffed) = sniff(bytes) {
+        let same_subtype = claimed.subtype() == sniffed.subtype();
+        let more_specific_zip = sniffed.essence_str() == "application/zip"
+            && claimed.type_() == mime::APPLICATION
+            && claimed != mime::APPLICATION_OCTET_STREAM;
+        return if same_subtype || more_specific_zip {
+            claimed
+        } else {
+            sniffed
+        };
+    }
+
+    match detect_charset(bytes) {
+        Some(encoding) if encoding == UTF_8 => mime::TEXT_PLAIN_UTF_8,
+        Some(encoding) => format!("text/plain; charset={}", encoding.name().to_lowercase())
+            .parse()
+            .unwrap(),
+        None => claimed,
+    }
+}
+
+/// Check whether `bytes` is valid text in `encoding`.
+///
+/// If the end of `bytes` might have been cut off, a character that's missing
+/// any of its last bytes is ignored.
+fn decodes_as(encoding: &'static Encoding, bytes: &[u8], truncated: bool) -> bool {
+    if encoding == UTF_8 {
+        return match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            // there's no error length when the bytes end in the middle of a
+            // character, and everything before it is valid
+            Err(e) => truncated && e.error_len().is_none(),
+        };
+    }
+
+    // the decoder keeps an incomplete character at the end for the next call
+    // unless it's told this is the last one
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let Some(capacity) = decoder.max_utf8_buffer_length_without_replacement(bytes.len()) else {
+        return false;
+    };
+    let mut decoded = String::with_capacity(capacity);
+    let (result, _) = decoder.decode_to_string_without_replacement(bytes, &mut decoded, !truncated);
+    result == DecoderResult::InputEmpty
+}
+
+/// Find the character encoding of a file that starts with `bytes`, or
+/// [`None`] if it doesn't look like text.
+///
+/// - a UTF-8 or UTF-16 byte order mark decides the encoding
+/// - text that's valid UTF-8 is UTF-8 (if `bytes` is [`SNIFF_LENGTH`] long, a
+///   character cut off at the end is allowed, since the rest of the file
+///   wasn't read)
+/// - anything else with NUL bytes or control characters (other than tab, line
+///   breaks, form feed and escape) isn't text
+/// - otherwise, it's guessed to be in a legacy encoding: if the non-ASCII bytes
+///   come in pairs or make up most of the text like in CJK text, Shift_JIS
+///   (only if it has bytes that only Shift_JIS uses to start a character) or
+///   GBK, whichever it's valid in, or else windows-1252 (which browsers also
+///   use for ISO-8859-1)
+pub fn detect_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Some(encoding);
+    }
+    let truncated = bytes.len() == SNIFF_LENGTH;
+
+    let is_control = |b: &u8| b.is_ascii_control() && !b"\t\n\r\x0c\x1b\x7f".contains(b);
+    if bytes.iter().any(is_control) {
+        return None;
+    }
+    if decodes_as(UTF_8, bytes, truncated) {
+        return Some(UTF_8);
+    }
+
+    let non_ascii = bytes.iter().filter(|b| !b.is_ascii()).count();
+    let paired = bytes
+        .split(u8::is_ascii)
+        .filter(|run| run.len() >= 2)
+        .map(<[u8]>::len)
+        .sum::<usize>();
+    // the second byte of a Shift_JIS character can be ASCII, so text that's
+    // mostly non-ASCII counts too
+    if paired * 10 >= non_ascii * 9 || non_ascii * 3 >= bytes.len() {
+        // Shift_JIS also has single byte characters from 0xa1 to 0xdf, so it
+        // would accept almost anything without this
+        let candidates: &[_] = if bytes.iter().any(|b| (0x81..=0x9f).contains(b)) {
+            &[SHIFT_JIS, GBK]
+        } else {
+            &[GBK]
+        };
+        if let Some(encoding) = candidates
+            .iter()
+            .copied()
+            .find(|e| decodes_as(e, bytes, truncated))
+        {
+            return Some(encoding);
+        }
+    }
+    Some(WINDOWS_1252)
+}
+
+/// Get the character encoding of a text upload from the `charset` parameter of
+/// its MIME type, defaulting to UTF-8.
+pub fn charset_of(mimetype: &Mime) -> &'static Encoding {
+    mimetype
+        .get_param(mime::CHARSET)
+        .and_then(|c| Encoding::for_label(c.as_str().as_bytes()))
+        .unwrap_or(UTF_8)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{UTF_16BE, UTF_16LE};
+
+    use super::*;
+
+    fn sniffed(bytes: &[u8]) -> Option<String> {
+        sniff(bytes).map(|m| m.to_string())
+    }
+
+    #[test]
+    fn images() {
+        assert_eq!(
+            sniffed(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniffed(b"\xff\xd8\xff\xe0\0\x10JFIF").as_deref(),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniffed(b"GIF89a\x01\0\x01\0").as_deref(), Some("image/gif"));
+        assert_eq!(
+            sniffed(b"RIFF\x24\0\0\0WEBPVP8 ").as_deref(),
+            Some("image/webp")
+        );
+        assert_eq!(
+            sniffed(b"\0\0\0\x1cftypavif\0\0\0\0").as_deref(),
+            Some("image/avif")
+        );
+    }
+    #[test]
+    fn bmp_header_is_checked() {
+        let mut bmp = b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0".to_vec();
+        bmp.extend_from_slice(&40_u32.to_le_bytes());
+        assert_eq!(sniffed(&bmp).as_deref(), Some("image/bmp"));
+        assert_eq!(sniffed(b"BMW is a car company"), None);
+    }
+    #[test]
+    fn video_and_audio() {
+        assert_eq!(
+            sniffed(b"\0\0\0\x20ftypisom\0\0\x02\0").as_deref(),
+            Some("video/mp4")
+        );
+        assert_eq!(
+            sniffed(b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm").as_deref(),
+            Some("video/webm")
+        );
+        assert_eq!(
+            sniffed(b"OggS\0\x02\0\0\x01\x1eOpusHead").as_deref(),
+            Some("audio/ogg")
+        );
+        assert_eq!(sniffed(b"ID3\x04\0\0").as_deref(), Some("audio/mpeg"));
+        assert_eq!(sniffed(b"\xff\xfb\x90\x64").as_deref(), Some("audio/mpeg"));
+        assert_eq!(sniffed(b"fLaC\0\0\0\x22").as_deref(), Some("audio/flac"));
+    }
+    #[test]
+    fn archives_and_programs() {
+        assert_eq!(sniffed(b"%PDF-1.7\n").as_deref(), Some("application/pdf"));
+        assert_eq!(
+            sniffed(b"\x1f\x8b\x08\0").as_deref(),
+            Some("application/gzip")
+        );
+        assert_eq!(
+            sniffed(b"\0asm\x01\0\0\0").as_deref(),
+            Some("application/wasm")
+        );
+        assert_eq!(
+            sniffed(b"PK\x03\x04\x14\0\0\0\0\0").as_deref(),
+            Some("application/zip")
+        );
+
+        let mut tar = vec![0; 512];
+        tar[..8].copy_from_slice(b"file.txt");
+        tar[257..263].copy_from_slice(b"ustar\0");
+        assert_eq!(sniffed(&tar).as_deref(), Some("application/x-tar"));
+    }
+    #[test]
+    fn epub_is_detected_from_mimetype_file() {
+        let mut epub = b"PK\x03\x04".to_vec();
+        epub.resize(30, 0);
+        epub[18] = 20; // size of the file
+        epub[26] = 8; // length of the name
+        epub.extend_from_slice(b"mimetypeapplication/epub+zipPK\x03\x04");
+        assert_eq!(sniffed(&epub).as_deref(), Some("application/epub+zip"));
+    }
+    #[test]
+    fn text_is_not_sniffed() {
+        assert_eq!(sniffed(b"hello world"), None);
+        assert_eq!(sniffed(b""), None);
+        assert_eq!(sniffed(b"MZ is not enough for an executable"), None);
+        // UTF-16 byte order marks aren't MP3 frames
+        assert_eq!(sniffed(b"\xff\xfeh\0i\0"), None);
+        assert_eq!(sniffed(b"\xfe\xff\0h\0i"), None);
+    }
+
+    #[test]
+    fn sniffed_type_wins() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "image", png),
+            mime::IMAGE_PNG
+        );
+        assert_eq!(
+            detect_mimetype(&mime::TEXT_HTML, "page.html", png),
+            mime::IMAGE_PNG
+        );
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "photo.jpg", png),
+            mime::IMAGE_PNG
+        );
+    }
+    #[test]
+    fn claimed_type_refines_sniffed_type() {
+        let mp4 = b"\0\0\0\x20ftypisom\0\0\x02\0";
+        let audio_mp4 = "audio/mp4".parse().unwrap();
+        assert_eq!(detect_mimetype(&audio_mp4, "song", mp4), audio_mp4);
+        let zip = b"PK\x03\x04\x14\0\0\0\0\0";
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "app.jar", zip).essence_str(),
+            "application/java-archive"
+        );
+        assert_eq!(
+            detect_mimetype(&mime::IMAGE_PNG, "archive", zip).essence_str(),
+            "application/zip"
+        );
+    }
+    #[test]
+    fn text_and_fallbacks() {
+        assert_eq!(
+            detect_mimetype(&mime::TEXT_HTML, "page.html", b"<html></html>"),
+            mime::TEXT_PLAIN_UTF_8
+        );
+        let binary = b"\x80\x81\x82\0\x01 not a known format";
+        assert_eq!(
+            detect_mimetype(&mime::IMAGE_BMP, "file", binary),
+            mime::IMAGE_BMP
+        );
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "picture.gif", binary),
+            mime::IMAGE_GIF
+        );
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "file", binary),
+            mime::APPLICATION_OCTET_STREAM
+        );
+    }
+
+    #[test]
+    fn utf8_cut_off_at_end() {
+        let mut text = "é".repeat(SNIFF_LENGTH / 2).into_bytes();
+        text.truncate(SNIFF_LENGTH - 1);
+        text.insert(0, b'a');
+        assert_eq!(text.len(), SNIFF_LENGTH);
+        assert_eq!(detect_charset(&text), Some(UTF_8));
+        // a short file was read completely, so it really is invalid
+        text.truncate(SNIFF_LENGTH - 2);
+        assert_eq!(detect_charset(&text), Some(WINDOWS_1252));
+    }
+    #[test]
+    fn utf8_cut_off_anywhere_in_character() {
+        for c in ["é", "中", "😀"] {
+            // shift the characters so the end cuts each one after every byte
+            for offset in 0..c.len() {
+                let mut text =
+                    format!("{}{}", "a".repeat(offset), c.repeat(SNIFF_LENGTH)).into_bytes();
+                text.truncate(SNIFF_LENGTH);
+                assert_eq!(detect_charset(&text), Some(UTF_8), "{c} after {offset}");
+                // a short file that ends in the middle of a character
+                if !(SNIFF_LENGTH - offset).is_multiple_of(c.len()) {
+                    let short = &text[..SNIFF_LENGTH - c.len()];
+                    assert_ne!(detect_charset(short), Some(UTF_8), "{c} after {offset}");
+                }
+            }
+        }
+    }
+    #[test]
+    fn gbk_cut_off_at_end() {
+        let text = format!("a{}", "你好".repeat(SNIFF_LENGTH));
+        let mut gbk = GBK.encode(&text).0.into_owned();
+        gbk.truncate(SNIFF_LENGTH);
+        assert_eq!(detect_charset(&gbk), Some(GBK));
+    }
+    #[test]
+    fn utf16_with_bom() {
+        let le: Vec<u8> = "\u{feff}héllo"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let be: Vec<u8> = "\u{feff}héllo"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        assert_eq!(detect_charset(&le), Some(UTF_16LE));
+        assert_eq!(detect_charset(&be), Some(UTF_16BE));
+        assert_eq!(
+            detect_mimetype(&mime::APPLICATION_OCTET_STREAM, "a.txt", &le).to_string(),
+            "text/plain; charset=utf-16le"
+        );
+    }
+    #[test]
+    fn legacy_encodings() {
+        let (latin1, _, _) = WINDOWS_1252.encode("café crème brûlée");
+        assert_eq!(detect_charset(&latin1), Some(WINDOWS_1252));
+        let (sjis, _, _) = SHIFT_JIS.encode("こんにちは、世界。");
+        assert_eq!(detect_charset(&sjis), Some(SHIFT_JIS));
+        // some of these have ASCII second bytes
+        let (sjis, _, _) = SHIFT_JIS.encode("日本語のテキストです\n");
+        assert_eq!(detect_charset(&sjis), Some(SHIFT_JIS));
+        let (gbk, _, _) = GBK.encode("你好，世界。");
+        assert_eq!(detect_charset(&gbk), Some(GBK));
+    }
+    #[test]
+    fn control_characters_are_not_text() {
+        assert_eq!(detect_charset(b"abc\0def"), None);
+        assert_eq!(detect_charset(b"\x01\x02\x03"), None);
+        assert_eq!(detect_charset(b"tab\tand\r\nnewline"), Some(UTF_8));
+        assert_eq!(detect_charset(b""), Some(UTF_8));
+    }
+    #[test]
+    fn charset_from_mimetype() {
+        assert_eq!(charset_of(&mime::TEXT_PLAIN), UTF_8);
+        assert_eq!(
+            charset_of(&"text/plain; charset=shift_jis".parse().unwrap()),
+            SHIFT_JIS
+        );
+        assert_eq!(
+            charset_of(&"text/plain; charset=latin1".parse().unwrap()),
+            WINDOWS_1252
+        );
+    }
+}
@@ -1,6 +1,10 @@
 //! A backend where uploads are stored as files on disk
 
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use chrono::{prelude::*, TimeDelta};
 use displaydoc::Display;
@@ -12,11 +16,14 @@ use tokio::{
 };
 use tracing::{event, instrument, Instrument, Level};
 
-use super::{handle::UploadHandle, upload::Upload};
-use crate::{
-    generate_delete_key,
-    serde::{MigrateError, UploadMetadata},
+use super::{
+    api_key::ApiKey,
+    ban::Ban,
+    handle::UploadHandle,
+    stats::{StatsRecorder, UploadStats},
+    upload::Upload,
 };
+use crate::serde::{MigrateError, UploadMetadata};
 
 /// Errors when creating a new [`FileBackend`]
 #[derive(Debug, Error, Display)]
@@ -71,12 +78,104 @@ impl FileBackend {
     fn get_metadata_path<S: AsRef<str>>(&self, id: S) -> PathBuf {
         self.get_upload_path(id.as_ref()).join("metadata.json")
     }
+    /// Get the path to the `stats.json` of the upload
+    fn get_stats_path<S: AsRef<str>>(&self, id: S) -> PathBuf {
+        self.get_upload_path(id.as_ref()).join("stats.json")
+    }
+    /// Get the path to the file containing the API keys. It starts with a dot
+    /// so it can't be confused with an upload.
+    fn get_api_keys_path(&self) -> PathBuf {
+        self.path.join(".api_keys.json")
+    }
+    /// Get the path to the file containing how many bytes each API key has
+    /// used
+    fn get_api_key_usage_path(&self) -> PathBuf {
+        self.path.join(".api_key_usage.json")
+    }
+    /// Get the path to the lock file that's held while the API keys or their
+    /// usage are written
+    fn get_api_keys_lock_path(&self) -> PathBuf {
+        self.path.join(".api_keys.lock")
+    }
+    /// Get the path to the file containing the banned IP addresses. It starts
+    /// with a dot so it can't be confused with an upload.
+    fn get_bans_path(&self) -> PathBuf {
+        self.path.join(".bans.json")
+    }
+    /// Get the path to the lock file that's held while the bans are written
+    fn get_bans_lock_path(&self) -> PathBuf {
+        self.path.join(".bans.lock")
+    }
     /// Get the path to the uploaded file
     fn get_upload_file_path<S: AsRef<str>>(&self, id: S) -> PathBuf {
-        self.get_upload_path(id.as_ref()).join(id.as_ref())
+        self.path.join(Self::upload_file_relative_path(id))
+    }
+
+    /// Get the path to the file of an upload, relative to the directory of the
+    /// backend, so another web server can serve it.
+    pub fn upload_file_relative_path<S: AsRef<str>>(id: S) -> PathBuf {
+        Path::new(id.as_ref()).join(id.as_ref())
     }
 }
 
+/// How many times to try taking a lock before giving up, when it's only held
+/// for a moment (like while another view is being counted)
+const LOCK_ATTEMPTS: u32 = 40;
+/// How long to wait between attempts to take a lock
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(25);
+/// How old the API keys or bans lock has to be to assume it was left behind by
+/// a process that crashed while holding it
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30);
+
+/// Create the lock file at `path`, waiting up to about a second if it already
+/// exists.
+///
+/// Fails with [`io::ErrorKind::AlreadyExists`] if it still exists after that.
+async fn wait_for_lock(path: &Path) -> io::Result<()> {
+    let mut attempts = 1;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < LOCK_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::sleep(LOCK_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Create the lock file at `path` like [`wait_for_lock`], for locks that are
+/// shared by the whole repository instead of one upload.
+///
+/// A lock that's older than [`STALE_LOCK_AGE`] is removed, since nothing holds
+/// it for that long. Fails with [`io::ErrorKind::AlreadyExists`] if it's still
+/// held after that.
+async fn wait_for_shared_lock(path: &Path) -> io::Result<()> {
+    match wait_for_lock(path).await {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        res => return res,
+    }
+    let stale = fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .is_ok_and(|m| m.elapsed().is_ok_and(|age| age > STALE_LOCK_AGE));
+    if !stale {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    event!(Level::WARN, path = %path.display(), "removing stale lock");
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    wait_for_lock(path).await
+}
+
 /// Errors when creating an upload in a file backend
 #[derive(Debug, Error, Display)]
 pub enum CreateUploadError {
@@ -92,13 +191,18 @@ pub enum CreateUploadError {
     CreateUploadFile(#[source] io::Error),
 }
 impl FileBackend {
+    /// Create a new locked upload, which can be deleted with the key hashed as
+    /// `delete_key_hash`.
+    ///
+    /// Hash generated keys with [`crate::hash_delete_key`], and keys chosen by
+    /// the uploader with [`crate::hash_custom_delete_key`].
     pub async fn create_upload<S: AsRef<str>>(
         &self,
         id: S,
         filename: S,
         mimetype: Mime,
         expiry: Option<TimeDelta>,
-        delete_key: Option<String>,
+        delete_key_hash: String,
     ) -> Result<UploadHandle, CreateUploadError> {
         let creation_date = Utc::now();
         let expiry_date = expiry.map(|e| creation_date + e);
@@ -142,7 +246,15 @@ impl FileBackend {
                 mimetype,
                 creation_date,
                 expiry_date,
-                delete_key: delete_key.unwrap_or_else(generate_delete_key),
+                delete_key_hash,
+                remaining_views: None,
+                password_hash: None,
+                private: false,
+                title: None,
+                description: None,
+                tags: Vec::new(),
+                api_key: None,
+                uploader_ip: None,
             },
             file,
             file_path,
@@ -152,6 +264,91 @@ impl FileBackend {
     }
 }
 
+/// Errors when changing the ID of an upload
+#[derive(Debug, Error, Display)]
+pub enum RenameUploadError {
+    /// an upload with the requested ID already exists
+    AlreadyExists,
+    /// error creating directory for the new ID
+    CreateDirectory(#[source] io::Error),
+    /// error creating lock file
+    CreateLockFile(#[source] io::Error),
+    /// error moving upload files to the new directory
+    MoveFile(#[source] io::Error),
+    /// error removing the old upload directory
+    RemoveOld(#[source] io::Error),
+}
+impl FileBackend {
+    /// Change the ID of a locked upload.
+    ///
+    /// A new upload directory is created and locked, the files are moved into
+    /// it, and then the old directory is deleted. Fails with
+    /// [`RenameUploadError::AlreadyExists`] if `id` is already used, in which
+    /// case the upload is left as it was.
+    #[instrument(skip(self, upload), fields(old_id = upload.metadata.id))]
+    pub async fn rename_upload(
+        &self,
+        upload: &mut UploadHandle,
+        id: &str,
+    ) -> Result<(), RenameUploadError> {
+        let old_id = upload.metadata.id.clone();
+        let path = self.get_upload_path(id);
+        fs::create_dir(&path).await.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => RenameUploadError::AlreadyExists,
+            _ => RenameUploadError::CreateDirectory(e),
+        })?;
+
+        let lock_path = self.get_lock_path(id);
+        if let Err(e) = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .await
+        {
+            // nothing has been moved yet, so give the ID back
+            if let Err(err) = fs::remove_dir(&path).await {
+                event!(Level::ERROR, "error removing new upload directory: {err}");
+            }
+            return Err(RenameUploadError::CreateLockFile(e));
+        }
+
+        let file_path = self.get_upload_file_path(id);
+        if let Err(e) = fs::rename(&upload.file_path, &file_path).await {
+            if let Err(err) = fs::remove_dir_all(&path).await {
+                event!(Level::ERROR, "error removing new upload directory: {err}");
+            }
+            return Err(RenameUploadError::MoveFile(e));
+        }
+        if let Err(e) =
+            fs::rename(self.get_metadata_path(&old_id), self.get_metadata_path(id)).await
+        {
+            // move the file back so the upload is left as it was
+            if let Err(err) = fs::rename(&file_path, &upload.file_path).await {
+                event!(Level::ERROR, "error moving upload file back: {err}");
+            } else if let Err(err) = fs::remove_dir_all(&path).await {
+                event!(Level::ERROR, "error removing new upload directory: {err}");
+            }
+            return Err(RenameUploadError::MoveFile(e));
+        }
+
+        // the upload is only in the new directory now, so it should be locked
+        // with the new lock even if the old one can't be removed
+        let old_lock_path = std::mem::replace(&mut upload.lock_path, lock_path);
+        upload.path = path;
+        upload.file_path = file_path;
+        upload.metadata.id = id.to_string();
+
+        fs::remove_file(&old_lock_path)
+            .await
+            .map_err(RenameUploadError::RemoveOld)?;
+        fs::remove_dir(self.get_upload_path(&old_id))
+            .await
+            .map_err(RenameUploadError::RemoveOld)?;
+        event!(Level::DEBUG, id, "renamed upload");
+        Ok(())
+    }
+}
+
 /// Errors when opening an upload stored in a file backend
 #[derive(Debug, Error, Display)]
 pub enum OpenUploadError {
@@ -180,7 +377,26 @@ impl FileBackend {
             return Err(OpenUploadError::Locked);
         }
 
-        let metadata_path = self.get_metadata_path(id.as_ref());
+        let (upload, migrated) = self.read_metadata_file(id.as_ref()).await?;
+        if migrated {
+            self.save_migrated_metadata(id.as_ref()).await;
+        }
+        Ok(upload)
+    }
+
+    /// Read the metadata of an upload without checking for a lock file. Only
+    /// use this if you are the one holding the lock.
+    async fn read_upload_metadata_unlocked<S: AsRef<str>>(
+        &self,
+        id: S,
+    ) -> Result<Upload, OpenUploadError> {
+        Ok(self.read_metadata_file(id.as_ref()).await?.0)
+    }
+
+    /// Read and migrate the metadata of an upload, returning whether it was
+    /// migrated from an older version.
+    async fn read_metadata_file(&self, id: &str) -> Result<(Upload, bool), OpenUploadError> {
+        let metadata_path = self.get_metadata_path(id);
         let mut metadata_file = OpenOptions::new()
             .read(true)
             .write(false)
@@ -194,9 +410,44 @@ impl FileBackend {
             .await
             .map_err(OpenUploadError::ReadMetadata)?;
         let metadata: UploadMetadata = serde_json::from_str(&metadata)?;
-        let metadata = metadata.into_migrated_upload(id.as_ref().to_string())?;
-        // TODO: if metadata.1 (was migrated), save new migrated metadata
-        Ok(metadata.0)
+        Ok(metadata.into_migrated_upload(id.to_string())?)
+    }
+
+    /// Write the metadata of an upload that was migrated from an older version
+    /// back to disk, so it's stored in the latest version.
+    ///
+    /// The upload is locked while the metadata is read again and written. If
+    /// it's already locked, it's left alone, and will be saved next time.
+    #[instrument(skip(self))]
+    async fn save_migrated_metadata(&self, id: &str) {
+        let lock_path = self.get_lock_path(id);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return,
+            Err(e) => {
+                event!(Level::WARN, "error creating lock file: {e}");
+                return;
+            }
+        }
+
+        // it could have changed since it was read without the lock
+        match self.read_metadata_file(id).await {
+            Ok((upload, true)) => match self.write_upload_metadata(&upload).await {
+                Ok(()) => event!(Level::INFO, "saved migrated metadata"),
+                Err(e) => event!(Level::WARN, "error saving migrated metadata: {e}"),
+            },
+            Ok((_, false)) => {}
+            Err(e) => event!(Level::WARN, "error reading metadata: {e}"),
+        }
+
+        if let Err(e) = fs::remove_file(&lock_path).await {
+            event!(Level::ERROR, "error removing lock file: {e}");
+        }
     }
 
     /// does not check if the upload is expired, do that yourself
@@ -233,6 +484,9 @@ impl FileBackend {
             .map_err(OpenUploadError::ReadMetadata)?;
         let metadata: UploadMetadata = serde_json::from_str(&metadata)?;
         let metadata = metadata.into_migrated_upload(id.as_ref().to_string())?;
+        if metadata.1 {
+            self.save_migrated_metadata(id.as_ref()).await;
+        }
 
         Ok(UploadHandle {
             path,
@@ -245,6 +499,193 @@ impl FileBackend {
     }
 }
 
+/// Errors when rewriting the metadata of an existing upload
+#[derive(Debug, Error, Display)]
+pub enum WriteMetadataError {
+    /// error while serializing with serde_json
+    Serialize(#[from] serde_json::Error),
+    /// error writing new metadata file
+    WriteMetadata(#[source] io::Error),
+    /// error replacing old metadata file
+    ReplaceMetadata(#[source] io::Error),
+}
+impl FileBackend {
+    /// Get the path to the temporary file used while replacing `metadata.json`
+    fn get_new_metadata_path<S: AsRef<str>>(&self, id: S) -> PathBuf {
+        self.get_upload_path(id.as_ref()).join("metadata.json.new")
+    }
+
+    /// Replace the stored metadata of an upload with `upload`.
+    ///
+    /// The new metadata is written to a separate file which is then renamed
+    /// over the old one, so readers never see a partially written file. The
+    /// caller should be holding the upload's lock.
+    async fn write_upload_metadata(&self, upload: &Upload) -> Result<(), WriteMetadataError> {
+        let serialized = serde_json::to_string(&UploadMetadata::from_upload(upload.clone()))?;
+        let new_path = self.get_new_metadata_path(&upload.id);
+        fs::write(&new_path, serialized)
+            .await
+            .map_err(WriteMetadataError::WriteMetadata)?;
+        fs::rename(&new_path, self.get_metadata_path(&upload.id))
+            .await
+            .map_err(WriteMetadataError::ReplaceMetadata)?;
+        Ok(())
+    }
+}
+
+/// Errors when consuming a view of an upload
+#[derive(Debug, Error, Display)]
+pub enum ConsumeViewError {
+    /// the upload was not found
+    NotFound(#[source] io::Error),
+    /// the upload is locked
+    Locked,
+    /// the upload has no views remaining
+    NoViewsRemaining,
+
+    /// error creating lock file
+    CreateLockFile(#[source] io::Error),
+    /// error reading upload metadata
+    ReadMetadata(#[source] OpenUploadError),
+    /// error writing upload metadata
+    WriteMetadata(#[from] WriteMetadataError),
+    /// error removing lock file
+    RemoveLock(#[source] io::Error),
+}
+impl FileBackend {
+    /// Atomically decrement the number of remaining views of an upload.
+    ///
+    /// The upload is locked while its metadata is updated, so concurrent
+    /// viewers can never both receive the last view. Returns the number of
+    /// views remaining after this one, or [`None`] if the upload has no view
+    /// limit. If it returns `Some(0)`, the caller just received the last view
+    /// and should delete the upload once it's done serving it.
+    ///
+    /// If the upload is already locked, this waits up to about a second for
+    /// the lock to be released before failing with
+    /// [`ConsumeViewError::Locked`].
+    pub async fn consume_view<S: AsRef<str>>(
+        &self,
+        id: S,
+    ) -> Result<Option<u64>, ConsumeViewError> {
+        let id = id.as_ref();
+        let lock_path = self.get_lock_path(id);
+        wait_for_lock(&lock_path)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => ConsumeViewError::Locked,
+                io::ErrorKind::NotFound => ConsumeViewError::NotFound(e),
+                _ => ConsumeViewError::CreateLockFile(e),
+            })?;
+
+        let res = async {
+            let mut upload = self
+                .read_upload_metadata_unlocked(id)
+                .await
+                .map_err(|e| match e {
+                    OpenUploadError::NotFound(e) => ConsumeViewError::NotFound(e),
+                    e => ConsumeViewError::ReadMetadata(e),
+                })?;
+            let Some(remaining) = upload.remaining_views else {
+                return Ok(None);
+            };
+            if remaining == 0 {
+                return Err(ConsumeViewError::NoViewsRemaining);
+            }
+
+            upload.remaining_views = Some(remaining - 1);
+            self.write_upload_metadata(&upload).await?;
+            event!(Level::DEBUG, id, remaining = remaining - 1, "consumed view");
+            Ok(upload.remaining_views)
+        }
+        .await;
+
+        fs::remove_file(&lock_path)
+            .await
+            .map_err(ConsumeViewError::RemoveLock)?;
+        res
+    }
+}
+
+/// Errors when reading or writing the access stats of an upload
+#[derive(Debug, Error, Display)]
+pub enum StatsError {
+    /// error reading stats file
+    ReadStats(#[source] io::Error),
+    /// error deserializing stats file
+    DeserializeStats(#[source] serde_json::Error),
+    /// error serializing stats
+    SerializeStats(#[source] serde_json::Error),
+    /// error writing new stats file
+    WriteStats(#[source] io::Error),
+    /// error replacing old stats file
+    ReplaceStats(#[source] io::Error),
+}
+impl FileBackend {
+    /// Read the access stats of an upload that have been written to disk.
+    ///
+    /// Uploads that have never been accessed don't have a stats file, so the
+    /// default (empty) stats are returned in that case. Stats that are still
+    /// pending in a [`StatsRecorder`] are not included.
+    pub async fn read_upload_stats<S: AsRef<str>>(&self, id: S) -> Result<UploadStats, StatsError> {
+        match fs::read(self.get_stats_path(id)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(StatsError::DeserializeStats),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UploadStats::default()),
+            Err(e) => Err(StatsError::ReadStats(e)),
+        }
+    }
+
+    /// Add `pending` to the stats stored on disk for an upload.
+    ///
+    /// This doesn't lock the upload, so it can be deleted while the stats are
+    /// being written, which [`FileBackend::delete_upload`] handles by deleting
+    /// the whole directory.
+    async fn add_upload_stats(&self, id: &str, pending: &UploadStats) -> Result<(), StatsError> {
+        let mut stats = self.read_upload_stats(id).await?;
+        stats.merge(pending);
+
+        let serialized = serde_json::to_string(&stats).map_err(StatsError::SerializeStats)?;
+        let stats_path = self.get_stats_path(id);
+        let new_path = stats_path.with_extension("json.new");
+        fs::write(&new_path, serialized)
+            .await
+            .map_err(StatsError::WriteStats)?;
+        fs::rename(&new_path, &stats_path)
+            .await
+            .map_err(StatsError::ReplaceStats)?;
+        Ok(())
+    }
+
+    /// Write all the stats collected by `recorder` to disk.
+    ///
+    /// Errors for individual uploads are logged and don't stop the others from
+    /// being written. Stats for uploads that were deleted in the meantime are
+    /// dropped.
+    #[instrument(skip(self, recorder))]
+    pub async fn flush_stats(&self, recorder: &StatsRecorder) {
+        let pending = recorder.take();
+        if pending.is_empty() {
+            return;
+        }
+        event!(Level::DEBUG, count = pending.len(), "writing upload stats");
+        for (id, stats) in pending {
+            if !self.get_upload_path(&id).is_dir() {
+                event!(Level::DEBUG, id, "upload no longer exists, dropping stats");
+                continue;
+            }
+            match self.add_upload_stats(&id, &stats).await {
+                Ok(()) => {}
+                Err(StatsError::WriteStats(e) | StatsError::ReplaceStats(e))
+                    if e.kind() == io::ErrorKind::NotFound =>
+                {
+                    event!(Level::DEBUG, id, "upload was deleted, dropping stats");
+                }
+                Err(err) => event!(Level::ERROR, id, "error writing upload stats: {err}"),
+            }
+        }
+    }
+}
+
 /// Errors when deleting an upload stored in a file backend
 #[derive(Debug, Error, Display)]
 pub enum DeleteUploadError {
@@ -259,11 +700,18 @@ pub enum DeleteUploadError {
     DeleteDirectory(#[source] io::Error),
 }
 impl FileBackend {
+    /// Delete an upload.
+    ///
+    /// The upload file and metadata are deleted first, and then the directory
+    /// with everything left in it, like stats that were written while it was
+    /// being deleted. If the upload was created with an API key, its size is
+    /// subtracted from the key's usage.
     pub async fn delete_upload<S: AsRef<str>>(&self, id: S) -> Result<(), DeleteUploadError> {
         let path = self.get_upload_path(id.as_ref());
         if !path.is_dir() {
             return Err(DeleteUploadError::NotFound);
         }
+        let usage = self.upload_usage(id.as_ref()).await;
 
         let metadata_path = self.get_metadata_path(id.as_ref());
         let file_path = self.get_upload_file_path(id.as_ref());
@@ -273,12 +721,47 @@ impl FileBackend {
         fs::remove_file(metadata_path)
             .await
             .map_err(DeleteUploadError::DeleteMetadata)?;
-        fs::remove_dir(path)
-            .await
-            .map_err(DeleteUploadError::DeleteDirectory)?;
+        if let Err(e) = fs::remove_dir_all(&path).await {
+            // stats could have been written after the directory was listed
+            if e.kind() != io::ErrorKind::DirectoryNotEmpty {
+                return Err(DeleteUploadError::DeleteDirectory(e));
+            }
+            fs::remove_dir_all(&path)
+                .await
+                .map_err(DeleteUploadError::DeleteDirectory)?;
+        }
+        if let Some((name, size)) = usage {
+            self.release_usage_of_deleted(&name, size).await;
+        }
 
         Ok(())
     }
+
+    /// Get the name of the API key an upload was created with and the size of
+    /// its file, if it has one.
+    async fn upload_usage(&self, id: &str) -> Option<(String, u64)> {
+        let (metadata, _) = self.read_metadata_file(id).await.ok()?;
+        let name = metadata.api_key?;
+        let size = fs::metadata(self.get_upload_file_path(id))
+            .await
+            .ok()?
+            .len();
+        Some((name, size))
+    }
+
+    /// Subtract the size of a deleted upload from the usage of the API key it
+    /// was created with. Errors are only logged, since the upload is already
+    /// gone.
+    async fn release_usage_of_deleted(&self, name: &str, size: u64) {
+        if let Err(err) = self.release_api_key_usage(name, size).await {
+            event!(
+                Level::ERROR,
+                name,
+                size,
+                "error updating usage of API key: {err}"
+            );
+        }
+    }
 }
 
 /// Critical errors when validating an upload that mean we can't determine
@@ -373,6 +856,369 @@ impl FileBackend {
     }
 }
 
+/// Errors when reading or writing the API keys
+#[derive(Debug, Error, Display)]
+pub enum ApiKeysError {
+    /// error reading API keys file
+    ReadKeys(#[source] io::Error),
+    /// error deserializing API keys file
+    DeserializeKeys(#[source] serde_json::Error),
+    /// error serializing API keys
+    SerializeKeys(#[source] serde_json::Error),
+    /// error writing new API keys file
+    WriteKeys(#[source] io::Error),
+    /// error replacing old API keys file
+    ReplaceKeys(#[source] io::Error),
+    /// the API keys are being written by something else
+    Locked,
+    /// error creating lock file
+    CreateLockFile(#[source] io::Error),
+    /// error removing lock file
+    RemoveLock(#[source] io::Error),
+}
+/// Errors when reading or updating how many bytes the API keys have used
+#[derive(Debug, Error, Display)]
+pub enum ApiKeyUsageError {
+    /// the API key's quota of {quota} bytes is used up
+    QuotaExceeded { quota: u64 },
+
+    /// the API key usage is being written by something else
+    Locked,
+    /// error creating lock file
+    CreateLockFile(#[source] io::Error),
+    /// error removing lock file
+    RemoveLock(#[source] io::Error),
+    /// error reading API key usage file
+    ReadUsage(#[source] io::Error),
+    /// error deserializing API key usage file
+    DeserializeUsage(#[source] serde_json::Error),
+    /// error serializing API key usage
+    SerializeUsage(#[source] serde_json::Error),
+    /// error writing new API key usage file
+    WriteUsage(#[source] io::Error),
+    /// error replacing old API key usage file
+    ReplaceUsage(#[source] io::Error),
+    /// error reading directory
+    ReadDir(#[source] io::Error),
+    /// error reading next directory entry
+    NextEntry(#[source] io::Error),
+}
+impl FileBackend {
+    /// Read the API keys stored in the repository.
+    ///
+    /// Returns an empty list if no keys have been created yet.
+    pub async fn read_api_keys(&self) -> Result<Vec<ApiKey>, ApiKeysError> {
+        match fs::read(self.get_api_keys_path()).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(ApiKeysError::DeserializeKeys),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(ApiKeysError::ReadKeys(e)),
+        }
+    }
+
+    /// Replace the API keys stored in the repository with `keys`.
+    ///
+    /// The API keys lock is held while they're written, so two processes can't
+    /// overwrite each other's changes.
+    pub async fn write_api_keys(&self, keys: &[ApiKey]) -> Result<(), ApiKeysError> {
+        self.lock_api_keys().await.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => ApiKeysError::Locked,
+            _ => ApiKeysError::CreateLockFile(e),
+        })?;
+        let res = async {
+            let serialized =
+                serde_json::to_string_pretty(keys).map_err(ApiKeysError::SerializeKeys)?;
+            let keys_path = self.get_api_keys_path();
+            let new_path = keys_path.with_extension("json.new");
+            fs::write(&new_path, serialized)
+                .await
+                .map_err(ApiKeysError::WriteKeys)?;
+            fs::rename(&new_path, &keys_path)
+                .await
+                .map_err(ApiKeysError::ReplaceKeys)
+        }
+        .await;
+        fs::remove_file(self.get_api_keys_lock_path())
+            .await
+            .map_err(ApiKeysError::RemoveLock)?;
+        res
+    }
+
+    /// Take the lock that's held while the API keys or their usage are
+    /// written, waiting up to about a second if something else has it (see
+    /// [`wait_for_shared_lock`]).
+    async fn lock_api_keys(&self) -> io::Result<()> {
+        wait_for_shared_lock(&self.get_api_keys_lock_path()).await
+    }
+
+    /// Read how many bytes each API key has used. Keys that haven't been used
+    /// yet aren't included.
+    async fn read_api_key_usage(&self) -> Result<HashMap<String, u64>, ApiKeyUsageError> {
+        match fs::read(self.get_api_key_usage_path()).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(ApiKeyUsageError::DeserializeUsage),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(ApiKeyUsageError::ReadUsage(e)),
+        }
+    }
+
+    /// Change the usage of the API keys with `update`, holding the API keys
+    /// lock so concurrent updates can't be lost.
+    ///
+    /// The usage is only written if `update` succeeds.
+    async fn update_api_key_usage<T>(
+        &self,
+        update: impl FnOnce(&mut HashMap<String, u64>) -> Result<T, ApiKeyUsageError>,
+    ) -> Result<T, ApiKeyUsageError> {
+        self.lock_api_keys().await.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => ApiKeyUsageError::Locked,
+            _ => ApiKeyUsageError::CreateLockFile(e),
+        })?;
+        let res = async {
+            let mut usage = self.read_api_key_usage().await?;
+            let value = update(&mut usage)?;
+            let serialized =
+                serde_json::to_string_pretty(&usage).map_err(ApiKeyUsageError::SerializeUsage)?;
+            let usage_path = self.get_api_key_usage_path();
+            let new_path = usage_path.with_extension("json.new");
+            fs::write(&new_path, serialized)
+                .await
+                .map_err(ApiKeyUsageError::WriteUsage)?;
+            fs::rename(&new_path, &usage_path)
+                .await
+                .map_err(ApiKeyUsageError::ReplaceUsage)?;
+            Ok(value)
+        }
+        .await;
+        fs::remove_file(self.get_api_keys_lock_path())
+            .await
+            .map_err(ApiKeyUsageError::RemoveLock)?;
+        res
+    }
+
+    /// Get how many bytes the API key named `name` has used: the total size of
+    /// the uploads created with it that haven't been deleted yet (including
+    /// expired ones that haven't been cleaned up), and the space reserved for
+    /// the ones that are still being uploaded.
+    pub async fn api_key_usage(&self, name: &str) -> Result<u64, ApiKeyUsageError> {
+        Ok(self
+            .read_api_key_usage()
+            .await?
+            .get(name)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    /// Reserve space for an upload created with the API key named `name`,
+    /// returning how many bytes were reserved: `max`, or less if there isn't
+    /// enough left of its `quota`.
+    ///
+    /// Fails with [`ApiKeyUsageError::QuotaExceeded`] if none of the quota is
+    /// left. Whatever isn't used by the upload should be given back with
+    /// [`FileBackend::release_api_key_usage`].
+    #[instrument(skip(self))]
+    pub async fn reserve_api_key_usage(
+        &self,
+        name: &str,
+        max: u64,
+        quota: Option<u64>,
+    ) -> Result<u64, ApiKeyUsageError> {
+        self.update_api_key_usage(|usage| {
+            let used = usage.entry(name.to_string()).or_default();
+            let reserved = match quota {
+                Some(quota) if *used >= quota => {
+                    event!(Level::INFO, used, quota, "API key quota is used up");
+                    return Err(ApiKeyUsageError::QuotaExceeded { quota });
+                }
+                Some(quota) => max.min(quota - *used),
+                None => max,
+            };
+            *used += reserved;
+            event!(Level::DEBUG, reserved, used, "reserved API key usage");
+            Ok(reserved)
+        })
+        .await
+    }
+
+    /// Give back `bytes` of the usage of the API key named `name`, after an
+    /// upload created with it was deleted or turned out to be smaller than the
+    /// space reserved for it.
+    #[instrument(skip(self))]
+    pub async fn release_api_key_usage(
+        &self,
+        name: &str,
+        bytes: u64,
+    ) -> Result<(), ApiKeyUsageError> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        self.update_api_key_usage(|usage| {
+            if let Some(used) = usage.get_mut(name) {
+                *used = used.saturating_sub(bytes);
+                event!(Level::DEBUG, used, "released API key usage");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Count the usage of every API key again from the uploads in the
+    /// repository, replacing the stored usage.
+    ///
+    /// This reads the metadata of every upload, so it should only be done when
+    /// nothing is being uploaded, like when the server starts. Uploads that
+    /// are still locked are not counted.
+    #[instrument(skip(self))]
+    pub async fn recount_api_key_usage(&self) -> Result<(), ApiKeyUsageError> {
+        let mut counted = HashMap::new();
+        let mut read_dir = fs::read_dir(&self.path)
+            .await
+            .map_err(ApiKeyUsageError::ReadDir)?;
+        while let Some(entry) = read_dir
+            .next_entry()
+            .await
+            .map_err(ApiKeyUsageError::NextEntry)?
+        {
+            let Some(id) = entry.file_name().to_str().map(ToString::to_string) else {
+                continue;
+            };
+            if id.starts_with('.') || fs::metadata(self.get_lock_path(&id)).await.is_ok() {
+                continue;
+            }
+            if let Some((name, size)) = self.upload_usage(&id).await {
+                *counted.entry(name).or_default() += size;
+            }
+        }
+        event!(Level::DEBUG, ?counted);
+        self.update_api_key_usage(|usage| {
+            *usage = counted;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Errors when reading or writing the bans
+#[derive(Debug, Error, Display)]
+pub enum BansError {
+    /// error reading bans file
+    ReadBans(#[source] io::Error),
+    /// error deserializing bans file
+    DeserializeBans(#[source] serde_json::Error),
+    /// error serializing bans
+    SerializeBans(#[source] serde_json::Error),
+    /// error writing new bans file
+    WriteBans(#[source] io::Error),
+    /// error replacing old bans file
+    ReplaceBans(#[source] io::Error),
+
+    /// the bans are being written by something else
+    Locked,
+    /// error creating lock file
+    CreateLockFile(#[source] io::Error),
+    /// error removing lock file
+    RemoveLock(#[source] io::Error),
+}
+/// Errors when finding the uploads created by an uploader
+#[derive(Debug, Error, Display)]
+pub enum FindUploadsError {
+    /// error reading directory
+    ReadDir(#[source] io::Error),
+    /// error reading next directory entry
+    NextEntry(#[source] io::Error),
+}
+impl FileBackend {
+    /// Read the bans stored in the repository.
+    ///
+    /// Returns an empty list if nobody has been banned yet.
+    pub async fn read_bans(&self) -> Result<Vec<Ban>, BansError> {
+        match fs::read(self.get_bans_path()).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(BansError::DeserializeBans),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(BansError::ReadBans(e)),
+        }
+    }
+
+    /// Replace the bans stored in the repository with `bans`.
+    ///
+    /// Use [`Self::update_bans`] to change the current bans, so changes made
+    /// at the same time aren't lost.
+    pub async fn write_bans(&self, bans: &[Ban]) -> Result<(), BansError> {
+        self.update_bans(|current| *current = bans.to_vec()).await
+    }
+
+    /// Change the bans stored in the repository with `update`, holding the
+    /// bans lock so two processes can't overwrite each other's changes.
+    pub async fn update_bans<T>(
+        &self,
+        update: impl FnOnce(&mut Vec<Ban>) -> T,
+    ) -> Result<T, BansError> {
+        wait_for_shared_lock(&self.get_bans_lock_path())
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => BansError::Locked,
+                _ => BansError::CreateLockFile(e),
+            })?;
+        let res = async {
+            let mut bans = self.read_bans().await?;
+            let value = update(&mut bans);
+            let serialized =
+                serde_json::to_string_pretty(&bans).map_err(BansError::SerializeBans)?;
+            let bans_path = self.get_bans_path();
+            let new_path = bans_path.with_extension("json.new");
+            fs::write(&new_path, serialized)
+                .await
+                .map_err(BansError::WriteBans)?;
+            fs::rename(&new_path, &bans_path)
+                .await
+                .map_err(BansError::ReplaceBans)?;
+            Ok(value)
+        }
+        .await;
+        fs::remove_file(self.get_bans_lock_path())
+            .await
+            .map_err(BansError::RemoveLock)?;
+        res
+    }
+
+    /// Find the uploads whose `uploader_ip` is one of `uploaders`, which can be
+    /// IP addresses or hashes of them.
+    ///
+    /// Uploads that are still locked are skipped.
+    #[instrument(skip(self))]
+    pub async fn find_uploads_by_uploader(
+        &self,
+        uploaders: &[String],
+    ) -> Result<Vec<Upload>, FindUploadsError> {
+        let mut uploads = Vec::new();
+        let mut read_dir = fs::read_dir(&self.path)
+            .await
+            .map_err(FindUploadsError::ReadDir)?;
+        while let Some(entry) = read_dir
+            .next_entry()
+            .await
+            .map_err(FindUploadsError::NextEntry)?
+        {
+            let Some(id) = entry.file_name().to_str().map(ToString::to_string) else {
+                continue;
+            };
+            if id.starts_with('.') {
+                continue;
+            }
+            let Ok(metadata) = self.read_upload_metadata(&id).await else {
+                continue;
+            };
+            if metadata
+                .uploader_ip
+                .as_ref()
+                .is_some_and(|u| uploaders.contains(u))
+            {
+                uploads.push(metadata);
+            }
+        }
+        event!(Level::DEBUG, count = uploads.len(), "found uploads");
+        Ok(uploads)
+    }
+}
+
 /// Errors when running a repository cleanup task
 #[derive(Debug, Error, Display)]
 pub enum CleanupError {
@@ -383,11 +1229,12 @@ pub enum CleanupError {
 }
 impl FileBackend {
     /// Validate all the uploads in the repository and delete ones that are
-    /// invalid.
+    /// invalid, returning how many uploads are left.
     ///
     /// See [`FileBackend::validate_upload`] for the checks that are performed
     #[instrument(skip(self))]
-    pub async fn cleanup(&self) -> Result<(), CleanupError> {
+    pub async fn cleanup(&self) -> Result<usize, CleanupError> {
+        let mut remaining = 0;
         let mut delete_queue = Vec::new();
         let mut read_dir = fs::read_dir(&self.path)
             .await
@@ -403,11 +1250,22 @@ impl FileBackend {
                     event!(Level::WARN, "invalid file name");
                     return;
                 };
+                // files that aren't uploads, like the API keys and bans
+                if id.starts_with('.') {
+                    event!(Level::DEBUG, "skipping");
+                    return;
+                }
 
                 match self.validate_upload(&id).await {
                     Ok(res) => match res {
-                        ValidateResult::Valid => event!(Level::DEBUG, "valid"),
-                        ValidateResult::Locked => event!(Level::INFO, "locked"),
+                        ValidateResult::Valid => {
+                            event!(Level::DEBUG, "valid");
+                            remaining += 1;
+                        }
+                        ValidateResult::Locked => {
+                            event!(Level::INFO, "locked");
+                            remaining += 1;
+                        }
                         ValidateResult::Invalid(reason) => {
                             event!(Level::DEBUG, "will delete: {reason}");
                             delete_queue.push(id);
@@ -427,14 +1285,19 @@ impl FileBackend {
             // TODO: some way to return a list of failed deletes
             async {
                 event!(Level::INFO, id, "deleting");
+                let usage = self.upload_usage(&id).await;
                 if let Err(err) = fs::remove_dir_all(self.get_upload_path(&id)).await {
                     event!(Level::ERROR, id, "error deleting: {err}");
+                    return;
+                }
+                if let Some((name, size)) = usage {
+                    self.release_usage_of_deleted(&name, size).await;
                 }
             }
             .instrument(span)
             .await
         }
 
-        Ok(())
+        Ok(remaining)
     }
 }
@@ -9,7 +9,6 @@ pub mod upload {
     use mime::Mime;
 
     /// Metadata about an upload
-    // TODO: maybe store uploader ip for spam reasons
     #[derive(Debug, Clone, PartialEq, Eq)]
     pub struct Upload {
         /// ID of the upload
@@ -22,8 +21,31 @@ pub mod upload {
         pub creation_date: DateTime<Utc>,
         /// date the upload expires, or [`None`] if never
         pub expiry_date: Option<DateTime<Utc>>,
-        /// secret key needed to delete the upload before it expires
-        pub delete_key: String,
+        /// salted hash of the secret key needed to delete the upload before it
+        /// expires (see [`crate::hash_delete_key`] and
+        /// [`crate::hash_custom_delete_key`])
+        pub delete_key_hash: String,
+        /// how many more times the upload can be viewed before it is deleted,
+        /// or [`None`] if unlimited
+        pub remaining_views: Option<u64>,
+        /// salted hash of the password needed to view the upload, or [`None`]
+        /// if it isn't password protected (see [`crate::hash_password`])
+        pub password_hash: Option<String>,
+        /// whether the upload can only be accessed through a signed link,
+        /// instead of by its ID alone
+        pub private: bool,
+        /// title shown instead of the filename, or [`None`] if not set
+        pub title: Option<String>,
+        /// longer description of the upload, or [`None`] if not set
+        pub description: Option<String>,
+        /// tags used to describe the upload
+        pub tags: Vec<String>,
+        /// name of the API key the upload was created with, or [`None`] if it
+        /// was uploaded without one (see [`crate::storage::api_key`])
+        pub api_key: Option<String>,
+        /// IP address of the uploader, or a salted hash of it (see
+        /// [`crate::ip::hash_ip`]), or [`None`] if it wasn't recorded
+        pub uploader_ip: Option<String>,
     }
     impl Upload {
         /// Check whether or not the upload is expired.
@@ -34,6 +56,26 @@ pub mod upload {
             // otherwise if expiry is before now, then it is expired
             self.expiry_date.is_some_and(|e| e < Utc::now())
         }
+
+        /// Check whether a password is needed to view the upload.
+        pub fn is_password_protected(&self) -> bool {
+            self.password_hash.is_some()
+        }
+
+        /// Check a password against the upload's password.
+        ///
+        /// Returns [`true`] if the password is correct, or if the upload is not
+        /// password protected at all.
+        pub fn check_password(&self, password: &str) -> bool {
+            self.password_hash
+                .as_deref()
+                .is_none_or(|hash| crate::verify_password(hash, password))
+        }
+
+        /// Check a key against the upload's delete key, in constant time.
+        pub fn check_delete_key(&self, key: &str) -> bool {
+            crate::verify_delete_key(&self.delete_key_hash, key)
+        }
     }
 
     #[cfg(test)]
@@ -55,7 +97,15 @@ pub mod upload {
                 mimetype: "text/plain".parse().unwrap(),
                 creation_date: creation_date(),
                 expiry_date: None,
-                delete_key: String::from("*^G^(MNCW#$(GMm9g87ctm4g98c43g789"),
+                delete_key_hash: crate::hash_delete_key("*^G^(MNCW#$(GMm9g87ctm4g98c43g789"),
+                remaining_views: None,
+                password_hash: None,
+                private: false,
+                title: None,
+                description: None,
+                tags: Vec::new(),
+                api_key: None,
+                uploader_ip: None,
             }
         }
         fn test_upload_expired() -> Upload {
@@ -86,6 +136,263 @@ pub mod upload {
         fn expired() {
             assert!(test_upload_expired().is_expired());
         }
+
+        #[test]
+        fn no_password_accepts_anything() {
+            let upload = test_upload_no_expiry();
+            assert!(!upload.is_password_protected());
+            assert!(upload.check_password("anything"));
+        }
+        #[test]
+        fn password_protected() {
+            let mut upload = test_upload_no_expiry();
+            upload.password_hash = Some(crate::hash_password("correct horse"));
+            assert!(upload.is_password_protected());
+            assert!(upload.check_password("correct horse"));
+            assert!(!upload.check_password("battery staple"));
+        }
+        #[test]
+        fn delete_key() {
+            let upload = test_upload_no_expiry();
+            assert!(upload.check_delete_key("*^G^(MNCW#$(GMm9g87ctm4g98c43g789"));
+            assert!(!upload.check_delete_key("*^G^(MNCW#$(GMm9g87ctm4g98c43g78"));
+        }
+    }
+}
+
+pub mod stats {
+    //! Access statistics of uploads, and a way to collect them in memory so
+    //! they can be written to disk in batches instead of on every request
+    use std::{collections::HashMap, sync::Mutex};
+
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    /// Statistics about how an upload has been accessed
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct UploadStats {
+        /// number of times the upload's display page was viewed
+        pub views: u64,
+        /// number of times the raw upload file was downloaded
+        pub downloads: u64,
+        /// total number of bytes of the upload file that were sent
+        pub bytes_served: u64,
+        /// last time the upload was viewed or downloaded, or [`None`] if never
+        pub last_accessed: Option<DateTime<Utc>>,
+    }
+    impl UploadStats {
+        /// Add the counts from `other` into these stats, keeping the latest
+        /// access time.
+        pub fn merge(&mut self, other: &UploadStats) {
+            self.views += other.views;
+            self.downloads += other.downloads;
+            self.bytes_served += other.bytes_served;
+            self.last_accessed = self.last_accessed.max(other.last_accessed);
+        }
+    }
+
+    /// Collects stats in memory until they are written to disk with
+    /// [`FileBackend::flush_stats`].
+    ///
+    /// [`FileBackend::flush_stats`]: super::file::FileBackend::flush_stats
+    #[derive(Debug, Default)]
+    pub struct StatsRecorder {
+        pending: Mutex<HashMap<String, UploadStats>>,
+    }
+    impl StatsRecorder {
+        fn record<F: FnOnce(&mut UploadStats)>(&self, id: &str, f: F) {
+            let mut pending = self.pending.lock().unwrap();
+            let stats = pending.entry(id.to_string()).or_default();
+            f(stats);
+            stats.last_accessed = Some(Utc::now());
+        }
+
+        /// Record a view of the upload's display page
+        pub fn record_view(&self, id: &str) {
+            self.record(id, |s| s.views += 1);
+        }
+        /// Record a download of the raw upload file
+        pub fn record_download(&self, id: &str) {
+            self.record(id, |s| s.downloads += 1);
+        }
+        /// Record that part of the upload file was sent
+        pub fn record_bytes_served(&self, id: &str, bytes: u64) {
+            self.record(id, |s| s.bytes_served += bytes);
+        }
+
+        /// Get the stats of an upload that haven't been written to disk yet
+        pub fn pending(&self, id: &str) -> UploadStats {
+            self.pending
+                .lock()
+                .unwrap()
+                .get(id)
+                .cloned()
+                .unwrap_or_default()
+        }
+        /// Take all the stats that haven't been written to disk yet, leaving
+        /// nothing pending.
+        pub fn take(&self) -> HashMap<String, UploadStats> {
+            std::mem::take(&mut *self.pending.lock().unwrap())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::TimeDelta;
+
+        use super::*;
+
+        #[test]
+        fn merge_adds_counts_and_keeps_latest_access() {
+            let earlier = Utc::now();
+            let later = earlier + TimeDelta::try_minutes(5).unwrap();
+            let mut stats = UploadStats {
+                views: 1,
+                downloads: 2,
+                bytes_served: 300,
+                last_accessed: Some(later),
+            };
+            stats.merge(&UploadStats {
+                views: 4,
+                downloads: 5,
+                bytes_served: 600,
+                last_accessed: Some(earlier),
+            });
+            assert_eq!(
+                stats,
+                UploadStats {
+                    views: 5,
+                    downloads: 7,
+                    bytes_served: 900,
+                    last_accessed: Some(later),
+                }
+            );
+        }
+        #[test]
+        fn recorder_collects_and_takes() {
+            let recorder = StatsRecorder::default();
+            recorder.record_view("abc");
+            recorder.record_download("abc");
+            recorder.record_bytes_served("abc", 42);
+            recorder.record_view("xyz");
+
+            let abc = recorder.pending("abc");
+            assert_eq!((abc.views, abc.downloads, abc.bytes_served), (1, 1, 42));
+            assert!(abc.last_accessed.is_some());
+
+            let taken = recorder.take();
+            assert_eq!(taken.len(), 2);
+            assert_eq!(recorder.pending("abc"), UploadStats::default());
+        }
+    }
+}
+
+pub mod api_key {
+    //! API keys that can be required to create uploads, each with its own
+    //! limits
+    use chrono::TimeDelta;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// An API key and the limits of the uploads created with it
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct ApiKey {
+        /// name used to refer to the key, which is stored in the metadata of
+        /// uploads created with it
+        pub name: String,
+        /// SHA-256 hash of the key (see [`crate::hash_api_key`])
+        pub key_hash: String,
+        /// maximum size of each upload in bytes, or [`None`] to use the
+        /// server's limit
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub max_file_size: Option<u64>,
+        /// maximum expiry of each upload, or [`None`] to use the server's limit
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "serialize_seconds",
+            deserialize_with = "deserialize_seconds"
+        )]
+        pub max_expiry: Option<TimeDelta>,
+        /// whether uploads can be made to never expire, even if the server
+        /// doesn't allow it
+        #[serde(default)]
+        pub allow_never_expiry: bool,
+        /// maximum total size in bytes of the uploads created with the key
+        /// that haven't been deleted yet, or [`None`] if unlimited
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub quota: Option<u64>,
+        /// whether uploads can be given a custom ID instead of a random one
+        #[serde(default)]
+        pub allow_custom_id: bool,
+    }
+    impl ApiKey {
+        /// Create a key with no limits of its own, which uses the server's
+        pub fn new(name: String, key_hash: String) -> Self {
+            Self {
+                name,
+                key_hash,
+                max_file_size: None,
+                max_expiry: None,
+                allow_never_expiry: false,
+                quota: None,
+                allow_custom_id: false,
+            }
+        }
+    }
+
+    fn serialize_seconds<S: Serializer>(
+        duration: &Option<TimeDelta>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration.map(|d| d.num_seconds()).serialize(serializer)
+    }
+    fn deserialize_seconds<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<TimeDelta>, D::Error> {
+        Option::<i64>::deserialize(deserializer)?
+            .map(|s| {
+                TimeDelta::try_seconds(s)
+                    .ok_or_else(|| serde::de::Error::custom("duration is out of range"))
+            })
+            .transpose()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn max_expiry_is_stored_in_seconds() {
+            let mut key = ApiKey::new(String::from("ci"), String::from("abc"));
+            key.max_expiry = TimeDelta::try_days(7);
+            let serialized = serde_json::to_string(&key).unwrap();
+            assert_eq!(
+                serialized,
+                r#"{"name":"ci","key_hash":"abc","max_expiry":604800,"allow_never_expiry":false,"allow_custom_id":false}"#
+            );
+            assert_eq!(serde_json::from_str::<ApiKey>(&serialized).unwrap(), key);
+        }
+    }
+}
+
+pub mod ban {
+    //! IP addresses that are banned from uploading
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    use crate::ip::IpCidr;
+
+    /// A range of IP addresses that can't create uploads
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Ban {
+        /// the banned addresses
+        pub range: IpCidr,
+        /// why the addresses were banned
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reason: Option<String>,
+        /// date the ban was created
+        pub creation_date: DateTime<Utc>,
     }
 }
 